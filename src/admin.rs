@@ -0,0 +1,210 @@
+// Maintenance that goes beyond single queries: deletions that also remove
+// files from disk, rebuilding what is derived from posts and files, and
+// consistency checks. Used by the `adelia-admin` tool.
+use mime_guess::MimeGuess;
+use rusqlite::{Connection, Result as SqlResult};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::db::{self, Deleted};
+use crate::store::{Store, StoreResult};
+use crate::{media, media_info};
+
+// Files that are already gone don't matter here
+pub fn remove_files(files: &[String]) {
+    for file in files {
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+// Deletions leave a notice in `conn` for a running server to pass on to
+// open pages
+pub fn delete_post(conn: &Connection, store: &dyn Store, id: i32) -> StoreResult<Deleted> {
+    let (board_id, post) = match (store.post_board(id)?, store.fetch_post(id)?) {
+        (Some(board_id), Some(post)) => (board_id, post),
+        _ => return Ok(Deleted::default()),
+    };
+    let deleted = store.delete_post(id)?;
+    remove_files(&deleted.files);
+    if deleted.posts > 0 {
+        let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+        db::add_deletion_notice(conn, board_id, thread_id, id, false)?;
+    }
+    Ok(deleted)
+}
+
+// The board's posts, then its name and settings
+pub fn delete_board(conn: &Connection, store: &dyn Store, board_id: i32) -> StoreResult<Deleted> {
+    let threads = store.list_threads(board_id, store.count_threads(board_id)? as usize, 0)?;
+    let deleted = store.delete_board(board_id)?;
+    remove_files(&deleted.files);
+    db::delete_board_settings(conn, board_id)?;
+    for thread in threads {
+        db::add_deletion_notice(conn, board_id, thread.id, thread.id, false)?;
+    }
+    Ok(deleted)
+}
+
+#[derive(Default, Serialize)]
+pub struct ThumbnailReport {
+    // Attachments read again
+    pub files: usize,
+    pub thumbnails: usize,
+    // Attachments whose file is gone
+    pub missing: usize,
+}
+
+// Reads every stored file again for its size, dimensions, duration and hash,
+// and makes its thumbnail anew
+pub fn rebuild_thumbnails(conn: &Connection) -> SqlResult<ThumbnailReport> {
+    let mut report = ThumbnailReport::default();
+    for (id, mut file) in db::list_attachments(conn)? {
+        let data = match std::fs::read(&file.file_path) {
+            Ok(data) => data,
+            Err(_) => {
+                report.missing += 1;
+                continue;
+            }
+        };
+        let mime_type = file
+            .mime_type
+            .clone()
+            .unwrap_or_else(|| MimeGuess::from_path(&file.file_path).first_or_octet_stream().to_string());
+        if let Some(old) = &file.thumbnail_path {
+            let _ = std::fs::remove_file(old);
+        }
+        let info = media_info::probe(&data, &mime_type);
+        file.size = Some(data.len() as i64);
+        file.sha256 = Some(media::content_hash(&data));
+        file.md5 = Some(media::content_md5(&data));
+        file.width = info.width.map(i64::from);
+        file.height = info.height.map(i64::from);
+        file.duration = info.duration;
+        file.thumbnail_path = media::make_thumbnail(&data, &mime_type, &file.file_path);
+        db::update_attachment(conn, id, &file)?;
+        report.files += 1;
+        report.thumbnails += file.thumbnail_path.is_some() as usize;
+    }
+    Ok(report)
+}
+
+#[derive(Serialize)]
+pub struct Problem {
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+fn problem(kind: &'static str, detail: String) -> Problem {
+    Problem { kind, detail }
+}
+
+// Everything that doesn't add up, or nothing
+pub fn check(conn: &Connection, upload_dir: &str) -> SqlResult<Vec<Problem>> {
+    let mut problems: Vec<Problem> = db::integrity_errors(conn)?
+        .into_iter()
+        .map(|error| problem("database", error))
+        .collect();
+    if !db::search_index_ok(conn) {
+        problems.push(problem("search_index", "The search index is out of date; rebuild it".to_string()));
+    }
+    for (attachment, post) in db::orphaned_attachments(conn)? {
+        problems.push(problem(
+            "orphaned_attachment",
+            format!("Attachment {} belongs to post {}, which doesn't exist", attachment, post),
+        ));
+    }
+    for (reply, thread) in db::orphaned_replies(conn)? {
+        problems.push(problem(
+            "orphaned_reply",
+            format!("Post {} replies to {}, which isn't a thread on the same board", reply, thread),
+        ));
+    }
+
+    let mut referenced = HashSet::new();
+    for (id, file) in db::list_attachments(conn)? {
+        for path in std::iter::once(&file.file_path).chain(&file.thumbnail_path) {
+            if !Path::new(path).is_file() {
+                problems.push(problem("missing_file", format!("{} (attachment {})", path, id)));
+            }
+            if let Some(name) = Path::new(path).file_name() {
+                referenced.insert(name.to_owned());
+            }
+        }
+    }
+    if let Ok(entries) = std::fs::read_dir(upload_dir) {
+        let mut unreferenced: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.file_name())
+            .filter(|name| name.to_str().is_some_and(media::looks_uploaded) && !referenced.contains(name))
+            .map(|name| Path::new(upload_dir).join(name).display().to_string())
+            .collect();
+        unreferenced.sort();
+        problems.extend(unreferenced.into_iter().map(|path| problem("unreferenced_file", path)));
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Attachment, Post, Poster};
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    // Written with nothing but its path, as if its details were lost
+    fn attachment(file_path: String) -> Attachment {
+        Attachment {
+            file_path,
+            original_name: None,
+            mime_type: Some("image/png".to_string()),
+            size: None,
+            width: None,
+            height: None,
+            duration: None,
+            thumbnail_path: None,
+            sha256: None,
+            md5: None,
+        }
+    }
+
+    #[test]
+    fn rebuild_thumbnails_updates_attachments() {
+        let dir = std::env::temp_dir().join(format!("adelia-rebuild-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("abcdef-a.png").to_str().unwrap().to_string();
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(RgbImage::new(400, 300)).write_to(&mut png, ImageFormat::Png).unwrap();
+        std::fs::write(&file_path, png.get_ref()).unwrap();
+
+        let conn = db::initialize_db(":memory:").unwrap();
+        let post = Post {
+            id: 0,
+            post_id: "p1".to_string(),
+            parent_id: 0,
+            title: "title".to_string(),
+            message: "message".to_string(),
+            files: vec![attachment(file_path.clone())],
+            name: None,
+            tripcode: None,
+            poster_id: None,
+            created_at: 100,
+            last_reply_at: 100,
+            edited_at: None,
+        };
+        db::insert_post(&conn, 1, &post, &Poster::default()).unwrap();
+
+        let report = rebuild_thumbnails(&conn);
+        let stored = db::list_attachments(&conn).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = report.unwrap();
+        assert_eq!((report.files, report.thumbnails, report.missing), (1, 1, 0));
+        let file = &stored[0].1;
+        assert_eq!(file.size, Some(png.get_ref().len() as i64));
+        assert_eq!((file.width, file.height), (Some(400), Some(300)));
+        assert_eq!(file.sha256.as_deref(), Some(media::content_hash(png.get_ref()).as_str()));
+        assert_eq!(file.md5.as_deref(), Some(media::content_md5(png.get_ref()).as_str()));
+        assert_eq!(file.thumbnail_path.as_deref(), Some(format!("{}.thumb.jpg", file_path).as_str()));
+    }
+}
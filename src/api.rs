@@ -0,0 +1,309 @@
+// Read-only JSON mirror of the HTML pages, modeled on the 4chan API so
+// clients written for it need few changes. Files differ: `tim` is the stored
+// file name, a string rather than a number, and the file is at
+// `/media/{tim}{ext}`; posts may also carry more than one file.
+use actix_web::{web, HttpResponse};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::db::{self, Attachment, Post};
+use crate::tripcode::DEFAULT_NAME;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::lock;
+use crate::media::{self, Kind};
+use crate::store::{self, Store, StoreResult};
+
+// Replies shown under each thread on board pages and in the catalog
+const PREVIEW_REPLIES: usize = 5;
+
+#[derive(Serialize)]
+struct ApiBoard {
+    board: String,
+    title: String,
+    per_page: usize,
+    pages: usize,
+    max_filesize: usize,
+    max_comment_chars: usize,
+    forced_anon: u8,
+    user_ids: u8,
+    // Not in the 4chan API, which allows one file per post
+    max_files: usize,
+}
+
+#[derive(Serialize)]
+struct ApiBoards {
+    boards: Vec<ApiBoard>,
+}
+
+#[derive(Serialize)]
+struct ApiFile {
+    // Stored file name without the extension, so `/media/{tim}{ext}` is the
+    // file. A string, where 4chan uses a number
+    tim: String,
+    // Name of the file as uploaded, without the extension
+    filename: String,
+    ext: String,
+    fsize: u64,
+    // Base64 MD5 of the file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    md5: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    w: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    h: Option<i64>,
+    // Size of the thumbnail, or of the image itself when it is shown as is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tn_w: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tn_h: Option<i64>,
+    // Seconds, for audio and video; not in the 4chan API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ApiPost {
+    no: i32,
+    resto: i32,
+    time: i64,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    sub: String,
+    com: String,
+    // The first attachment, as in the 4chan API
+    #[serde(flatten)]
+    file: Option<ApiFile>,
+    // Any further attachments, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_files: Vec<ApiFile>,
+    #[serde(flatten)]
+    thread: Option<ApiThreadInfo>,
+}
+
+// Only present on the first post of a thread
+#[derive(Serialize)]
+struct ApiThreadInfo {
+    replies: i64,
+    images: i64,
+    last_modified: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    omitted_posts: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    omitted_images: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_replies: Option<Vec<ApiPost>>,
+}
+
+#[derive(Serialize)]
+struct ApiThread {
+    posts: Vec<ApiPost>,
+}
+
+#[derive(Serialize)]
+struct ApiPage {
+    threads: Vec<ApiThread>,
+}
+
+#[derive(Serialize)]
+struct ApiCatalogPage {
+    page: usize,
+    threads: Vec<ApiPost>,
+}
+
+impl ApiFile {
+    fn from_attachment(file: &Attachment) -> ApiFile {
+        let path = Path::new(&file.file_path);
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("").to_string();
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+        let display_name = Path::new(file.display_name());
+        let filename = display_name.file_stem().and_then(|s| s.to_str()).unwrap_or("").to_string();
+        let fsize = match file.size {
+            Some(size) => size as u64,
+            None => std::fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        };
+        let thumbnail = match &file.thumbnail_path {
+            Some(thumbnail) => imagesize::size(thumbnail)
+                .ok()
+                .map(|size| (Some(size.width as i64), Some(size.height as i64))),
+            None => match media::lookup(&media::mime_type_of(file)).map(|media_type| media_type.kind) {
+                Some(Kind::Image) => Some((file.width, file.height)),
+                _ => None,
+            },
+        };
+        let (tn_w, tn_h) = thumbnail.unwrap_or((None, None));
+        ApiFile {
+            tim: stem,
+            filename,
+            ext,
+            fsize,
+            md5: file.md5.clone(),
+            w: file.width,
+            h: file.height,
+            tn_w,
+            tn_h,
+            duration: file.duration,
+        }
+    }
+}
+
+impl ApiPost {
+    fn from_post(post: Post) -> ApiPost {
+        let mut files = post.files.iter().map(ApiFile::from_attachment);
+        ApiPost {
+            no: post.id,
+            resto: post.parent_id,
+            time: post.created_at,
+            name: post.name.unwrap_or_else(|| DEFAULT_NAME.to_string()),
+            trip: post.tripcode,
+            id: post.poster_id,
+            sub: post.title,
+            com: post.message,
+            file: files.next(),
+            extra_files: files.collect(),
+            thread: None,
+        }
+    }
+}
+
+fn page_count(threads: i64, per_page: usize) -> usize {
+    (threads as usize).div_ceil(per_page).max(1)
+}
+
+// The thread starter with its counters, and its newest replies
+fn thread_preview(store: &dyn Store, op: Post) -> StoreResult<(ApiPost, Vec<ApiPost>)> {
+    let (replies, images) = store.count_replies(op.id)?;
+    let preview: Vec<ApiPost> = store.last_replies(op.id, PREVIEW_REPLIES)?
+        .into_iter()
+        .map(ApiPost::from_post)
+        .collect();
+    let shown_images = preview.iter().filter(|p| p.file.is_some()).count() as i64;
+
+    let info = ApiThreadInfo {
+        replies,
+        images,
+        last_modified: op.last_reply_at.max(op.created_at),
+        omitted_posts: Some(replies - preview.len() as i64),
+        omitted_images: Some(images - shown_images),
+        last_replies: None,
+    };
+    Ok((ApiPost { thread: Some(info), ..ApiPost::from_post(op) }, preview))
+}
+
+pub async fn boards(
+    conn: web::Data<Mutex<Connection>>,
+    store: web::Data<dyn Store>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, AppError> {
+    let conn = lock(&conn);
+    let result = store::list_boards(&conn, store.get_ref()).and_then(|boards| {
+        let boards = boards
+            .into_iter()
+            .map(|(board_id, name)| {
+                let settings = db::board_settings(&conn, board_id);
+                Ok(ApiBoard {
+                    board: board_id.to_string(),
+                    title: if name.is_empty() { format!("Board {}", board_id) } else { name },
+                    per_page: config.posts_per_page,
+                    pages: page_count(store.count_threads(board_id)?, config.posts_per_page),
+                    max_filesize: config.max_file_size,
+                    max_comment_chars: config.max_message_length,
+                    forced_anon: settings.force_anonymous as u8,
+                    user_ids: settings.poster_ids as u8,
+                    max_files: settings.max_files,
+                })
+            })
+            .collect::<StoreResult<Vec<_>>>()?;
+        Ok(ApiBoards { boards })
+    });
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+pub async fn board_page(
+    store: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    path: web::Path<(i32, usize)>,
+) -> Result<HttpResponse, AppError> {
+    let store = store.get_ref();
+    let (board_id, page) = path.into_inner();
+    let per_page = config.posts_per_page;
+    if page == 0 || page > page_count(store.count_threads(board_id)?, per_page) {
+        return Err(AppError::NotFound);
+    }
+
+    let result = store.list_threads(board_id, per_page, (page - 1) * per_page).and_then(|ops| {
+        let threads = ops
+            .into_iter()
+            .map(|op| {
+                let (op, preview) = thread_preview(store, op)?;
+                let mut posts = vec![op];
+                posts.extend(preview);
+                Ok(ApiThread { posts })
+            })
+            .collect::<StoreResult<Vec<_>>>()?;
+        Ok(ApiPage { threads })
+    });
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+pub async fn thread(store: web::Data<dyn Store>, path: web::Path<(i32, i32)>) -> Result<HttpResponse, AppError> {
+    let (board_id, thread_id) = path.into_inner();
+
+    let mut posts = store.fetch_thread(board_id, thread_id)?.into_iter();
+    let op = match posts.next() {
+        Some(op) if op.parent_id == 0 => op,
+        _ => return Err(AppError::NotFound),
+    };
+
+    let replies: Vec<ApiPost> = posts.map(ApiPost::from_post).collect();
+    let info = ApiThreadInfo {
+        replies: replies.len() as i64,
+        images: replies.iter().filter(|p| p.file.is_some()).count() as i64,
+        last_modified: op.last_reply_at.max(op.created_at),
+        omitted_posts: None,
+        omitted_images: None,
+        last_replies: None,
+    };
+    let mut posts = vec![ApiPost { thread: Some(info), ..ApiPost::from_post(op) }];
+    posts.extend(replies);
+    Ok(HttpResponse::Ok().json(ApiThread { posts }))
+}
+
+pub async fn catalog(
+    store: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    board_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let store = store.get_ref();
+    let board_id = board_id.into_inner();
+    let per_page = config.posts_per_page;
+
+    let result = store.count_threads(board_id).and_then(|total| {
+        (1..=page_count(total, per_page))
+            .map(|page| {
+                let threads = store
+                    .list_threads(board_id, per_page, (page - 1) * per_page)?
+                    .into_iter()
+                    .map(|op| {
+                        let (mut op, preview) = thread_preview(store, op)?;
+                        if let Some(info) = op.thread.as_mut() {
+                            info.last_replies = Some(preview);
+                        }
+                        Ok(op)
+                    })
+                    .collect::<StoreResult<Vec<_>>>()?;
+                Ok(ApiCatalogPage { page, threads })
+            })
+            .collect::<StoreResult<Vec<_>>>()
+    });
+    Ok(HttpResponse::Ok().json(result?))
+}
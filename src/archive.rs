@@ -0,0 +1,596 @@
+// Portable board archives: a tar file whose first member, `board.json`, is a
+// versioned dump of the board's settings and posts, followed by the files
+// those posts reference under `media/` (each thumbnail right after its file).
+//
+// Imported posts get new ids, so quotes between posts of the archive are
+// rewritten to match. Files this instance already stores are reused rather
+// than written a second time.
+//
+// Posts are read from and written to the SQLite tables, so archives only
+// cover boards whose posts are stored there.
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::db::{self, Attachment, BoardSettings, Post, Poster};
+use crate::media;
+
+// Bumped whenever `Dump` changes in a way older code can't read
+const FORMAT_VERSION: u32 = 1;
+const DUMP_NAME: &str = "board.json";
+const MEDIA_DIR: &str = "media/";
+
+#[derive(Serialize, Deserialize)]
+struct Dump {
+    version: u32,
+    board: BoardDump,
+    // Oldest first
+    posts: Vec<PostDump>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BoardDump {
+    id: i32,
+    name: String,
+    settings: BoardSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PostDump {
+    id: i32,
+    post_id: String,
+    // 0 for thread starters
+    parent_id: i32,
+    // HTML-escaped, as stored
+    title: String,
+    message: String,
+    name: Option<String>,
+    tripcode: Option<String>,
+    poster_id: Option<String>,
+    // Unix seconds
+    created_at: i64,
+    last_reply_at: i64,
+    // Archives made before posts could be edited don't have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edited_at: Option<i64>,
+    files: Vec<FileDump>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileDump {
+    // Member names within the archive
+    path: String,
+    thumbnail: Option<String>,
+    original_name: Option<String>,
+    mime_type: Option<String>,
+    size: Option<i64>,
+    width: Option<i64>,
+    height: Option<i64>,
+    duration: Option<f64>,
+    sha256: Option<String>,
+}
+
+pub enum ArchiveError {
+    Io(std::io::Error),
+    Database(rusqlite::Error),
+    Format(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Io(e) => write!(f, "{}", e),
+            ArchiveError::Database(e) => write!(f, "Database error: {}", e),
+            ArchiveError::Format(message) => write!(f, "Invalid archive: {}", message),
+        }
+    }
+}
+
+impl fmt::Debug for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(e: std::io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for ArchiveError {
+    fn from(e: rusqlite::Error) -> Self {
+        ArchiveError::Database(e)
+    }
+}
+
+fn member_name(file_path: &str) -> String {
+    let name = Path::new(file_path).file_name().and_then(|name| name.to_str()).unwrap_or(file_path);
+    format!("{}{}", MEDIA_DIR, name)
+}
+
+#[derive(Serialize)]
+pub struct ExportReport {
+    pub posts: usize,
+    pub files: usize,
+    // Files the database knows of that are gone from disk
+    pub missing: usize,
+}
+
+pub fn export_board(conn: &Connection, board_id: i32, out: &Path) -> Result<ExportReport, ArchiveError> {
+    let mut report = ExportReport { posts: 0, files: 0, missing: 0 };
+    let mut media = Vec::new();
+    let mut posts = Vec::new();
+    for post in db::board_posts(conn, board_id)? {
+        let mut files = Vec::new();
+        for file in post.files {
+            if !Path::new(&file.file_path).is_file() {
+                report.missing += 1;
+                continue;
+            }
+            let thumbnail = file.thumbnail_path.filter(|thumbnail| Path::new(thumbnail).is_file());
+            media.push((file.file_path.clone(), thumbnail.clone()));
+            files.push(FileDump {
+                path: member_name(&file.file_path),
+                thumbnail: thumbnail.as_deref().map(member_name),
+                original_name: file.original_name,
+                mime_type: file.mime_type,
+                size: file.size,
+                width: file.width,
+                height: file.height,
+                duration: file.duration,
+                sha256: file.sha256,
+            });
+        }
+        posts.push(PostDump {
+            id: post.id,
+            post_id: post.post_id,
+            parent_id: post.parent_id,
+            title: post.title,
+            message: post.message,
+            name: post.name,
+            tripcode: post.tripcode,
+            poster_id: post.poster_id,
+            created_at: post.created_at,
+            last_reply_at: post.last_reply_at,
+            edited_at: post.edited_at,
+            files,
+        });
+    }
+    report.posts = posts.len();
+    let dump = Dump {
+        version: FORMAT_VERSION,
+        board: BoardDump {
+            id: board_id,
+            name: db::board_name(conn, board_id)?,
+            settings: db::board_settings(conn, board_id),
+        },
+        posts,
+    };
+    let json = serde_json::to_vec_pretty(&dump).map_err(|e| ArchiveError::Format(e.to_string()))?;
+
+    let written = (|| -> std::io::Result<usize> {
+        let mut builder = tar::Builder::new(File::create(out)?);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(json.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(chrono::Utc::now().timestamp() as u64);
+        builder.append_data(&mut header, DUMP_NAME, json.as_slice())?;
+        // Posts sharing a file carry it once
+        let mut added = HashSet::new();
+        for (file_path, thumbnail) in &media {
+            if added.insert(file_path) {
+                builder.append_path_with_name(file_path, member_name(file_path))?;
+                if let Some(thumbnail) = thumbnail {
+                    builder.append_path_with_name(thumbnail, member_name(thumbnail))?;
+                }
+            }
+        }
+        builder.into_inner()?.sync_all()?;
+        Ok(added.len())
+    })();
+    match written {
+        Ok(files) => {
+            report.files = files;
+            Ok(report)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(out);
+            Err(e.into())
+        }
+    }
+}
+
+// `>>123` quotes a post on the same board and `>>>/1/123` one on any board,
+// both stored escaped. Quotes of imported posts are pointed at their new
+// ids; all others stay as they are.
+fn rewrite_quotes(message: &str, old_board: i32, new_board: i32, ids: &HashMap<i32, i64>) -> String {
+    const QUOTE: &str = "&gt;&gt;";
+    const BOARD_QUOTE: &str = "&gt;/";
+
+    // A number at the start of `text`, and what follows it
+    fn number(text: &str) -> Option<(i32, &str)> {
+        let end = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+        Some((text[..end].parse().ok()?, &text[end..]))
+    }
+
+    let mut rewritten = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find(QUOTE) {
+        rewritten.push_str(&rest[..start + QUOTE.len()]);
+        rest = &rest[start + QUOTE.len()..];
+        if let Some(link) = rest.strip_prefix(BOARD_QUOTE) {
+            let target = number(link)
+                .and_then(|(board, tail)| Some((board, number(tail.strip_prefix('/')?)?)))
+                .filter(|(board, _)| *board == old_board)
+                .and_then(|(_, (id, tail))| Some((ids.get(&id)?, tail)));
+            if let Some((id, tail)) = target {
+                rewritten.push_str(&format!("{}{}/{}", BOARD_QUOTE, new_board, id));
+                rest = tail;
+            }
+        } else if let Some((id, tail)) = number(rest).and_then(|(id, tail)| Some((ids.get(&id)?, tail))) {
+            rewritten.push_str(&id.to_string());
+            rest = tail;
+        }
+    }
+    rewritten.push_str(rest);
+    rewritten
+}
+
+// Files written during an import, removed again unless it succeeds
+#[derive(Default)]
+struct Written {
+    files: Vec<String>,
+    keep: bool,
+}
+
+impl Drop for Written {
+    fn drop(&mut self) {
+        if !self.keep {
+            for file in &self.files {
+                let _ = std::fs::remove_file(file);
+            }
+        }
+    }
+}
+
+// Where an archived file ended up: (file, thumbnail, whether it was already here)
+type Stored = (String, Option<String>, bool);
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub board_id: i32,
+    pub posts: usize,
+    // Files written to the upload directory
+    pub files: usize,
+    // Files that were already stored and are shared now
+    pub reused: usize,
+    // Replies whose thread isn't in the archive
+    pub skipped: usize,
+}
+
+// Imports into `target`, or the board the archive was made from. A board
+// that exists already is only added to when `merge` is set, and keeps its
+// own name and settings.
+pub fn import_board(
+    conn: &Connection,
+    archive: &Path,
+    upload_dir: &str,
+    target: Option<i32>,
+    merge: bool,
+) -> Result<ImportReport, ArchiveError> {
+    let mut archive = tar::Archive::new(File::open(archive)?);
+    let mut entries = archive.entries()?;
+
+    let dump: Dump = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry?;
+            if entry.path()?.to_str() != Some(DUMP_NAME) {
+                return Err(ArchiveError::Format(format!("{} must come first", DUMP_NAME)));
+            }
+            serde_json::from_reader(&mut entry).map_err(|e| ArchiveError::Format(e.to_string()))?
+        }
+        None => return Err(ArchiveError::Format("the archive is empty".to_string())),
+    };
+    if dump.version != FORMAT_VERSION {
+        return Err(ArchiveError::Format(format!("format version {} is not supported", dump.version)));
+    }
+
+    let board_id = target.unwrap_or(dump.board.id);
+    let exists = db::board_exists(conn, board_id)?;
+    if exists && !merge {
+        return Err(ArchiveError::Format(format!("board {} exists already; merge into it to add the posts", board_id)));
+    }
+
+    // Thumbnail member -> member of the file it belongs to
+    let mut thumbnails = HashMap::new();
+    let mut originals = HashSet::new();
+    for file in dump.posts.iter().flat_map(|post| &post.files) {
+        originals.insert(file.path.clone());
+        if let Some(thumbnail) = &file.thumbnail {
+            thumbnails.insert(thumbnail.clone(), file.path.clone());
+        }
+    }
+
+    let mut written = Written::default();
+    let mut stored: HashMap<String, Stored> = HashMap::new();
+    // Hash -> member, for files that appear twice under different names
+    let mut by_hash: HashMap<String, String> = HashMap::new();
+    // Member -> MD5, which archives don't carry
+    let mut md5s: HashMap<String, String> = HashMap::new();
+    for entry in entries {
+        let mut entry = entry?;
+        let member = match entry.path()?.to_str() {
+            Some(member) => member.to_string(),
+            None => continue,
+        };
+        // Only the file name is used for writing, whatever the member is called
+        let name = match Path::new(&member).file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if originals.contains(&member) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            let hash = media::content_hash(&data);
+            md5s.insert(member.clone(), media::content_md5(&data));
+            let existing = match by_hash.get(&hash).and_then(|other| stored.get(other)) {
+                Some((file_path, thumbnail, _)) => Some((file_path.clone(), thumbnail.clone())),
+                None => db::file_with_hash(conn, &hash)?.filter(|(file_path, _)| Path::new(file_path).is_file()),
+            };
+            if let Some((file_path, thumbnail)) = existing {
+                stored.insert(member, (file_path, thumbnail, true));
+                continue;
+            }
+            let unique_id: String = rand::thread_rng().sample_iter(&Alphanumeric).take(6).map(char::from).collect();
+            let original = name.split_once('-').map(|(_, original)| original).unwrap_or(&name);
+            let file_path = format!("{}/{}-{}", upload_dir.trim_end_matches('/'), unique_id, original);
+            written.files.push(file_path.clone());
+            std::fs::write(&file_path, &data)?;
+            by_hash.insert(hash, member.clone());
+            stored.insert(member, (file_path, None, false));
+        } else if let Some(owner) = thumbnails.get(&member) {
+            let extension = match Path::new(&name).extension().and_then(|e| e.to_str()) {
+                Some(extension @ ("jpg" | "png")) => extension,
+                _ => continue,
+            };
+            // Reused files come with their own thumbnails
+            if let Some((file_path, thumbnail @ None, false)) = stored.get_mut(owner) {
+                let thumbnail_path = format!("{}.thumb.{}", file_path, extension);
+                written.files.push(thumbnail_path.clone());
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                std::fs::write(&thumbnail_path, &data)?;
+                *thumbnail = Some(thumbnail_path);
+            }
+        }
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    if !exists {
+        db::create_board(&tx, board_id, &dump.board.name, &dump.board.settings)?;
+    }
+    let mut ids: HashMap<i32, i64> = HashMap::new();
+    let mut skipped = 0;
+    for post in &dump.posts {
+        let parent_id = match post.parent_id {
+            0 => 0,
+            parent_id => match ids.get(&parent_id) {
+                Some(&parent_id) => parent_id as i32,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            },
+        };
+        let files = post
+            .files
+            .iter()
+            .filter_map(|file| {
+                let (file_path, thumbnail_path, _) = stored.get(&file.path)?.clone();
+                Some(Attachment {
+                    file_path,
+                    original_name: file.original_name.clone(),
+                    mime_type: file.mime_type.clone(),
+                    size: file.size,
+                    width: file.width,
+                    height: file.height,
+                    duration: file.duration,
+                    thumbnail_path,
+                    sha256: file.sha256.clone(),
+                    md5: md5s.get(&file.path).cloned(),
+                })
+            })
+            .collect();
+        let id = db::insert_post(
+            &tx,
+            board_id,
+            &Post {
+                id: 0,
+                post_id: post.post_id.clone(),
+                parent_id,
+                title: post.title.clone(),
+                message: post.message.clone(),
+                files,
+                name: post.name.clone(),
+                tripcode: post.tripcode.clone(),
+                poster_id: post.poster_id.clone(),
+                created_at: post.created_at,
+                last_reply_at: post.last_reply_at,
+                edited_at: post.edited_at,
+            },
+            &Poster::default(),
+        )?;
+        ids.insert(post.id, id);
+    }
+    // Quotes may point forward, so this waits until every post has its id
+    for post in &dump.posts {
+        if let Some(&id) = ids.get(&post.id) {
+            let message = rewrite_quotes(&post.message, dump.board.id, board_id, &ids);
+            if message != post.message {
+                db::update_message(&tx, id, &message)?;
+            }
+        }
+    }
+    tx.commit()?;
+    written.keep = true;
+
+    Ok(ImportReport {
+        board_id,
+        posts: ids.len(),
+        files: stored.values().filter(|(_, _, reused)| !reused).count(),
+        reused: stored.values().filter(|(_, _, reused)| *reused).count(),
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("adelia-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn post(parent_id: i32, message: &str, files: Vec<Attachment>) -> Post {
+        Post {
+            id: 0,
+            post_id: "p".to_string(),
+            parent_id,
+            title: "title".to_string(),
+            message: message.to_string(),
+            files,
+            name: None,
+            tripcode: None,
+            poster_id: None,
+            created_at: 100,
+            last_reply_at: 100,
+            edited_at: None,
+        }
+    }
+
+    fn message(conn: &Connection, id: i64) -> String {
+        db::fetch_post(conn, id as i32).unwrap().unwrap().message
+    }
+
+    #[test]
+    fn rewrite_quotes_on_the_same_board() {
+        let ids = HashMap::from([(5, 50), (9, 90)]);
+        assert_eq!(rewrite_quotes("&gt;&gt;5 yes", 1, 2, &ids), "&gt;&gt;50 yes");
+        assert_eq!(rewrite_quotes("&gt;&gt;5&gt;&gt;9", 1, 2, &ids), "&gt;&gt;50&gt;&gt;90");
+        // Whole numbers only
+        assert_eq!(rewrite_quotes("&gt;&gt;55 &gt;&gt;59", 1, 2, &ids), "&gt;&gt;55 &gt;&gt;59");
+        assert_eq!(rewrite_quotes("5 &gt;5 &gt;&gt;", 1, 2, &ids), "5 &gt;5 &gt;&gt;");
+    }
+
+    #[test]
+    fn rewrite_quotes_across_boards() {
+        let ids = HashMap::from([(5, 50)]);
+        assert_eq!(rewrite_quotes("&gt;&gt;&gt;/1/5.", 1, 2, &ids), "&gt;&gt;&gt;/2/50.");
+        // Posts of other boards weren't imported
+        assert_eq!(rewrite_quotes("&gt;&gt;&gt;/3/5", 1, 2, &ids), "&gt;&gt;&gt;/3/5");
+        assert_eq!(rewrite_quotes("&gt;&gt;&gt;/1/", 1, 2, &ids), "&gt;&gt;&gt;/1/");
+        assert_eq!(rewrite_quotes("&gt;&gt;&gt;/x/5", 1, 2, &ids), "&gt;&gt;&gt;/x/5");
+    }
+
+    #[test]
+    fn rewrite_quotes_leaves_unknown_ids() {
+        let ids = HashMap::from([(5, 50)]);
+        assert_eq!(rewrite_quotes("&gt;&gt;7 and &gt;&gt;&gt;/1/7", 1, 2, &ids), "&gt;&gt;7 and &gt;&gt;&gt;/1/7");
+        assert_eq!(rewrite_quotes("no quotes", 1, 2, &HashMap::new()), "no quotes");
+    }
+
+    #[test]
+    fn rewrite_quotes_forward() {
+        // A post quoting one written after it, which only gets its id later
+        let ids = HashMap::from([(5, 50), (6, 60)]);
+        assert_eq!(rewrite_quotes("&gt;&gt;6", 1, 2, &ids), "&gt;&gt;60");
+    }
+
+    #[test]
+    fn written_files_are_removed_unless_kept() {
+        let dir = scratch_dir("written");
+        let file = dir.join("a").to_str().unwrap().to_string();
+        std::fs::write(&file, b"a").unwrap();
+        drop(Written { files: vec![file.clone()], keep: true });
+        assert!(Path::new(&file).is_file());
+        drop(Written { files: vec![file.clone()], keep: false });
+        assert!(!Path::new(&file).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_and_import() {
+        let dir = scratch_dir("archive");
+        let uploads = dir.join("uploads");
+        std::fs::create_dir_all(&uploads).unwrap();
+        let data = b"not really a png";
+        let file_path = uploads.join("abcdef-a.png").to_str().unwrap().to_string();
+        std::fs::write(&file_path, data).unwrap();
+        let file = Attachment {
+            file_path: file_path.clone(),
+            original_name: Some("a.png".to_string()),
+            mime_type: Some("image/png".to_string()),
+            size: Some(data.len() as i64),
+            width: None,
+            height: None,
+            duration: None,
+            thumbnail_path: None,
+            sha256: Some(media::content_hash(data)),
+            md5: None,
+        };
+
+        let conn = db::initialize_db(":memory:").unwrap();
+        db::create_board(&conn, 1, "one", &BoardSettings::default()).unwrap();
+        let op = db::insert_post(&conn, 1, &post(0, "", vec![file]), &Poster::default()).unwrap();
+        let reply = db::insert_post(&conn, 1, &post(op as i32, "", Vec::new()), &Poster::default()).unwrap();
+        // The thread quotes its reply, which comes after it
+        db::update_message(&conn, op, &format!("&gt;&gt;{} &gt;&gt;&gt;/3/{}", reply, reply)).unwrap();
+        db::update_message(&conn, reply, &format!("&gt;&gt;{} &gt;&gt;&gt;/1/{} &gt;&gt;999", op, op)).unwrap();
+
+        let archive = dir.join("one.tar");
+        let report = export_board(&conn, 1, &archive).unwrap();
+        assert_eq!((report.posts, report.files, report.missing), (2, 1, 0));
+
+        // Into another board of the same instance, which has the file already
+        let upload_dir = uploads.to_str().unwrap();
+        let report = import_board(&conn, &archive, upload_dir, Some(2), false).unwrap();
+        assert_eq!((report.board_id, report.posts, report.files, report.reused), (2, 2, 0, 1));
+        let imported = db::board_posts(&conn, 2).unwrap();
+        let (new_op, new_reply) = (imported[0].id as i64, imported[1].id as i64);
+        assert!(new_op > reply && new_reply > new_op);
+        assert_eq!(imported[1].parent_id as i64, new_op);
+        assert_eq!(message(&conn, new_op), format!("&gt;&gt;{} &gt;&gt;&gt;/3/{}", new_reply, reply));
+        assert_eq!(message(&conn, new_reply), format!("&gt;&gt;{} &gt;&gt;&gt;/2/{} &gt;&gt;999", new_op, new_op));
+        assert_eq!(imported[0].files[0].file_path, file_path);
+        // The source board is untouched
+        assert_eq!(message(&conn, reply), format!("&gt;&gt;{} &gt;&gt;&gt;/1/{} &gt;&gt;999", op, op));
+
+        // The board exists now, so only merging adds to it
+        assert!(matches!(import_board(&conn, &archive, upload_dir, Some(2), false), Err(ArchiveError::Format(_))));
+
+        // Into a fresh instance, which has to write the file
+        let other = db::initialize_db(":memory:").unwrap();
+        let other_uploads = dir.join("other");
+        std::fs::create_dir_all(&other_uploads).unwrap();
+        let report = import_board(&other, &archive, other_uploads.to_str().unwrap(), None, false).unwrap();
+        assert_eq!((report.board_id, report.posts, report.files, report.reused), (1, 2, 1, 0));
+        let imported = db::board_posts(&other, 1).unwrap();
+        let written = &imported[0].files[0];
+        assert!(written.file_path.starts_with(other_uploads.to_str().unwrap()));
+        assert_eq!(std::fs::read(&written.file_path).unwrap(), data);
+        assert_eq!(written.md5.as_deref(), Some(media::content_md5(data).as_str()));
+        assert_eq!(db::board_name(&other, 1).unwrap(), "one");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
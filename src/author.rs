@@ -0,0 +1,110 @@
+// Recognizing the author of a post, so they can edit it for a while. The
+// first post from a browser gives it a random key in a cookie signed with
+// the server secret; posts keep an id derived from the key, which can't be
+// turned back into a cookie, so the database alone doesn't let anyone edit.
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const COOKIE_NAME: &str = "adelia_author";
+
+const KEY_LENGTH: usize = 32;
+
+fn mac(secret: &[u8], purpose: &str, key: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}|{}", purpose, key).as_bytes());
+    mac
+}
+
+// What posts store to name their author
+fn author_id(secret: &[u8], key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, "author", key).finalize().into_bytes())
+}
+
+// The author id of the request's browser, if it has a validly signed key
+pub fn author(req: &HttpRequest, secret: &[u8]) -> Option<String> {
+    let cookie = req.cookie(COOKIE_NAME)?;
+    let (key, signature) = cookie.value().split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    mac(secret, "cookie", key).verify_slice(&signature).ok()?;
+    Some(author_id(secret, key))
+}
+
+// A new key for a browser that has none: its author id and the cookie
+pub fn issue(secret: &[u8]) -> (String, Cookie<'static>) {
+    let key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(KEY_LENGTH)
+        .map(char::from)
+        .collect();
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, "cookie", &key).finalize().into_bytes());
+    let cookie = Cookie::build(COOKIE_NAME, format!("{}.{}", key, signature))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::days(365))
+        .finish();
+    (author_id(secret, &key), cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::within_window;
+    use actix_web::test::TestRequest;
+
+    const SECRET: &[u8] = b"server secret";
+
+    fn with_cookie(value: &str) -> HttpRequest {
+        TestRequest::default().cookie(Cookie::new(COOKIE_NAME, value.to_string())).to_http_request()
+    }
+
+    #[test]
+    fn issued_cookie() {
+        let (id, cookie) = issue(SECRET);
+        assert_eq!(author(&with_cookie(cookie.value()), SECRET), Some(id));
+        assert_eq!(author(&TestRequest::default().to_http_request(), SECRET), None);
+    }
+
+    #[test]
+    fn tampered_cookie() {
+        let (_, cookie) = issue(SECRET);
+        let (key, signature) = cookie.value().split_once('.').unwrap();
+        let mut other_key = key.to_string();
+        other_key.replace_range(0..1, if key.starts_with('a') { "b" } else { "a" });
+        assert_eq!(author(&with_cookie(&format!("{}.{}", other_key, signature)), SECRET), None);
+        assert_eq!(author(&with_cookie(&format!("{}.{}", key, &signature[1..])), SECRET), None);
+        assert_eq!(author(&with_cookie(&format!("{}.{}!", key, signature)), SECRET), None);
+        assert_eq!(author(&with_cookie(key), SECRET), None);
+    }
+
+    #[test]
+    fn signed_with_another_secret() {
+        let (_, cookie) = issue(b"other secret");
+        assert_eq!(author(&with_cookie(cookie.value()), SECRET), None);
+    }
+
+    #[test]
+    fn truncated_cookie() {
+        let (_, cookie) = issue(SECRET);
+        let value = cookie.value();
+        for length in [0, 1, value.len() / 2, value.find('.').unwrap() + 1, value.len() - 1] {
+            assert_eq!(author(&with_cookie(&value[..length]), SECRET), None, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn edit_window_boundary() {
+        let window = 15 * 60;
+        assert!(within_window(1000, window, 1000 + window as i64 - 1));
+        assert!(within_window(1000, window, 1000 + window as i64));
+        assert!(!within_window(1000, window, 1000 + window as i64 + 1));
+        // Closed when editing is off
+        assert!(!within_window(1000, 0, 1000));
+    }
+}
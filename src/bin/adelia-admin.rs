@@ -0,0 +1,496 @@
+// Maintenance for an Adelia instance. Works on the database and upload
+// directory named by the same configuration as the server, so it takes the
+// same config file and ADELIA_* variables.
+//
+//   adelia-admin boards create 3 --name Music
+//   adelia-admin boards export 3 music.tar
+//   adelia-admin boards import music.tar --board 7
+//   adelia-admin posts delete 120 121
+//   adelia-admin posts revisions 120
+//   adelia-admin bans add 203.0.113.7 --days 7 --reason spam
+//   adelia-admin migrate-sled ../adelia1/my_database --board 1
+//   adelia-admin --json check
+use clap::{Parser, Subcommand, ValueEnum};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use adelia::config::{Config, Storage};
+use adelia::store::Store;
+use adelia::{admin, archive, db, store};
+
+#[derive(Parser)]
+#[command(name = "adelia-admin", about = "Manage boards, posts and bans of an Adelia instance")]
+struct Cli {
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List, create, rename, delete, export and import boards
+    #[command(subcommand)]
+    Boards(BoardCommand),
+    /// Delete posts and threads, and see earlier versions of edited posts
+    #[command(subcommand)]
+    Posts(PostCommand),
+    /// List, add and lift bans
+    #[command(subcommand)]
+    Bans(BanCommand),
+    /// Rebuild data derived from posts and files
+    Rebuild {
+        #[arg(value_enum, default_value = "all")]
+        what: RebuildTarget,
+    },
+    /// Look for problems in the database and the upload directory
+    Check,
+    /// Move the posts and files of the old sled edition into a board
+    #[cfg(feature = "sled")]
+    MigrateSled {
+        /// The sled edition's `my_database` directory
+        database: PathBuf,
+        #[arg(long)]
+        board: i32,
+        /// Where its files are; defaults to `static` next to the database
+        #[arg(long)]
+        files: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BoardCommand {
+    List,
+    Create {
+        id: i32,
+        #[arg(long, default_value = "")]
+        name: String,
+    },
+    Rename {
+        id: i32,
+        name: String,
+    },
+    /// Delete a board with all its posts and files
+    Delete {
+        id: i32,
+    },
+    /// Write a board's posts and files to a tar archive
+    Export {
+        id: i32,
+        archive: PathBuf,
+    },
+    /// Add the posts and files of an exported board
+    Import {
+        archive: PathBuf,
+        /// Import into this board instead of the one the archive was made from
+        #[arg(long)]
+        board: Option<i32>,
+        /// Add to the board if it exists already
+        #[arg(long)]
+        merge: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PostCommand {
+    /// Delete posts and their files; a thread starter takes its thread with it
+    Delete {
+        #[arg(required = true)]
+        ids: Vec<i32>,
+    },
+    /// Show the titles and messages a post had before its author edited it
+    Revisions {
+        id: i32,
+    },
+}
+
+#[derive(Subcommand)]
+enum BanCommand {
+    List,
+    /// Keep an IP address from posting
+    Add {
+        ip: std::net::IpAddr,
+        /// Only ban from this board
+        #[arg(long)]
+        board: Option<i32>,
+        #[arg(long, default_value = "")]
+        reason: String,
+        /// Lift the ban automatically after this many days
+        #[arg(long)]
+        days: Option<u32>,
+    },
+    Lift {
+        id: i64,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum RebuildTarget {
+    /// File sizes, dimensions, durations, hashes and thumbnails
+    Thumbnails,
+    /// The full-text search index
+    Search,
+    /// Thread bump times, from their newest replies
+    Threads,
+    All,
+}
+
+#[derive(Serialize)]
+struct BoardInfo {
+    id: i32,
+    name: String,
+    threads: i64,
+}
+
+#[derive(Serialize)]
+struct RebuildReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnails: Option<admin::ThumbnailReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    search_index: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Message<'a> {
+    message: &'a str,
+}
+
+// What a command did, as JSON or as text for people
+struct Output {
+    json: serde_json::Value,
+    text: String,
+    // Whether the command found what it was after
+    success: bool,
+}
+
+fn output<T: Serialize>(value: &T, text: String) -> Result<Output, String> {
+    Ok(Output {
+        json: serde_json::to_value(value).map_err(|e| e.to_string())?,
+        text,
+        success: true,
+    })
+}
+
+fn message(text: String) -> Result<Output, String> {
+    output(&Message { message: &text }, text.clone())
+}
+
+fn deleted_text(what: &str, deleted: &db::Deleted) -> String {
+    format!(
+        "Deleted {}: {} post{}, {} file{}",
+        what,
+        deleted.posts,
+        if deleted.posts == 1 { "" } else { "s" },
+        deleted.files.len(),
+        if deleted.files.len() == 1 { "" } else { "s" }
+    )
+}
+
+// Posts go through the post store, which may not be this database. A sled
+// store can't be opened while the server has it open.
+fn open_store(config: &Config) -> Result<Arc<dyn Store>, String> {
+    let conn = db::initialize_db(&config.database_path).map_err(|e| e.to_string())?;
+    store::open(config, Arc::new(Mutex::new(conn))).map_err(|e| e.to_string())
+}
+
+// For commands that read or write the SQLite post tables themselves, which
+// hold nothing the server reads when it keeps posts elsewhere
+fn sqlite_only(config: &Config, command: &str) -> Result<(), String> {
+    match config.storage {
+        Storage::Sqlite => Ok(()),
+        storage => Err(format!(
+            "{} only works with storage = \"sqlite\"; this instance keeps posts in {}",
+            command,
+            storage.name()
+        )),
+    }
+}
+
+fn boards(conn: &Connection, config: &Config, command: BoardCommand) -> Result<Output, String> {
+    match command {
+        BoardCommand::List => {
+            let store = open_store(config)?;
+            let boards = store::list_boards(conn, &*store)
+                .and_then(|boards| {
+                    boards
+                        .into_iter()
+                        .map(|(id, name)| Ok(BoardInfo { id, name, threads: store.count_threads(id)? }))
+                        .collect::<store::StoreResult<Vec<_>>>()
+                })
+                .map_err(|e| e.to_string())?;
+            let text = boards
+                .iter()
+                .map(|board| format!("/{}/\t{}\t{} threads", board.id, board.name, board.threads))
+                .collect::<Vec<_>>()
+                .join("\n");
+            output(&boards, text)
+        }
+        BoardCommand::Create { id, name } => {
+            if db::board_exists(conn, id).map_err(|e| e.to_string())? {
+                return Err(format!("Board {} already exists", id));
+            }
+            db::create_board(conn, id, &name, &db::BoardSettings::default()).map_err(|e| e.to_string())?;
+            message(format!("Created board {}", id))
+        }
+        BoardCommand::Rename { id, name } => {
+            if !db::board_exists(conn, id).map_err(|e| e.to_string())? {
+                return Err(format!("Board {} doesn't exist", id));
+            }
+            db::rename_board(conn, id, &name).map_err(|e| e.to_string())?;
+            message(format!("Renamed board {} to {:?}", id, name))
+        }
+        BoardCommand::Delete { id } => {
+            let store = open_store(config)?;
+            let exists = db::board_exists(conn, id).map_err(|e| e.to_string())?
+                || store.count_threads(id).map_err(|e| e.to_string())? > 0;
+            if !exists {
+                return Err(format!("Board {} doesn't exist", id));
+            }
+            let deleted = admin::delete_board(conn, &*store, id).map_err(|e| e.to_string())?;
+            output(&deleted, deleted_text(&format!("board {}", id), &deleted))
+        }
+        BoardCommand::Export { id, archive } => {
+            sqlite_only(config, "boards export")?;
+            if !db::board_exists(conn, id).map_err(|e| e.to_string())? {
+                return Err(format!("Board {} doesn't exist", id));
+            }
+            let report = archive::export_board(conn, id, &archive).map_err(|e| e.to_string())?;
+            let mut text = format!(
+                "Exported board {} to {}: {} posts, {} files",
+                id,
+                archive.display(),
+                report.posts,
+                report.files
+            );
+            if report.missing > 0 {
+                text.push_str(&format!("; {} files were missing and left out", report.missing));
+            }
+            output(&report, text)
+        }
+        BoardCommand::Import { archive, board, merge } => {
+            sqlite_only(config, "boards import")?;
+            let report = archive::import_board(conn, &archive, &config.upload_dir, board, merge)
+                .map_err(|e| e.to_string())?;
+            let mut text = format!(
+                "Imported {} posts into board {}: {} files written, {} already stored",
+                report.posts, report.board_id, report.files, report.reused
+            );
+            if report.skipped > 0 {
+                text.push_str(&format!("; {} replies without their thread were skipped", report.skipped));
+            }
+            output(&report, text)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PostRevisions {
+    id: i32,
+    title: String,
+    message: String,
+    edited_at: Option<i64>,
+    // Oldest first
+    revisions: Vec<db::Revision>,
+}
+
+fn version_text(label: &str, title: &str, message: &str) -> String {
+    format!("{}\nTitle: {}\n{}\n", label, title, message)
+}
+
+fn posts(conn: &Connection, config: &Config, command: PostCommand) -> Result<Output, String> {
+    let store = open_store(config)?;
+    match command {
+        PostCommand::Delete { ids } => {
+            let mut results = Vec::new();
+            let mut lines = Vec::new();
+            let mut success = true;
+            for id in ids {
+                let deleted = admin::delete_post(conn, &*store, id).map_err(|e| e.to_string())?;
+                if deleted.posts == 0 {
+                    lines.push(format!("Post {} doesn't exist", id));
+                    success = false;
+                } else {
+                    lines.push(deleted_text(&format!("post {}", id), &deleted));
+                }
+                results.push(deleted);
+            }
+            let mut output = output(&results, lines.join("\n"))?;
+            output.success = success;
+            Ok(output)
+        }
+        PostCommand::Revisions { id } => {
+            let post = store
+                .fetch_post(id)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("Post {} doesn't exist", id))?;
+            let revisions = store.revisions(id).map_err(|e| e.to_string())?;
+            let text = if revisions.is_empty() {
+                format!("Post {} hasn't been edited", id)
+            } else {
+                let mut sections: Vec<_> = revisions
+                    .iter()
+                    .enumerate()
+                    .map(|(n, revision)| {
+                        let replaced_at = chrono::DateTime::from_timestamp(revision.replaced_at, 0).unwrap_or_default();
+                        let label = format!("Revision {}, replaced {}", n + 1, replaced_at);
+                        version_text(&label, &revision.title, &revision.message)
+                    })
+                    .collect();
+                sections.push(version_text("Current", &post.title, &post.message));
+                sections.join("\n")
+            };
+            let revisions = PostRevisions {
+                id,
+                title: post.title,
+                message: post.message,
+                edited_at: post.edited_at,
+                revisions,
+            };
+            output(&revisions, text)
+        }
+    }
+}
+
+fn bans(conn: &Connection, command: BanCommand) -> Result<Output, String> {
+    match command {
+        BanCommand::List => {
+            let bans = db::list_bans(conn).map_err(|e| e.to_string())?;
+            let text = bans
+                .iter()
+                .map(|ban| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}",
+                        ban.id,
+                        ban.ip,
+                        ban.board_id.map_or("all boards".to_string(), |board| format!("/{}/", board)),
+                        ban.expires_at.as_ref().map_or("permanent".to_string(), |until| format!("until {}", until)),
+                        ban.reason
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            output(&bans, text)
+        }
+        BanCommand::Add { ip, board, reason, days } => {
+            let id = db::add_ban(conn, &ip.to_string(), board, &reason, days).map_err(|e| e.to_string())?;
+            message(format!("Banned {} (ban {})", ip, id))
+        }
+        BanCommand::Lift { id } => {
+            if !db::lift_ban(conn, id).map_err(|e| e.to_string())? {
+                return Err(format!("Ban {} doesn't exist", id));
+            }
+            message(format!("Lifted ban {}", id))
+        }
+    }
+}
+
+fn rebuild(conn: &Connection, config: &Config, what: RebuildTarget) -> Result<Output, String> {
+    sqlite_only(config, "rebuild")?;
+    let wanted = |target| what == target || what == RebuildTarget::All;
+    let mut report = RebuildReport { thumbnails: None, search_index: None, threads: None };
+    let mut lines = Vec::new();
+    if wanted(RebuildTarget::Thumbnails) {
+        let thumbnails = admin::rebuild_thumbnails(conn).map_err(|e| e.to_string())?;
+        lines.push(format!(
+            "Read {} files, made {} thumbnails; {} files are missing",
+            thumbnails.files, thumbnails.thumbnails, thumbnails.missing
+        ));
+        report.thumbnails = Some(thumbnails);
+    }
+    if wanted(RebuildTarget::Search) {
+        db::rebuild_search_index(conn).map_err(|e| e.to_string())?;
+        lines.push("Rebuilt the search index".to_string());
+        report.search_index = Some(true);
+    }
+    if wanted(RebuildTarget::Threads) {
+        let threads = db::rebuild_bump_times(conn).map_err(|e| e.to_string())?;
+        lines.push(format!("Updated bump times of {} threads", threads));
+        report.threads = Some(threads);
+    }
+    output(&report, lines.join("\n"))
+}
+
+fn check(conn: &Connection, config: &Config) -> Result<Output, String> {
+    sqlite_only(config, "check")?;
+    let problems = admin::check(conn, &config.upload_dir).map_err(|e| e.to_string())?;
+    let text = if problems.is_empty() {
+        "No problems found".to_string()
+    } else {
+        problems
+            .iter()
+            .map(|problem| format!("{}: {}", problem.kind, problem.detail))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let mut output = output(&problems, text)?;
+    output.success = problems.is_empty();
+    Ok(output)
+}
+
+#[cfg(feature = "sled")]
+fn migrate_sled(
+    conn: &Connection,
+    config: &Config,
+    database: PathBuf,
+    board: i32,
+    files: Option<PathBuf>,
+) -> Result<Output, String> {
+    use adelia::migrate;
+
+    sqlite_only(config, "migrate-sled")?;
+    let files = files.unwrap_or_else(|| migrate::default_files_dir(&database));
+    let report = migrate::migrate_sled(conn, &database, &files, &config.upload_dir, board).map_err(|e| e.to_string())?;
+    let mut lines = vec![format!(
+        "Moved {} threads and {} replies into board {}: {} files copied, {} already stored",
+        report.threads, report.replies, report.board_id, report.files, report.reused
+    )];
+    lines.extend(report.missing_files.iter().map(|path| format!("Missing file: {}", path)));
+    lines.extend(report.skipped.iter().map(|entry| format!("Skipped {}", entry)));
+    output(&report, lines.join("\n"))
+}
+
+fn run(command: Command) -> Result<Output, String> {
+    let config = Config::load().map_err(|e| e.to_string())?;
+    let conn = db::initialize_db(&config.database_path).map_err(|e| e.to_string())?;
+    match command {
+        Command::Boards(command) => boards(&conn, &config, command),
+        Command::Posts(command) => posts(&conn, &config, command),
+        Command::Bans(command) => bans(&conn, command),
+        Command::Rebuild { what } => rebuild(&conn, &config, what),
+        Command::Check => check(&conn, &config),
+        #[cfg(feature = "sled")]
+        Command::MigrateSled { database, board, files } => migrate_sled(&conn, &config, database, board, files),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli.command) {
+        Ok(output) => {
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&output.json).unwrap_or_default());
+            } else if !output.text.is_empty() {
+                println!("{}", output.text);
+            }
+            if output.success {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(error) => {
+            if cli.json {
+                println!("{}", serde_json::json!({ "error": error }));
+            } else {
+                eprintln!("Error: {}", error);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
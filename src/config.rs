@@ -0,0 +1,291 @@
+// Settings that can differ between instances. They are read once at startup
+// from a TOML file, `adelia.toml` or the path in ADELIA_CONFIG, and any
+// ADELIA_<SETTING> environment variable overrides the matching setting, e.g.
+// ADELIA_BIND=127.0.0.1:9000. A missing file just means the defaults.
+//
+//   bind = "0.0.0.0:8082"
+//   storage = "sqlite"
+//   database_path = "my_database.db"
+//   sled_path = "sled_database"
+//   upload_dir = "./uploads"
+//   secret_path = "secret.key"
+//   max_file_size = 20971520
+//   max_total_size = 52428800
+//   posts_per_page = 30
+//   max_title_length = 30
+//   max_name_length = 30
+//   max_message_length = 50000
+//   preview_length = 2700
+//   deletion_window = 3600
+//   edit_window = 900
+//
+// The Content-Security-Policy, X-Frame-Options, Referrer-Policy and
+// Permissions-Policy headers can be set for each group of routes (see
+// security.rs): `pages_`, `data_` and `media_` followed by
+// `content_security_policy`, `frame_options`, `referrer_policy` or
+// `permissions_policy`, e.g.
+//
+//   media_frame_options = "DENY"
+//   pages_referrer_policy = "no-referrer"
+//
+// or ADELIA_MEDIA_FRAME_OPTIONS=DENY. An empty value leaves the header out.
+use actix_web::http::header::HeaderValue;
+use serde::Deserialize;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+
+use crate::security;
+
+const DEFAULT_PATH: &str = "adelia.toml";
+const ENV_PREFIX: &str = "ADELIA_";
+
+// Where posts are kept (see store/mod.rs)
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Sqlite,
+    Sled,
+}
+
+impl Storage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Storage::Sqlite => "sqlite",
+            Storage::Sled => "sled",
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            Storage::Sqlite => cfg!(feature = "sqlite"),
+            Storage::Sled => cfg!(feature = "sled"),
+        }
+    }
+}
+
+impl FromStr for Storage {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(Storage::Sqlite),
+            "sled" => Ok(Storage::Sled),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: String,
+    pub storage: Storage,
+    // Board settings, bans and search live here whichever `storage` is used
+    pub database_path: String,
+    pub sled_path: String,
+    // Where uploaded files and their thumbnails are written; they are served
+    // under /media, apart from the stylesheets and scripts in ./static
+    pub upload_dir: String,
+    pub secret_path: String,
+    // Bytes, for one file and for all files of a post together
+    pub max_file_size: usize,
+    pub max_total_size: usize,
+    pub posts_per_page: usize,
+    // Counted after HTML escaping
+    pub max_title_length: usize,
+    pub max_name_length: usize,
+    pub max_message_length: usize,
+    // How much of a long message the board page shows
+    pub preview_length: usize,
+    // Seconds after posting during which posters can delete their post with
+    // its password; 0 turns that off
+    pub deletion_window: u64,
+    // Seconds after posting during which authors can edit the title and
+    // message of their post; 0 turns editing off
+    pub edit_window: u64,
+    // Security headers of pages, of JSON and feeds, and of uploaded files
+    pub pages_content_security_policy: String,
+    pub pages_frame_options: String,
+    pub pages_referrer_policy: String,
+    pub pages_permissions_policy: String,
+    pub data_content_security_policy: String,
+    pub data_frame_options: String,
+    pub data_referrer_policy: String,
+    pub data_permissions_policy: String,
+    pub media_content_security_policy: String,
+    pub media_frame_options: String,
+    pub media_referrer_policy: String,
+    pub media_permissions_policy: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "0.0.0.0:8082".to_string(),
+            storage: Storage::Sqlite,
+            database_path: "my_database.db".to_string(),
+            sled_path: "sled_database".to_string(),
+            upload_dir: "./uploads".to_string(),
+            secret_path: "secret.key".to_string(),
+            max_file_size: 20 * 1024 * 1024,
+            max_total_size: 50 * 1024 * 1024,
+            posts_per_page: 30,
+            max_title_length: 30,
+            max_name_length: 30,
+            max_message_length: 50000,
+            preview_length: 2700,
+            deletion_window: 60 * 60,
+            edit_window: 15 * 60,
+            pages_content_security_policy: security::PAGES_CONTENT_SECURITY_POLICY.to_string(),
+            pages_frame_options: security::PAGES_FRAME_OPTIONS.to_string(),
+            pages_referrer_policy: security::PAGES_REFERRER_POLICY.to_string(),
+            pages_permissions_policy: security::PAGES_PERMISSIONS_POLICY.to_string(),
+            data_content_security_policy: security::DATA_CONTENT_SECURITY_POLICY.to_string(),
+            data_frame_options: security::DATA_FRAME_OPTIONS.to_string(),
+            data_referrer_policy: security::DATA_REFERRER_POLICY.to_string(),
+            data_permissions_policy: security::DATA_PERMISSIONS_POLICY.to_string(),
+            media_content_security_policy: security::MEDIA_CONTENT_SECURITY_POLICY.to_string(),
+            media_frame_options: security::MEDIA_FRAME_OPTIONS.to_string(),
+            media_referrer_policy: security::MEDIA_REFERRER_POLICY.to_string(),
+            media_permissions_policy: security::MEDIA_PERMISSIONS_POLICY.to_string(),
+        }
+    }
+}
+
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Env(String, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Unable to read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path, e),
+            ConfigError::Env(name, value) => write!(f, "Invalid value for {}: {:?}", name, value),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(e: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+fn override_from_env<T: FromStr>(setting: &mut T, name: &str) -> Result<(), ConfigError> {
+    let name = format!("{}{}", ENV_PREFIX, name);
+    if let Ok(value) = std::env::var(&name) {
+        *setting = value.trim().parse().map_err(|_| ConfigError::Env(name, value))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn load() -> Result<Config, ConfigError> {
+        let path = std::env::var(format!("{}CONFIG", ENV_PREFIX));
+        let explicit = path.is_ok();
+        let path = path.unwrap_or_else(|_| DEFAULT_PATH.to_string());
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?,
+            // Only a file that was asked for has to exist
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Config::default(),
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        override_from_env(&mut self.bind, "BIND")?;
+        override_from_env(&mut self.storage, "STORAGE")?;
+        override_from_env(&mut self.database_path, "DATABASE_PATH")?;
+        override_from_env(&mut self.sled_path, "SLED_PATH")?;
+        override_from_env(&mut self.upload_dir, "UPLOAD_DIR")?;
+        override_from_env(&mut self.secret_path, "SECRET_PATH")?;
+        override_from_env(&mut self.max_file_size, "MAX_FILE_SIZE")?;
+        override_from_env(&mut self.max_total_size, "MAX_TOTAL_SIZE")?;
+        override_from_env(&mut self.posts_per_page, "POSTS_PER_PAGE")?;
+        override_from_env(&mut self.max_title_length, "MAX_TITLE_LENGTH")?;
+        override_from_env(&mut self.max_name_length, "MAX_NAME_LENGTH")?;
+        override_from_env(&mut self.max_message_length, "MAX_MESSAGE_LENGTH")?;
+        override_from_env(&mut self.preview_length, "PREVIEW_LENGTH")?;
+        override_from_env(&mut self.deletion_window, "DELETION_WINDOW")?;
+        override_from_env(&mut self.edit_window, "EDIT_WINDOW")?;
+        override_from_env(&mut self.pages_content_security_policy, "PAGES_CONTENT_SECURITY_POLICY")?;
+        override_from_env(&mut self.pages_frame_options, "PAGES_FRAME_OPTIONS")?;
+        override_from_env(&mut self.pages_referrer_policy, "PAGES_REFERRER_POLICY")?;
+        override_from_env(&mut self.pages_permissions_policy, "PAGES_PERMISSIONS_POLICY")?;
+        override_from_env(&mut self.data_content_security_policy, "DATA_CONTENT_SECURITY_POLICY")?;
+        override_from_env(&mut self.data_frame_options, "DATA_FRAME_OPTIONS")?;
+        override_from_env(&mut self.data_referrer_policy, "DATA_REFERRER_POLICY")?;
+        override_from_env(&mut self.data_permissions_policy, "DATA_PERMISSIONS_POLICY")?;
+        override_from_env(&mut self.media_content_security_policy, "MEDIA_CONTENT_SECURITY_POLICY")?;
+        override_from_env(&mut self.media_frame_options, "MEDIA_FRAME_OPTIONS")?;
+        override_from_env(&mut self.media_referrer_policy, "MEDIA_REFERRER_POLICY")?;
+        override_from_env(&mut self.media_permissions_policy, "MEDIA_PERMISSIONS_POLICY")?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+        if self.bind.to_socket_addrs().map_or(true, |mut addrs| addrs.next().is_none()) {
+            return invalid(format!("bind address {:?} is not a host and port", self.bind));
+        }
+        if !self.storage.enabled() {
+            return invalid(format!("storage {:?} was not enabled when building", self.storage.name()));
+        }
+        for (name, value) in [
+            ("database_path", &self.database_path),
+            ("sled_path", &self.sled_path),
+            ("upload_dir", &self.upload_dir),
+            ("secret_path", &self.secret_path),
+        ] {
+            if value.trim().is_empty() {
+                return invalid(format!("{} is empty", name));
+            }
+        }
+        for (name, value) in [
+            ("max_file_size", self.max_file_size),
+            ("max_total_size", self.max_total_size),
+            ("posts_per_page", self.posts_per_page),
+            ("max_title_length", self.max_title_length),
+            ("max_name_length", self.max_name_length),
+            ("max_message_length", self.max_message_length),
+            ("preview_length", self.preview_length),
+        ] {
+            if value == 0 {
+                return invalid(format!("{} must be greater than 0", name));
+            }
+        }
+        // Checked here rather than when the headers are added, which panics
+        for (name, value) in [
+            ("pages_content_security_policy", &self.pages_content_security_policy),
+            ("pages_frame_options", &self.pages_frame_options),
+            ("pages_referrer_policy", &self.pages_referrer_policy),
+            ("pages_permissions_policy", &self.pages_permissions_policy),
+            ("data_content_security_policy", &self.data_content_security_policy),
+            ("data_frame_options", &self.data_frame_options),
+            ("data_referrer_policy", &self.data_referrer_policy),
+            ("data_permissions_policy", &self.data_permissions_policy),
+            ("media_content_security_policy", &self.media_content_security_policy),
+            ("media_frame_options", &self.media_frame_options),
+            ("media_referrer_policy", &self.media_referrer_policy),
+            ("media_permissions_policy", &self.media_permissions_policy),
+        ] {
+            if HeaderValue::from_str(value.trim()).is_err() {
+                return invalid(format!("{} is not a valid header value", name));
+            }
+        }
+        if self.max_total_size < self.max_file_size {
+            return invalid("max_total_size is smaller than max_file_size".to_string());
+        }
+        Ok(())
+    }
+}
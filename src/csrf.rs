@@ -0,0 +1,176 @@
+// Protection against other sites posting through a visitor's browser. Each
+// browser gets a random session cookie, and forms carry a token derived from
+// it with the server secret, so a site that can't read the cookie can't make
+// a valid form. The Origin (or Referer) header is checked as well. Posts to
+// /api/ only get that check, since API clients have no session: what keeps
+// other sites out there is that browsers name them in Origin.
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const COOKIE_NAME: &str = "adelia_session";
+// Form field holding the token; forms put it first, before any file
+pub const FIELD_NAME: &str = "csrf_token";
+
+const SESSION_LENGTH: usize = 32;
+
+// The visitor's session, and the cookie to set when they didn't have one yet
+pub fn session(req: &HttpRequest) -> (String, Option<Cookie<'static>>) {
+    if let Some(cookie) = req.cookie(COOKIE_NAME).filter(|cookie| cookie.value().len() == SESSION_LENGTH) {
+        return (cookie.value().to_string(), None);
+    }
+    let session: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_LENGTH)
+        .map(char::from)
+        .collect();
+    let cookie = Cookie::build(COOKIE_NAME, session.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .finish();
+    (session, Some(cookie))
+}
+
+fn mac(secret: &[u8], session: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(b"csrf|");
+    mac.update(session.as_bytes());
+    mac
+}
+
+pub fn token(secret: &[u8], session: &str) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, session).finalize().into_bytes())
+}
+
+// Whether a post must carry a token; API posts rely on `same_origin` alone
+pub fn token_required(req: &HttpRequest) -> bool {
+    !req.path().starts_with("/api/")
+}
+
+// Whether the token was made for the session the request's cookie names
+pub fn verify(req: &HttpRequest, secret: &[u8], token: &str) -> bool {
+    let session = match req.cookie(COOKIE_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return false,
+    };
+    match URL_SAFE_NO_PAD.decode(token.trim()) {
+        Ok(token) => mac(secret, &session).verify_slice(&token).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// The `host[:port]` of a URL. Only the host is compared, since behind a
+// proxy the scheme the server sees may not be the one the browser used.
+fn host_of(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let host = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+    Some(host).filter(|host| !host.is_empty())
+}
+
+// False when the browser says the request comes from a page of another
+// site. Browsers send Origin with every cross-site POST; Referer is the
+// fallback for older ones. Clients that send neither aren't browsers.
+pub fn same_origin(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    let source = match headers.get("Origin").or_else(|| headers.get("Referer")) {
+        Some(source) => source.to_str().unwrap_or(""),
+        None => return true,
+    };
+    let info = req.connection_info();
+    host_of(source).is_some_and(|host| host.eq_ignore_ascii_case(info.host()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    const SECRET: &[u8] = b"secret";
+    const SESSION: &str = "0123456789abcdef0123456789abcdef";
+
+    fn with_session(session: &str) -> HttpRequest {
+        TestRequest::default().cookie(Cookie::new(COOKIE_NAME, session.to_string())).to_http_request()
+    }
+
+    // A request to example.com with the given headers
+    fn from(headers: &[(&'static str, &str)]) -> HttpRequest {
+        let mut req = TestRequest::post().uri("/1/upload").insert_header(("Host", "example.com"));
+        for (name, value) in headers {
+            req = req.insert_header((*name, value.to_string()));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn valid_token() {
+        let token = token(SECRET, SESSION);
+        assert!(verify(&with_session(SESSION), SECRET, &token));
+        assert!(verify(&with_session(SESSION), SECRET, &format!(" {}\n", token)));
+    }
+
+    #[test]
+    fn token_of_another_session() {
+        let token = token(SECRET, "ffffffffffffffffffffffffffffffff");
+        assert!(!verify(&with_session(SESSION), SECRET, &token));
+        // Or signed with another secret
+        assert!(!verify(&with_session(SESSION), b"other", &super::token(b"other", "x")));
+        assert!(!verify(&with_session(SESSION), SECRET, &super::token(b"other", SESSION)));
+    }
+
+    #[test]
+    fn missing_or_malformed_token() {
+        let token = token(SECRET, SESSION);
+        assert!(!verify(&TestRequest::default().to_http_request(), SECRET, &token));
+        assert!(!verify(&with_session(SESSION), SECRET, ""));
+        assert!(!verify(&with_session(SESSION), SECRET, "not base64!"));
+        assert!(!verify(&with_session(SESSION), SECRET, &token[..token.len() - 4]));
+        assert!(!verify(&with_session(SESSION), SECRET, &format!("{}AAAA", token)));
+    }
+
+    #[test]
+    fn origin() {
+        assert!(same_origin(&from(&[("Origin", "http://example.com")])));
+        assert!(!same_origin(&from(&[("Origin", "http://evil.example")])));
+        assert!(!same_origin(&from(&[("Origin", "http://example.com.evil.example")])));
+        // Sandboxed frames and some redirects
+        assert!(!same_origin(&from(&[("Origin", "null")])));
+        assert!(!same_origin(&from(&[("Origin", "")])));
+    }
+
+    #[test]
+    fn referer_fallback() {
+        assert!(same_origin(&from(&[("Referer", "http://example.com/1/post/2?x#p3")])));
+        assert!(!same_origin(&from(&[("Referer", "http://evil.example/example.com")])));
+        // Origin wins over Referer
+        assert!(!same_origin(&from(&[("Origin", "http://evil.example"), ("Referer", "http://example.com/")])));
+        // Neither: not a browser
+        assert!(same_origin(&from(&[])));
+    }
+
+    #[test]
+    fn ports_scheme_and_case() {
+        assert!(!same_origin(&from(&[("Origin", "http://example.com:8082")])));
+        let req = TestRequest::post().insert_header(("Host", "example.com:8082"));
+        assert!(same_origin(&req.insert_header(("Origin", "http://example.com:8082")).to_http_request()));
+        let req = TestRequest::post().insert_header(("Host", "example.com:8082"));
+        assert!(!same_origin(&req.insert_header(("Origin", "http://example.com:9000")).to_http_request()));
+        // The scheme may have been changed by a proxy; host names aren't case sensitive
+        assert!(same_origin(&from(&[("Origin", "https://example.com")])));
+        assert!(same_origin(&from(&[("Origin", "http://EXAMPLE.com")])));
+    }
+
+    #[test]
+    fn api_posts_only_check_the_origin() {
+        assert!(token_required(&from(&[])));
+        let api = TestRequest::post().uri("/api/1/upload").insert_header(("Host", "example.com"));
+        assert!(!token_required(&api.to_http_request()));
+        let api = TestRequest::post().uri("/api/1/upload").insert_header(("Host", "example.com"));
+        assert!(!same_origin(&api.insert_header(("Origin", "http://evil.example")).to_http_request()));
+    }
+}
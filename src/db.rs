@@ -0,0 +1,1114 @@
+use mime_guess::MimeGuess;
+use rusqlite::{params, Connection, Result as SqlResult, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::{media, media_info};
+
+// Attachments allowed per post on boards that don't set their own limit
+pub const DEFAULT_MAX_FILES: usize = 4;
+
+// Timestamps are stored as SQLite TIMESTAMP text and read back as unix seconds.
+// Attachments come back as one JSON array, in order.
+const POST_COLUMNS: &str = "id, post_id, parent_id, title, message, \
+    (SELECT json_group_array(json_object('file_path', file_path, 'original_name', original_name, \
+        'mime_type', mime_type, 'size', size, 'width', width, 'height', height, 'duration', duration, \
+        'thumbnail_path', thumbnail_path, 'sha256', sha256, 'md5', md5)) FROM \
+        (SELECT * FROM attachments WHERE attachments.post = files.id ORDER BY position)), \
+    name, tripcode, poster_id, \
+    CAST(strftime('%s', created_at) AS INTEGER), CAST(strftime('%s', last_reply_at) AS INTEGER), \
+    CAST(strftime('%s', edited_at) AS INTEGER)";
+
+// Whether the `files` row has at least one attachment
+const HAS_FILE: &str = "EXISTS(SELECT 1 FROM attachments WHERE attachments.post = files.id)";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub file_path: String,
+    // As uploaded, before sanitizing and prefixing
+    pub original_name: Option<String>,
+    pub mime_type: Option<String>,
+    // Bytes
+    pub size: Option<i64>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    // Seconds
+    pub duration: Option<f64>,
+    // Shown in place of large images, and as the cover of audio and video
+    pub thumbnail_path: Option<String>,
+    // Hex digest of the stored file; the same file may be shared by posts
+    pub sha256: Option<String>,
+    // Base64 digest, given to API clients
+    pub md5: Option<String>,
+}
+
+impl Attachment {
+    // Stored names are `{6 random chars}-{sanitized original name}`, which
+    // is the best guess for files uploaded before names were kept
+    pub fn display_name(&self) -> &str {
+        self.original_name.as_deref().unwrap_or_else(|| {
+            let stored = self.file_path.rsplit('/').next().unwrap_or(&self.file_path);
+            stored.split_once('-').map(|(_, original)| original).unwrap_or(stored)
+        })
+    }
+
+    // Points the file and thumbnail kept directly in `from` at the same
+    // names in `to`; false when neither was in `from`
+    pub fn move_dir(&mut self, from: &str, to: &str) -> bool {
+        let prefix = format!("{}/", from.trim_end_matches('/'));
+        let to = to.trim_end_matches('/');
+        let mut moved = false;
+        for path in std::iter::once(&mut self.file_path).chain(self.thumbnail_path.as_mut()) {
+            if let Some(name) = path.strip_prefix(&prefix) {
+                *path = format!("{}/{}", to, name);
+                moved = true;
+            }
+        }
+        moved
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Post {
+    pub id: i32,
+    pub post_id: String,
+    pub parent_id: i32,
+    pub title: String,
+    pub message: String,
+    // In upload order
+    pub files: Vec<Attachment>,
+    pub name: Option<String>,
+    pub tripcode: Option<String>,
+    pub poster_id: Option<String>,
+    pub created_at: i64,
+    pub last_reply_at: i64,
+    // When the author last changed the title or message
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+}
+
+impl Post {
+    fn from_row(row: &Row) -> SqlResult<Post> {
+        Ok(Post {
+            id: row.get(0)?,
+            post_id: row.get(1)?,
+            parent_id: row.get(2)?,
+            title: row.get(3)?,
+            message: row.get(4)?,
+            files: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+            name: row.get(6)?,
+            tripcode: row.get(7)?,
+            poster_id: row.get(8)?,
+            created_at: row.get::<_, Option<i64>>(9)?.unwrap_or(0),
+            last_reply_at: row.get::<_, Option<i64>>(10)?.unwrap_or(0),
+            edited_at: row.get(11)?,
+        })
+    }
+}
+
+// A title and message a post had before it was edited
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub title: String,
+    pub message: String,
+    // When the edit replaced it
+    pub replaced_at: i64,
+}
+
+// What a post is stored with besides itself, for its poster: the hash of
+// its deletion password (see deletion.rs), its author id (see author.rs),
+// and how its poster id follows from the id of its thread, which for a new
+// thread is only known once the post is being stored
+#[derive(Default)]
+pub struct Poster<'a> {
+    pub deletion_password: Option<String>,
+    pub author: Option<String>,
+    pub poster_id: Option<&'a dyn Fn(i32) -> String>,
+}
+
+impl Poster<'_> {
+    // The poster id of a post with this id, or the one it already has
+    pub fn poster_id(&self, post: &Post, id: i32) -> Option<String> {
+        let thread_id = if post.parent_id == 0 { id } else { post.parent_id };
+        match self.poster_id {
+            Some(poster_id) => Some(poster_id(thread_id)),
+            None => post.poster_id.clone(),
+        }
+    }
+}
+
+// Boards without a row in `boards` use the defaults
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct BoardSettings {
+    pub force_anonymous: bool,
+    pub poster_ids: bool,
+    pub max_files: usize,
+    // Decode and encode images again instead of only stripping metadata
+    pub reencode_images: bool,
+    // Let the EXIF orientation survive metadata stripping
+    pub keep_orientation: bool,
+}
+
+impl Default for BoardSettings {
+    fn default() -> BoardSettings {
+        BoardSettings {
+            force_anonymous: false,
+            poster_ids: false,
+            max_files: DEFAULT_MAX_FILES,
+            reencode_images: false,
+            keep_orientation: false,
+        }
+    }
+}
+
+pub fn board_settings(conn: &Connection, board_id: i32) -> BoardSettings {
+    conn.query_row(
+        "SELECT force_anonymous, poster_ids, max_files, reencode_images, keep_orientation FROM boards WHERE id = ?1",
+        params![board_id],
+        |row| {
+            Ok(BoardSettings {
+                force_anonymous: row.get(0)?,
+                poster_ids: row.get(1)?,
+                max_files: row.get::<_, i64>(2)?.max(0) as usize,
+                reencode_images: row.get(3)?,
+                keep_orientation: row.get(4)?,
+            })
+        },
+    )
+    .unwrap_or_default()
+}
+
+pub fn board_name(conn: &Connection, board_id: i32) -> SqlResult<String> {
+    let mut stmt = conn.prepare("SELECT name FROM boards WHERE id = ?1")?;
+    let mut names = stmt.query_map(params![board_id], |row| row.get(0))?;
+    Ok(names.next().transpose()?.unwrap_or_default())
+}
+
+// Boards with a row in `boards`, with their names
+pub fn board_names(conn: &Connection) -> SqlResult<Vec<(i32, String)>> {
+    let mut stmt = conn.prepare("SELECT id, name FROM boards ORDER BY id")?;
+    let boards = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+    boards
+}
+
+pub fn post_board(conn: &Connection, id: i32) -> SqlResult<Option<i32>> {
+    let mut stmt = conn.prepare("SELECT board_id FROM files WHERE id = ?1")?;
+    let mut boards = stmt.query_map(params![id], |row| row.get(0))?;
+    boards.next().transpose()
+}
+
+// Boards with at least one post, in order
+pub fn posted_boards(conn: &Connection) -> SqlResult<Vec<i32>> {
+    let mut stmt = conn.prepare("SELECT DISTINCT board_id FROM files ORDER BY board_id")?;
+    let boards = stmt.query_map([], |row| row.get(0))?.collect();
+    boards
+}
+
+// The post itself followed by its replies, oldest first
+pub fn fetch_thread(conn: &Connection, board_id: i32, thread_id: i32) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE (id = ?1 OR parent_id = ?1) AND board_id = ?2 ORDER BY id ASC",
+        POST_COLUMNS
+    ))?;
+    let posts = stmt
+        .query_map(params![thread_id, board_id], Post::from_row)?
+        .collect();
+    posts
+}
+
+// All posts of a board, oldest first, so threads come before their replies
+pub fn board_posts(conn: &Connection, board_id: i32) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM files WHERE board_id = ?1 ORDER BY id ASC", POST_COLUMNS))?;
+    let posts = stmt.query_map(params![board_id], Post::from_row)?.collect();
+    posts
+}
+
+pub fn fetch_post(conn: &Connection, id: i32) -> SqlResult<Option<Post>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM files WHERE id = ?1", POST_COLUMNS))?;
+    let mut posts = stmt.query_map(params![id], Post::from_row)?;
+    posts.next().transpose()
+}
+
+// Posts on a board, or in one thread of it, newer than `after_id`
+pub fn posts_after(conn: &Connection, board_id: i32, thread_id: Option<i32>, after_id: i32, limit: usize) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE board_id = ?1 AND id > ?2 AND (?3 IS NULL OR id = ?3 OR parent_id = ?3) ORDER BY id ASC LIMIT ?4",
+        POST_COLUMNS
+    ))?;
+    let posts = stmt
+        .query_map(params![board_id, after_id, thread_id, limit as i64], Post::from_row)?
+        .collect();
+    posts
+}
+
+// 0 for a thread starter, otherwise the reply's position within its thread
+pub fn reply_number(conn: &Connection, post: &Post) -> SqlResult<i64> {
+    if post.parent_id == 0 {
+        return Ok(0);
+    }
+    conn.query_row(
+        "SELECT COUNT(*) FROM files WHERE parent_id = ?1 AND id <= ?2",
+        params![post.parent_id, post.id],
+        |row| row.get(0),
+    )
+}
+
+// Stores a post's files in the order they were uploaded
+pub fn add_attachments(conn: &Connection, post: i64, files: &[Attachment]) -> SqlResult<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO attachments (post, position, file_path, original_name, mime_type, size, width, height, duration, thumbnail_path, sha256, md5)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?;
+    for (position, file) in files.iter().enumerate() {
+        stmt.execute(params![
+            post,
+            position as i64,
+            file.file_path,
+            file.original_name,
+            file.mime_type,
+            file.size,
+            file.width,
+            file.height,
+            file.duration,
+            file.thumbnail_path,
+            file.sha256,
+            file.md5
+        ])?;
+    }
+    Ok(())
+}
+
+// Whether the board has a row in `boards` or any posts
+pub fn board_exists(conn: &Connection, board_id: i32) -> SqlResult<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM boards WHERE id = ?1) OR EXISTS(SELECT 1 FROM files WHERE board_id = ?1)",
+        params![board_id],
+        |row| row.get(0),
+    )
+}
+
+pub fn create_board(conn: &Connection, board_id: i32, name: &str, settings: &BoardSettings) -> SqlResult<()> {
+    conn.execute(
+        "INSERT INTO boards (id, name, force_anonymous, poster_ids, max_files, reencode_images, keep_orientation)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            board_id,
+            name,
+            settings.force_anonymous,
+            settings.poster_ids,
+            settings.max_files as i64,
+            settings.reencode_images,
+            settings.keep_orientation
+        ],
+    )?;
+    Ok(())
+}
+
+// Boards that only exist through their posts get a row with the defaults
+pub fn rename_board(conn: &Connection, board_id: i32, name: &str) -> SqlResult<()> {
+    conn.execute(
+        "INSERT INTO boards (id, name) VALUES (?1, ?2) ON CONFLICT (id) DO UPDATE SET name = excluded.name",
+        params![board_id, name],
+    )?;
+    Ok(())
+}
+
+// What a deletion took with it. The files are only removed from the
+// database; the caller deletes them from disk once that is committed. Files
+// that other posts still use are left out.
+#[derive(Default, Serialize)]
+pub struct Deleted {
+    pub posts: usize,
+    pub files: Vec<String>,
+}
+
+// Attachments of the posts `posts` selects (with `?1` bound to `value`),
+// returning the files no other post uses
+fn delete_attachments(conn: &Connection, posts: &str, value: i32) -> SqlResult<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT file_path, thumbnail_path FROM attachments WHERE post IN ({})",
+        posts
+    ))?;
+    let mut files = Vec::new();
+    for row in stmt.query_map(params![value], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))? {
+        let (file_path, thumbnail_path) = row?;
+        files.push(file_path);
+        files.extend(thumbnail_path);
+    }
+    conn.execute(&format!("DELETE FROM attachments WHERE post IN ({})", posts), params![value])?;
+    let mut still_used = conn.prepare(
+        "SELECT EXISTS(SELECT 1 FROM attachments WHERE file_path = ?1 OR thumbnail_path = ?1)",
+    )?;
+    let mut unused = Vec::new();
+    for file in files {
+        if !still_used.query_row(params![file], |row| row.get::<_, bool>(0))? {
+            unused.push(file);
+        }
+    }
+    unused.sort();
+    unused.dedup();
+    Ok(unused)
+}
+
+// Posts matching `filter` (with `?1` bound to `value`) and their attachments
+fn delete_posts(conn: &Connection, filter: &str, value: i32) -> SqlResult<Deleted> {
+    let files = delete_attachments(conn, &format!("SELECT id FROM files WHERE {}", filter), value)?;
+    conn.execute(
+        &format!("DELETE FROM revisions WHERE post IN (SELECT id FROM files WHERE {})", filter),
+        params![value],
+    )?;
+    let posts = conn.execute(&format!("DELETE FROM files WHERE {}", filter), params![value])?;
+    Ok(Deleted { posts, files })
+}
+
+// A reply on its own, or a thread starter with its whole thread
+pub fn delete_post(conn: &Connection, id: i32) -> SqlResult<Deleted> {
+    delete_posts(conn, "id = ?1 OR parent_id = ?1", id)
+}
+
+// The post's attachments, leaving the post
+pub fn delete_files(conn: &Connection, id: i32) -> SqlResult<Deleted> {
+    let files = delete_attachments(conn, "SELECT ?1", id)?;
+    Ok(Deleted { posts: 0, files })
+}
+
+// Everything posted to the board
+pub fn delete_board_posts(conn: &Connection, board_id: i32) -> SqlResult<Deleted> {
+    delete_posts(conn, "board_id = ?1", board_id)
+}
+
+// The board's name and settings
+pub fn delete_board_settings(conn: &Connection, board_id: i32) -> SqlResult<()> {
+    conn.execute("DELETE FROM boards WHERE id = ?1", params![board_id])?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Ban {
+    pub id: i64,
+    pub ip: String,
+    // None for a ban on every board
+    pub board_id: Option<i32>,
+    pub reason: String,
+    pub created_at: String,
+    // None for a ban that doesn't run out
+    pub expires_at: Option<String>,
+}
+
+impl Ban {
+    fn from_row(row: &Row) -> SqlResult<Ban> {
+        Ok(Ban {
+            id: row.get(0)?,
+            ip: row.get(1)?,
+            board_id: row.get(2)?,
+            reason: row.get(3)?,
+            created_at: row.get(4)?,
+            expires_at: row.get(5)?,
+        })
+    }
+}
+
+const BAN_COLUMNS: &str = "id, ip, board_id, reason, created_at, expires_at";
+const BAN_ACTIVE: &str = "(expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)";
+
+pub fn add_ban(conn: &Connection, ip: &str, board_id: Option<i32>, reason: &str, days: Option<u32>) -> SqlResult<i64> {
+    conn.execute(
+        "INSERT INTO bans (ip, board_id, reason, expires_at)
+         VALUES (?1, ?2, ?3, CASE WHEN ?4 IS NULL THEN NULL ELSE datetime('now', '+' || ?4 || ' days') END)",
+        params![ip, board_id, reason, days],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+// Bans that haven't run out, newest first
+pub fn list_bans(conn: &Connection) -> SqlResult<Vec<Ban>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM bans WHERE {} ORDER BY id DESC",
+        BAN_COLUMNS, BAN_ACTIVE
+    ))?;
+    let bans = stmt.query_map([], Ban::from_row)?.collect();
+    bans
+}
+
+// Returns whether there was such a ban
+pub fn lift_ban(conn: &Connection, id: i64) -> SqlResult<bool> {
+    Ok(conn.execute("DELETE FROM bans WHERE id = ?1", params![id])? > 0)
+}
+
+// The ban keeping `ip` from posting on the board, if any
+pub fn active_ban(conn: &Connection, ip: &str, board_id: i32) -> SqlResult<Option<Ban>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM bans WHERE ip = ?1 AND (board_id IS NULL OR board_id = ?2) AND {} ORDER BY id DESC LIMIT 1",
+        BAN_COLUMNS, BAN_ACTIVE
+    ))?;
+    let mut bans = stmt.query_map(params![ip, board_id], Ban::from_row)?;
+    bans.next().transpose()
+}
+
+// A deletion made by the admin tool, left for the server to pass on to
+// open pages (see live.rs)
+pub struct DeletionNotice {
+    pub id: i64,
+    pub board_id: i32,
+    pub thread_id: i32,
+    pub post: i32,
+    pub file_only: bool,
+}
+
+pub fn add_deletion_notice(conn: &Connection, board_id: i32, thread_id: i32, post: i32, file_only: bool) -> SqlResult<()> {
+    conn.execute(
+        "INSERT INTO deletion_notices (board_id, thread_id, post, file_only) VALUES (?1, ?2, ?3, ?4)",
+        params![board_id, thread_id, post, file_only],
+    )?;
+    Ok(())
+}
+
+// The id of the newest notice, or 0
+pub fn last_deletion_notice(conn: &Connection) -> SqlResult<i64> {
+    conn.query_row("SELECT COALESCE(MAX(id), 0) FROM deletion_notices", [], |row| row.get(0))
+}
+
+// Notices newer than `after_id`, oldest first. Those older than an hour
+// have been passed on long ago and are dropped.
+pub fn deletion_notices_after(conn: &Connection, after_id: i64) -> SqlResult<Vec<DeletionNotice>> {
+    conn.execute("DELETE FROM deletion_notices WHERE created_at < datetime('now', '-1 hour')", [])?;
+    let mut stmt = conn.prepare(
+        "SELECT id, board_id, thread_id, post, file_only FROM deletion_notices WHERE id > ?1 ORDER BY id",
+    )?;
+    let notices = stmt
+        .query_map(params![after_id], |row| {
+            Ok(DeletionNotice {
+                id: row.get(0)?,
+                board_id: row.get(1)?,
+                thread_id: row.get(2)?,
+                post: row.get(3)?,
+                file_only: row.get(4)?,
+            })
+        })?
+        .collect();
+    notices
+}
+
+// Every attachment with its row id, for maintenance that walks all files
+const ATTACHMENT_COLUMNS: &str =
+    "id, file_path, original_name, mime_type, size, width, height, duration, thumbnail_path, sha256, md5";
+
+fn attachment_from_row(row: &Row) -> SqlResult<(i64, Attachment)> {
+    Ok((
+        row.get(0)?,
+        Attachment {
+            file_path: row.get(1)?,
+            original_name: row.get(2)?,
+            mime_type: row.get(3)?,
+            size: row.get(4)?,
+            width: row.get(5)?,
+            height: row.get(6)?,
+            duration: row.get(7)?,
+            thumbnail_path: row.get(8)?,
+            sha256: row.get(9)?,
+            md5: row.get(10)?,
+        },
+    ))
+}
+
+pub fn list_attachments(conn: &Connection) -> SqlResult<Vec<(i64, Attachment)>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM attachments ORDER BY id", ATTACHMENT_COLUMNS))?;
+    let attachments = stmt.query_map([], attachment_from_row)?.collect();
+    attachments
+}
+
+// The attachment whose file or thumbnail is stored under `name`, in any
+// directory
+pub fn find_attachment(conn: &Connection, name: &str) -> SqlResult<Option<Attachment>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM attachments
+         WHERE substr(file_path, -length(?1) - 1) = '/' || ?1 OR substr(thumbnail_path, -length(?1) - 1) = '/' || ?1
+         ORDER BY id LIMIT 1",
+        ATTACHMENT_COLUMNS
+    ))?;
+    let mut attachments = stmt.query_map(params![name], attachment_from_row)?;
+    Ok(attachments.next().transpose()?.map(|(_, attachment)| attachment))
+}
+
+// Stores what was read from the file again
+pub fn update_attachment(conn: &Connection, id: i64, file: &Attachment) -> SqlResult<()> {
+    conn.execute(
+        "UPDATE attachments SET size = ?1, width = ?2, height = ?3, duration = ?4, thumbnail_path = ?5, sha256 = ?6, md5 = ?7
+         WHERE id = ?8",
+        params![file.size, file.width, file.height, file.duration, file.thumbnail_path, file.sha256, file.md5, id],
+    )?;
+    Ok(())
+}
+
+// Points attachments kept directly in `from` at the same names in `to`,
+// returning how many changed
+pub fn move_files(conn: &Connection, from: &str, to: &str) -> SqlResult<usize> {
+    let mut moved = 0;
+    for (id, mut file) in list_attachments(conn)? {
+        if file.move_dir(from, to) {
+            conn.execute(
+                "UPDATE attachments SET file_path = ?1, thumbnail_path = ?2 WHERE id = ?3",
+                params![file.file_path, file.thumbnail_path, id],
+            )?;
+            moved += 1;
+        }
+    }
+    Ok(moved)
+}
+
+// A stored file with this content, and its thumbnail
+pub fn file_with_hash(conn: &Connection, sha256: &str) -> SqlResult<Option<(String, Option<String>)>> {
+    let mut stmt = conn.prepare("SELECT file_path, thumbnail_path FROM attachments WHERE sha256 = ?1 ORDER BY id LIMIT 1")?;
+    let mut files = stmt.query_map(params![sha256], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    files.next().transpose()
+}
+
+// Attachment rows whose post no longer exists, as (attachment, post)
+pub fn orphaned_attachments(conn: &Connection) -> SqlResult<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare("SELECT id, post FROM attachments WHERE post NOT IN (SELECT id FROM files) ORDER BY id")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+    rows
+}
+
+// Replies whose thread is gone, is a reply itself or is on another board,
+// as (reply, thread)
+pub fn orphaned_replies(conn: &Connection) -> SqlResult<Vec<(i32, i32)>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, parent_id FROM files AS reply WHERE parent_id != 0 AND NOT EXISTS (
+            SELECT 1 FROM files AS thread
+            WHERE thread.id = reply.parent_id AND thread.parent_id = 0 AND thread.board_id = reply.board_id
+         ) ORDER BY id",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+    rows
+}
+
+// SQLite's own consistency check; empty when all is well
+pub fn integrity_errors(conn: &Connection) -> SqlResult<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<SqlResult<Vec<_>>>()?;
+    Ok(rows.into_iter().filter(|row| row != "ok").collect())
+}
+
+// Whether the search index matches the posts it was built from
+pub fn search_index_ok(conn: &Connection) -> bool {
+    conn.execute("INSERT INTO files_fts(files_fts, rank) VALUES ('integrity-check', 1)", [])
+        .is_ok()
+}
+
+// Sets each thread's bump time to its newest reply, or to when it was
+// started if it has none. Returns the number of threads.
+pub fn rebuild_bump_times(conn: &Connection) -> SqlResult<usize> {
+    conn.execute(
+        "UPDATE files SET last_reply_at = COALESCE(
+            (SELECT MAX(reply.created_at) FROM files AS reply WHERE reply.parent_id = files.id),
+            created_at
+         ) WHERE parent_id = 0",
+        [],
+    )
+}
+
+// Stores a post as it is, timestamps included, and returns its id
+pub fn insert_post(conn: &Connection, board_id: i32, post: &Post, poster: &Poster) -> SqlResult<i64> {
+    conn.execute(
+        "INSERT INTO files (post_id, parent_id, title, message, board_id, name, tripcode, poster_id, created_at, last_reply_at, edited_at,
+                            deletion_password, author)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, datetime(?9, 'unixepoch'), datetime(?10, 'unixepoch'), datetime(?11, 'unixepoch'),
+                 ?12, ?13)",
+        params![
+            post.post_id,
+            post.parent_id,
+            post.title,
+            post.message,
+            board_id,
+            post.name,
+            post.tripcode,
+            post.poster_id,
+            post.created_at,
+            post.last_reply_at,
+            post.edited_at,
+            poster.deletion_password,
+            poster.author
+        ],
+    )?;
+    let id = conn.last_insert_rowid();
+    // A new thread's id, which its poster id needs, is only known now
+    if poster.poster_id.is_some() {
+        conn.execute(
+            "UPDATE files SET poster_id = ?1 WHERE id = ?2",
+            params![poster.poster_id(post, id as i32), id],
+        )?;
+    }
+    add_attachments(conn, id, &post.files)?;
+    Ok(id)
+}
+
+pub fn author(conn: &Connection, id: i32) -> SqlResult<Option<String>> {
+    let mut stmt = conn.prepare("SELECT author FROM files WHERE id = ?1")?;
+    let mut authors = stmt.query_map(params![id], |row| row.get::<_, Option<String>>(0))?;
+    Ok(authors.next().transpose()?.flatten())
+}
+
+// Replaces the title and message, keeping the old ones as a revision.
+// Returns whether the post exists.
+pub fn edit_post(conn: &Connection, id: i32, title: &str, message: &str, at: i64) -> SqlResult<bool> {
+    conn.execute(
+        "INSERT INTO revisions (post, title, message, replaced_at)
+         SELECT id, title, message, datetime(?2, 'unixepoch') FROM files WHERE id = ?1",
+        params![id, at],
+    )?;
+    let edited = conn.execute(
+        "UPDATE files SET title = ?1, message = ?2, edited_at = datetime(?3, 'unixepoch') WHERE id = ?4",
+        params![title, message, at, id],
+    )?;
+    Ok(edited > 0)
+}
+
+// Oldest first
+pub fn revisions(conn: &Connection, id: i32) -> SqlResult<Vec<Revision>> {
+    let mut stmt = conn.prepare(
+        "SELECT title, message, CAST(strftime('%s', replaced_at) AS INTEGER) FROM revisions WHERE post = ?1 ORDER BY id",
+    )?;
+    let revisions = stmt
+        .query_map(params![id], |row| {
+            Ok(Revision {
+                title: row.get(0)?,
+                message: row.get(1)?,
+                replaced_at: row.get(2)?,
+            })
+        })?
+        .collect();
+    revisions
+}
+
+pub fn deletion_password(conn: &Connection, id: i32) -> SqlResult<Option<String>> {
+    let mut stmt = conn.prepare("SELECT deletion_password FROM files WHERE id = ?1")?;
+    let mut hashes = stmt.query_map(params![id], |row| row.get::<_, Option<String>>(0))?;
+    Ok(hashes.next().transpose()?.flatten())
+}
+
+// Moves a thread to the top of its board
+pub fn bump(conn: &Connection, thread_id: i32, at: i64) -> SqlResult<()> {
+    conn.execute(
+        "UPDATE files SET last_reply_at = datetime(?2, 'unixepoch') WHERE id = ?1 OR parent_id = ?1",
+        params![thread_id, at],
+    )?;
+    Ok(())
+}
+
+pub fn update_message(conn: &Connection, id: i64, message: &str) -> SqlResult<()> {
+    conn.execute("UPDATE files SET message = ?1 WHERE id = ?2", params![message, id])?;
+    Ok(())
+}
+
+// Whether `thread_id` starts a thread on this board
+pub fn thread_exists(conn: &Connection, board_id: i32, thread_id: i32) -> SqlResult<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM files WHERE id = ?1 AND parent_id = 0 AND board_id = ?2)",
+        params![thread_id, board_id],
+        |row| row.get(0),
+    )
+}
+
+pub fn count_threads(conn: &Connection, board_id: i32) -> SqlResult<i64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM files WHERE parent_id = 0 AND board_id = ?1",
+        params![board_id],
+        |row| row.get(0),
+    )
+}
+
+// Thread starters in bump order
+pub fn list_threads(conn: &Connection, board_id: i32, limit: usize, offset: usize) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE parent_id = 0 AND board_id = ?1 ORDER BY last_reply_at DESC, id DESC LIMIT ?2 OFFSET ?3",
+        POST_COLUMNS
+    ))?;
+    let posts = stmt
+        .query_map(params![board_id, limit as i64, offset as i64], Post::from_row)?
+        .collect();
+    posts
+}
+
+// Newest thread starters first, regardless of bumps
+pub fn list_recent_threads(conn: &Connection, board_id: i32, limit: usize) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM files WHERE parent_id = 0 AND board_id = ?1 ORDER BY id DESC LIMIT ?2",
+        POST_COLUMNS
+    ))?;
+    let posts = stmt
+        .query_map(params![board_id, limit as i64], Post::from_row)?
+        .collect();
+    posts
+}
+
+// Number of replies and how many of them carry a file
+pub fn count_replies(conn: &Connection, thread_id: i32) -> SqlResult<(i64, i64)> {
+    conn.query_row(
+        &format!("SELECT COUNT(*), COUNT(CASE WHEN {} THEN 1 END) FROM files WHERE parent_id = ?1", HAS_FILE),
+        params![thread_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+// The newest `limit` replies of a thread, oldest first
+pub fn last_replies(conn: &Connection, thread_id: i32, limit: usize) -> SqlResult<Vec<Post>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT * FROM (SELECT {} FROM files WHERE parent_id = ?1 ORDER BY id DESC LIMIT ?2) ORDER BY 1 ASC",
+        POST_COLUMNS
+    ))?;
+    let posts = stmt
+        .query_map(params![thread_id, limit as i64], Post::from_row)?
+        .collect();
+    posts
+}
+
+pub struct SearchQuery {
+    // Words and phrases that must all appear, as split by `search_terms`
+    pub terms: Vec<String>,
+    pub board_id: Option<i32>,
+    // Inclusive YYYY-MM-DD bounds on the post date
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub has_file: bool,
+}
+
+pub struct SearchHit {
+    pub id: i32,
+    pub parent_id: i32,
+    pub board_id: i32,
+    // Snippets keep the stored (escaped) text and wrap matches in <mark>
+    pub title: String,
+    pub message: String,
+    pub created_at: String,
+    pub has_file: bool,
+}
+
+// Splits what a user typed into terms: "quoted phrases" stay phrases and
+// every other word is a term of its own
+pub fn search_terms(input: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    for (index, part) in input.split('"').enumerate() {
+        if index % 2 == 1 {
+            // Inside quotes
+            if !part.trim().is_empty() {
+                terms.push(part.trim().to_string());
+            }
+        } else {
+            terms.extend(part.split_whitespace().map(str::to_string));
+        }
+    }
+    terms
+}
+
+// An FTS5 expression matching every term literally
+fn fts_query(terms: &[String]) -> String {
+    let quoted: Vec<String> = terms
+        .iter()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    quoted.join(" AND ")
+}
+
+// Total number of matches, and one page of them ordered by relevance
+pub fn search(conn: &Connection, query: &SearchQuery, limit: usize, offset: usize) -> SqlResult<(i64, Vec<SearchHit>)> {
+    let filters = format!(
+        "files_fts MATCH ?1
+        AND (?2 IS NULL OR files.board_id = ?2)
+        AND (?3 IS NULL OR files.created_at >= ?3)
+        AND (?4 IS NULL OR files.created_at < date(?4, '+1 day'))
+        AND (?5 = 0 OR {})",
+        HAS_FILE
+    );
+
+    let text = fts_query(&query.terms);
+    let total = conn.query_row(
+        &format!("SELECT COUNT(*) FROM files_fts JOIN files ON files.id = files_fts.rowid WHERE {}", filters),
+        params![text, query.board_id, query.from, query.to, query.has_file],
+        |row| row.get(0),
+    )?;
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT files.id, files.parent_id, files.board_id,
+            snippet(files_fts, 0, '<mark>', '</mark>', '…', 64),
+            snippet(files_fts, 1, '<mark>', '</mark>', '…', 32),
+            files.created_at, {}
+         FROM files_fts JOIN files ON files.id = files_fts.rowid
+         WHERE {}
+         ORDER BY rank LIMIT ?6 OFFSET ?7",
+        HAS_FILE, filters
+    ))?;
+    let hits = stmt
+        .query_map(
+            params![text, query.board_id, query.from, query.to, query.has_file, limit as i64, offset as i64],
+            |row| {
+                Ok(SearchHit {
+                    id: row.get(0)?,
+                    parent_id: row.get(1)?,
+                    board_id: row.get(2)?,
+                    title: row.get(3)?,
+                    message: row.get(4)?,
+                    created_at: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                    has_file: row.get(6)?,
+                })
+            },
+        )?
+        .collect::<SqlResult<Vec<_>>>()?;
+    Ok((total, hits))
+}
+
+pub fn initialize_db(path: &str) -> SqlResult<Connection> {
+    let conn = Connection::open(path)?;
+    // `file_path` is from when posts had at most one file; it is no longer
+    // written and only read to migrate into `attachments`
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            post_id TEXT NOT NULL,
+            parent_id INTEGER,
+            title TEXT NOT NULL,
+            message TEXT NOT NULL,
+            file_path TEXT,
+            board_id INTEGER NOT NULL,
+            last_reply_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            name TEXT,
+            tripcode TEXT,
+            poster_id TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            deletion_password TEXT,
+            author TEXT,
+            edited_at TIMESTAMP
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS boards (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL DEFAULT '',
+            force_anonymous INTEGER NOT NULL DEFAULT 0,
+            poster_ids INTEGER NOT NULL DEFAULT 0,
+            max_files INTEGER NOT NULL DEFAULT 4,
+            reencode_images INTEGER NOT NULL DEFAULT 0,
+            keep_orientation INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+    // `ip` is the address as written by `IpAddr`'s Display
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bans (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            ip TEXT NOT NULL,
+            board_id INTEGER,
+            reason TEXT NOT NULL DEFAULT '',
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP
+        )",
+        [],
+    )?;
+    // Earlier titles and messages of edited posts
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS revisions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            post INTEGER NOT NULL,
+            title TEXT NOT NULL,
+            message TEXT NOT NULL,
+            replaced_at TIMESTAMP NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS revisions_post ON revisions (post)", [])?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS deletion_notices (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            board_id INTEGER NOT NULL,
+            thread_id INTEGER NOT NULL,
+            post INTEGER NOT NULL,
+            file_only INTEGER NOT NULL DEFAULT 0,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    // Databases created before these columns existed
+    add_column_if_missing(&conn, "files", "name", "TEXT")?;
+    add_column_if_missing(&conn, "files", "tripcode", "TEXT")?;
+    add_column_if_missing(&conn, "files", "poster_id", "TEXT")?;
+    add_column_if_missing(&conn, "files", "deletion_password", "TEXT")?;
+    add_column_if_missing(&conn, "files", "author", "TEXT")?;
+    add_column_if_missing(&conn, "files", "edited_at", "TIMESTAMP")?;
+    add_column_if_missing(&conn, "boards", "poster_ids", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "boards", "max_files", "INTEGER NOT NULL DEFAULT 4")?;
+    add_column_if_missing(&conn, "boards", "reencode_images", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "boards", "keep_orientation", "INTEGER NOT NULL DEFAULT 0")?;
+    if add_column_if_missing(&conn, "files", "created_at", "TIMESTAMP")? {
+        // The closest thing to a creation time that older rows have
+        conn.execute("UPDATE files SET created_at = last_reply_at WHERE created_at IS NULL", [])?;
+    }
+    initialize_attachments(&conn)?;
+    initialize_search_index(&conn)?;
+    Ok(conn)
+}
+
+fn initialize_attachments(conn: &Connection) -> SqlResult<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'attachments')",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS attachments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            post INTEGER NOT NULL REFERENCES files(id),
+            position INTEGER NOT NULL,
+            file_path TEXT NOT NULL,
+            original_name TEXT,
+            mime_type TEXT,
+            size INTEGER,
+            width INTEGER,
+            height INTEGER,
+            duration REAL,
+            thumbnail_path TEXT,
+            sha256 TEXT,
+            md5 TEXT,
+            UNIQUE (post, position)
+        );",
+    )?;
+    let mut missing_info = !exists;
+    if !exists {
+        // Move the single file of posts made before attachments existed
+        conn.execute(
+            "INSERT INTO attachments (post, position, file_path)
+             SELECT id, 0, file_path FROM files WHERE file_path IS NOT NULL",
+            [],
+        )?;
+    }
+    for (column, definition) in [
+        ("original_name", "TEXT"),
+        ("size", "INTEGER"),
+        ("width", "INTEGER"),
+        ("height", "INTEGER"),
+        ("duration", "REAL"),
+    ] {
+        missing_info |= add_column_if_missing(conn, "attachments", column, definition)?;
+    }
+    if missing_info {
+        backfill_media_info(conn)?;
+    }
+    // Older files have no thumbnails and are shown as they are
+    add_column_if_missing(conn, "attachments", "thumbnail_path", "TEXT")?;
+    if add_column_if_missing(conn, "attachments", "mime_type", "TEXT")? || !exists {
+        backfill_mime_types(conn)?;
+    }
+    let added_sha256 = add_column_if_missing(conn, "attachments", "sha256", "TEXT")?;
+    if add_column_if_missing(conn, "attachments", "md5", "TEXT")? || added_sha256 || !exists {
+        backfill_hashes(conn)?;
+    }
+    conn.execute("CREATE INDEX IF NOT EXISTS attachments_sha256 ON attachments (sha256)", [])?;
+    Ok(())
+}
+
+fn backfill_hashes(conn: &Connection) -> SqlResult<()> {
+    let mut stmt = conn.prepare("SELECT id, file_path FROM attachments WHERE sha256 IS NULL OR md5 IS NULL")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<SqlResult<Vec<_>>>()?;
+    for (id, file_path) in rows {
+        if let Ok(data) = std::fs::read(&file_path) {
+            conn.execute(
+                "UPDATE attachments SET sha256 = ?1, md5 = ?2 WHERE id = ?3",
+                params![media::content_hash(&data), media::content_md5(&data), id],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Files uploaded before MIME types were stored get the type their
+// extension implies, which is what they were accepted by
+fn backfill_mime_types(conn: &Connection) -> SqlResult<()> {
+    let mut stmt = conn.prepare("SELECT id, file_path FROM attachments WHERE mime_type IS NULL")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<SqlResult<Vec<_>>>()?;
+    for (id, file_path) in rows {
+        let mime_type = MimeGuess::from_path(&file_path).first_or_octet_stream();
+        conn.execute(
+            "UPDATE attachments SET mime_type = ?1 WHERE id = ?2",
+            params![mime_type.as_ref(), id],
+        )?;
+    }
+    Ok(())
+}
+
+// Reads size, dimensions and duration of files uploaded before they were
+// recorded. Files that are gone keep NULLs.
+fn backfill_media_info(conn: &Connection) -> SqlResult<()> {
+    let mut stmt = conn.prepare("SELECT id, file_path FROM attachments WHERE size IS NULL")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<SqlResult<Vec<_>>>()?;
+    for (id, file_path) in rows {
+        let data = match std::fs::read(&file_path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let mime_type = MimeGuess::from_path(&file_path).first_or_octet_stream();
+        let info = media_info::probe(&data, mime_type.as_ref());
+        conn.execute(
+            "UPDATE attachments SET size = ?1, width = ?2, height = ?3, duration = ?4 WHERE id = ?5",
+            params![data.len() as i64, info.width, info.height, info.duration, id],
+        )?;
+    }
+    Ok(())
+}
+
+// Full-text index over titles and messages. It stores no text of its own and
+// is kept in step with `files` by triggers.
+fn initialize_search_index(conn: &Connection) -> SqlResult<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'files_fts')",
+        [],
+        |row| row.get(0),
+    )?;
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS files_fts USING fts5(
+            title, message, content='files', content_rowid='id'
+        );
+        CREATE TRIGGER IF NOT EXISTS files_fts_insert AFTER INSERT ON files BEGIN
+            INSERT INTO files_fts(rowid, title, message) VALUES (new.id, new.title, new.message);
+        END;
+        CREATE TRIGGER IF NOT EXISTS files_fts_delete AFTER DELETE ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, title, message) VALUES ('delete', old.id, old.title, old.message);
+        END;
+        CREATE TRIGGER IF NOT EXISTS files_fts_update AFTER UPDATE OF title, message ON files BEGIN
+            INSERT INTO files_fts(files_fts, rowid, title, message) VALUES ('delete', old.id, old.title, old.message);
+            INSERT INTO files_fts(rowid, title, message) VALUES (new.id, new.title, new.message);
+        END;",
+    )?;
+    if !exists {
+        // Index the posts made before search existed
+        rebuild_search_index(conn)?;
+    }
+    Ok(())
+}
+
+pub fn rebuild_search_index(conn: &Connection) -> SqlResult<()> {
+    conn.execute("INSERT INTO files_fts(files_fts) VALUES ('rebuild')", [])?;
+    Ok(())
+}
+
+// Returns whether the column had to be added
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> SqlResult<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(!exists)
+}
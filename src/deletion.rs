@@ -0,0 +1,114 @@
+// Posters deleting their own posts. A post may carry a deletion password,
+// kept as a salted HMAC keyed with the server secret, so the stored hash is
+// of no use without the secret. Browsers remember the last password in a
+// cookie and the post forms fill it in.
+use actix_web::cookie::{time::Duration, Cookie, SameSite};
+use actix_web::HttpRequest;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const COOKIE_NAME: &str = "adelia_password";
+pub const MAX_PASSWORD_LENGTH: usize = 64;
+
+const SALT_LENGTH: usize = 16;
+
+fn mac(secret: &[u8], salt: &str, password: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("deletion|{}|{}", salt, password).as_bytes());
+    mac
+}
+
+// `{salt}${hash}`
+pub fn hash_password(secret: &[u8], password: &str) -> String {
+    let salt: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SALT_LENGTH)
+        .map(char::from)
+        .collect();
+    let hash = URL_SAFE_NO_PAD.encode(mac(secret, &salt, password).finalize().into_bytes());
+    format!("{}${}", salt, hash)
+}
+
+// Posts without a password can't be deleted by their poster, and an empty
+// password never opens one that has
+pub fn verify_password(secret: &[u8], stored: &str, password: &str) -> bool {
+    let (salt, hash) = match stored.split_once('$') {
+        Some(parts) if !password.is_empty() => parts,
+        _ => return false,
+    };
+    match URL_SAFE_NO_PAD.decode(hash) {
+        Ok(hash) => mac(secret, salt, password).verify_slice(&hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// The password the browser last posted with
+pub fn remembered_password(req: &HttpRequest) -> String {
+    req.cookie(COOKIE_NAME)
+        .and_then(|cookie| URL_SAFE_NO_PAD.decode(cookie.value()).ok())
+        .and_then(|password| String::from_utf8(password).ok())
+        .map(|password| password.chars().take(MAX_PASSWORD_LENGTH).collect())
+        .unwrap_or_default()
+}
+
+// Encoded, since passwords may have characters cookies can't
+pub fn password_cookie(password: &str) -> Cookie<'static> {
+    Cookie::build(COOKIE_NAME, URL_SAFE_NO_PAD.encode(password))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(Duration::days(365))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"server secret";
+
+    #[test]
+    fn right_password() {
+        let stored = hash_password(SECRET, "hunter2");
+        assert!(verify_password(SECRET, &stored, "hunter2"));
+        // Salted, so the same password hashes differently each time
+        assert_ne!(stored, hash_password(SECRET, "hunter2"));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let stored = hash_password(SECRET, "hunter2");
+        assert!(!verify_password(SECRET, &stored, "hunter3"));
+        assert!(!verify_password(SECRET, &stored, "Hunter2"));
+        assert!(!verify_password(SECRET, &stored, "hunter2 "));
+        assert!(!verify_password(b"other secret", &stored, "hunter2"));
+    }
+
+    #[test]
+    fn salt_of_another_post() {
+        let stored = hash_password(SECRET, "hunter2");
+        let other = hash_password(SECRET, "hunter2");
+        let (salt, _) = other.split_once('$').unwrap();
+        let (_, hash) = stored.split_once('$').unwrap();
+        assert!(!verify_password(SECRET, &format!("{}${}", salt, hash), "hunter2"));
+    }
+
+    #[test]
+    fn empty_password() {
+        assert!(!verify_password(SECRET, &hash_password(SECRET, ""), ""));
+        assert!(!verify_password(SECRET, &hash_password(SECRET, "hunter2"), ""));
+        assert!(!verify_password(SECRET, "", ""));
+    }
+
+    #[test]
+    fn malformed_hash() {
+        let stored = hash_password(SECRET, "hunter2");
+        assert!(!verify_password(SECRET, &stored.replace('$', ""), "hunter2"));
+        assert!(!verify_password(SECRET, &format!("{}!", stored), "hunter2"));
+        assert!(!verify_password(SECRET, &stored[..stored.len() - 2], "hunter2"));
+    }
+}
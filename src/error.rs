@@ -0,0 +1,119 @@
+// What handlers fail with. Every error maps to a status code and is shown as
+// the error page; what went wrong inside the server only goes to the log, so
+// visitors never see database or file system details.
+use actix_multipart::MultipartError;
+use actix_web::error::BlockingError;
+use actix_web::http::{header::ContentType, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::render_template;
+use crate::store::StoreError;
+
+// All visitors learn about failures on our side
+pub const SERVER_ERROR_MESSAGE: &str = "Something went wrong on our end. Please try again later.";
+
+pub enum AppError {
+    NotFound,
+    // A request that can't be understood; the message is shown as is
+    BadRequest(String),
+    Store(StoreError),
+    // Templates, uploads, and anything else read from or written to disk
+    Io(io::Error),
+    // A blocking task that never finished
+    Blocking,
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "not found"),
+            AppError::BadRequest(message) => write!(f, "bad request: {}", message),
+            AppError::Store(e) => write!(f, "store error: {}", e),
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::Blocking => write!(f, "blocking task was canceled"),
+        }
+    }
+}
+
+impl fmt::Debug for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<StoreError> for AppError {
+    fn from(e: StoreError) -> Self {
+        AppError::Store(e)
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> Self {
+        AppError::Store(e.into())
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl From<BlockingError> for AppError {
+    fn from(_: BlockingError) -> Self {
+        AppError::Blocking
+    }
+}
+
+// A broken or cut off upload
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        log::info!("unreadable upload: {}", e);
+        AppError::BadRequest("The upload could not be read.".to_string())
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Store(_) | AppError::Io(_) | AppError::Blocking => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = match self {
+            AppError::NotFound => "The page you are looking for does not exist.",
+            AppError::BadRequest(message) => message,
+            AppError::Store(_) | AppError::Io(_) | AppError::Blocking => {
+                log::error!("{}", self);
+                SERVER_ERROR_MESSAGE
+            }
+        };
+        error_page(self.status_code(), message)
+    }
+}
+
+// The error page, with `message` escaped. Falls back to plain text when the
+// template itself can't be read.
+pub fn error_page(status: StatusCode, message: &str) -> HttpResponse {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let context = HashMap::from([
+        ("STATUS", status.as_u16().to_string()),
+        ("REASON", reason.to_string()),
+        ("MESSAGE", htmlescape::encode_minimal(message)),
+    ]);
+    match render_template("templates/error.html", &context) {
+        Ok(body) => HttpResponse::build(status).content_type(ContentType::html()).body(body),
+        Err(e) => {
+            log::error!("error page unavailable: {}", e);
+            HttpResponse::build(status)
+                .content_type(ContentType::plaintext())
+                .body(format!("{} {}\n\n{}", status.as_u16(), reason, message))
+        }
+    }
+}
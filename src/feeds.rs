@@ -0,0 +1,209 @@
+// Atom and RSS feeds of new threads on a board and new posts in a thread.
+// Titles and messages are stored HTML-escaped already, so they are escaped
+// once more here to be carried as HTML inside the XML.
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use htmlescape::{decode_html, encode_minimal as xml_escape};
+use mime_guess::MimeGuess;
+
+use crate::db::Post;
+use crate::error::AppError;
+use crate::media;
+use crate::store::Store;
+use crate::tripcode::DEFAULT_NAME;
+
+const FEED_ENTRIES: usize = 30;
+
+fn timestamp(seconds: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(seconds, 0).unwrap_or_default()
+}
+
+// Feed readers need absolute links
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+fn post_url(base: &str, board_id: i32, post: &Post) -> String {
+    let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+    format!("{}/{}/post/{}#p{}", base, board_id, thread_id, post.id)
+}
+
+// URL, MIME type and size of each of the post's files that still exist on disk
+fn enclosures(base: &str, post: &Post) -> Vec<(String, String, u64)> {
+    post.files
+        .iter()
+        .filter_map(|file| {
+            let file_path = &file.file_path;
+            let length = std::fs::metadata(file_path).ok()?.len();
+            let mime_type = match &file.mime_type {
+                Some(mime_type) => mime_type.clone(),
+                None => MimeGuess::from_path(file_path).first_or_octet_stream().to_string(),
+            };
+            Some((
+                format!("{}{}", base, media::public_path(file_path)),
+                mime_type,
+                length,
+            ))
+        })
+        .collect()
+}
+
+// Edits count as changes to a post
+fn updated_at(post: &Post) -> i64 {
+    post.edited_at.unwrap_or(post.created_at)
+}
+
+fn plain_title(post: &Post) -> String {
+    decode_html(&post.title).unwrap_or_else(|_| post.title.clone())
+}
+
+fn atom(title: &str, self_url: &str, alternate_url: &str, updated: i64, posts: &[Post], base: &str, board_id: i32) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    xml.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+    xml.push_str(&format!("<id>{}</id>\n", xml_escape(self_url)));
+    xml.push_str(&format!("<link rel=\"self\" href=\"{}\"/>\n", xml_escape(self_url)));
+    xml.push_str(&format!("<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n", xml_escape(alternate_url)));
+    xml.push_str(&format!(
+        "<updated>{}</updated>\n",
+        timestamp(updated).to_rfc3339_opts(SecondsFormat::Secs, true)
+    ));
+
+    for post in posts {
+        let url = post_url(base, board_id, post);
+        xml.push_str("<entry>\n");
+        xml.push_str(&format!("<title type=\"html\">{}</title>\n", xml_escape(&post.title)));
+        xml.push_str(&format!("<id>{}</id>\n", xml_escape(&url)));
+        xml.push_str(&format!("<link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n", xml_escape(&url)));
+        for (href, mime_type, length) in enclosures(base, post) {
+            xml.push_str(&format!(
+                "<link rel=\"enclosure\" href=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                xml_escape(&href),
+                mime_type,
+                length
+            ));
+        }
+        xml.push_str(&format!(
+            "<author><name>{}</name></author>\n",
+            xml_escape(post.name.as_deref().unwrap_or(DEFAULT_NAME))
+        ));
+        xml.push_str(&format!(
+            "<published>{}</published>\n",
+            timestamp(post.created_at).to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+        xml.push_str(&format!(
+            "<updated>{}</updated>\n",
+            timestamp(updated_at(post)).to_rfc3339_opts(SecondsFormat::Secs, true)
+        ));
+        xml.push_str(&format!("<content type=\"html\">{}</content>\n", xml_escape(&post.message)));
+        xml.push_str("</entry>\n");
+    }
+
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn rss(title: &str, link: &str, updated: i64, posts: &[Post], base: &str, board_id: i32) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\">\n<channel>\n");
+    xml.push_str(&format!("<title>{}</title>\n", xml_escape(title)));
+    xml.push_str(&format!("<link>{}</link>\n", xml_escape(link)));
+    xml.push_str(&format!("<description>{}</description>\n", xml_escape(title)));
+    xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", timestamp(updated).to_rfc2822()));
+
+    for post in posts {
+        let url = post_url(base, board_id, post);
+        xml.push_str("<item>\n");
+        xml.push_str(&format!("<title>{}</title>\n", xml_escape(&plain_title(post))));
+        xml.push_str(&format!("<link>{}</link>\n", xml_escape(&url)));
+        xml.push_str(&format!("<guid isPermaLink=\"true\">{}</guid>\n", xml_escape(&url)));
+        xml.push_str(&format!("<pubDate>{}</pubDate>\n", timestamp(post.created_at).to_rfc2822()));
+        // RSS allows a single enclosure per item
+        if let Some((href, mime_type, length)) = enclosures(base, post).into_iter().next() {
+            xml.push_str(&format!(
+                "<enclosure url=\"{}\" type=\"{}\" length=\"{}\"/>\n",
+                xml_escape(&href),
+                mime_type,
+                length
+            ));
+        }
+        xml.push_str(&format!("<description>{}</description>\n", xml_escape(&post.message)));
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+// A board feed changes whenever a thread is created, bumped or edited
+fn board_updated(threads: &[Post]) -> i64 {
+    threads.iter().map(|p| p.last_reply_at.max(updated_at(p))).max().unwrap_or(0)
+}
+
+pub async fn board_atom(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    board_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let threads = store.recent_threads(*board_id, FEED_ENTRIES)?;
+    let base = base_url(&req);
+    let body = atom(
+        &format!("/{}/ - new threads", board_id),
+        &format!("{}/{}/feed.atom", base, board_id),
+        &format!("{}/{}", base, board_id),
+        board_updated(&threads),
+        &threads,
+        &base,
+        *board_id,
+    );
+    Ok(HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(body))
+}
+
+pub async fn board_rss(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    board_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    let threads = store.recent_threads(*board_id, FEED_ENTRIES)?;
+    let base = base_url(&req);
+    let body = rss(
+        &format!("/{}/ - new threads", board_id),
+        &format!("{}/{}", base, board_id),
+        board_updated(&threads),
+        &threads,
+        &base,
+        *board_id,
+    );
+    Ok(HttpResponse::Ok().content_type("application/rss+xml; charset=utf-8").body(body))
+}
+
+pub async fn thread_atom(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (board_id, thread_id) = path.into_inner();
+    let mut posts = store.fetch_thread(board_id, thread_id)?;
+    let op_title = match posts.first() {
+        Some(op) if op.parent_id == 0 => plain_title(op),
+        _ => return Err(AppError::NotFound),
+    };
+
+    let updated = posts.iter().map(updated_at).max().unwrap_or(0);
+    // Newest first, like the board feeds
+    posts.reverse();
+    posts.truncate(FEED_ENTRIES);
+
+    let base = base_url(&req);
+    let body = atom(
+        &format!("/{}/ - {}", board_id, op_title),
+        &format!("{}/{}/post/{}/feed.atom", base, board_id, thread_id),
+        &format!("{}/{}/post/{}", base, board_id, thread_id),
+        updated,
+        &posts,
+        &base,
+        board_id,
+    );
+    Ok(HttpResponse::Ok().content_type("application/atom+xml; charset=utf-8").body(body))
+}
@@ -0,0 +1,118 @@
+// Everything the server and the admin tool share: the database layer, media
+// handling, configuration, and the handlers and renderers behind the pages.
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::hash::Hasher;
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use siphasher::sip::SipHasher13;
+
+pub mod admin;
+pub mod api;
+pub mod archive;
+pub mod author;
+pub mod config;
+pub mod csrf;
+pub mod db;
+pub mod deletion;
+pub mod error;
+pub mod feeds;
+pub mod live;
+pub mod media;
+pub mod media_info;
+pub mod metadata;
+#[cfg(feature = "sled")]
+pub mod migrate;
+pub mod poster_id;
+pub mod search;
+pub mod security;
+pub mod store;
+pub mod tripcode;
+pub mod ws;
+
+// Fixed so that ID colors stay the same across restarts and Rust versions
+const COLOR_HASH_KEYS: (u64, u64) = (0x6164_656c_6961_2d69, 0x642d_636f_6c6f_7273);
+
+pub fn render_template(path: &str, context: &HashMap<&str, String>) -> io::Result<String> {
+    let mut rendered = read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    for (key, value) in context {
+        let placeholder = format!("{{{{{}}}}}", key);
+        rendered = rendered.replace(&placeholder, value);
+    }
+    Ok(rendered)
+}
+
+// Locks shared state even after a handler panicked while holding it, so one
+// bad request can't take every later one down. The database connection stays
+// consistent: transactions left open roll back when dropped.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Number of `.id-color-N` classes in styles.css
+const ID_COLORS: u64 = 32;
+
+// Colors come from a fixed palette of classes rather than inline styles,
+// which the Content-Security-Policy doesn't allow
+fn color_class_from_id(id: &str) -> String {
+    let mut hasher = SipHasher13::new_with_keys(COLOR_HASH_KEYS.0, COLOR_HASH_KEYS.1);
+    hasher.write(id.as_bytes());
+    format!("id-color-{}", hasher.finish() % ID_COLORS)
+}
+
+// The poster's id in the thread where the board gives them, and the post's
+// own random id everywhere else, the same on every page
+pub fn render_id_box(post: &db::Post) -> String {
+    let id = post.poster_id.as_deref().unwrap_or(&post.post_id);
+    format!("<div class=\"post-id-box {}\">{}</div>", color_class_from_id(id), id)
+}
+
+// Whether a post written at `created_at` is still within `window` seconds
+// of being posted, for what its poster may do to it. A window of 0 is closed.
+pub fn within_window(created_at: i64, window: u64, now: i64) -> bool {
+    window > 0 && now.saturating_sub(created_at) <= window as i64
+}
+
+pub fn sanitize_input(input: &str) -> String {
+    htmlescape::encode_minimal(input)
+}
+
+pub fn render_poster(name: Option<String>, tripcode: Option<String>) -> String {
+    let mut html = format!(
+        "<span class=\"post-name\">{}</span>",
+        name.unwrap_or_else(|| tripcode::DEFAULT_NAME.to_string())
+    );
+    if let Some(tripcode) = tripcode {
+        html.push_str(&format!("<span class=\"post-trip\">{}</span>", tripcode));
+    }
+    html
+}
+
+// Marks posts their author has changed since posting
+pub fn render_edited(edited_at: Option<i64>) -> String {
+    match edited_at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)) {
+        Some(at) => format!("<div class=\"post-edited\">Edited {}</div>", at.format("%Y-%m-%d %H:%M UTC")),
+        None => String::new(),
+    }
+}
+
+// One post as it appears on the thread page. The original post has
+// `reply_number` 0; `actions` is shown below the message, e.g. the form to
+// delete the post.
+pub fn render_thread_post(post: db::Post, reply_number: i64, actions: &str) -> String {
+    let mut html = format!("<div class=\"post\" id=\"p{}\">", post.id);
+    if reply_number == 0 {
+        html.push_str("<div class=\"post-id\">Original Post</div>");
+    } else {
+        html.push_str(&format!("<div class=\"post-id\">Reply {}</div>", reply_number));
+    }
+    html.push_str(&render_id_box(&post));
+    html.push_str(&render_poster(post.name, post.tripcode));
+    html.push_str(&format!("<div class=\"post-title\">{}</div>", post.title));
+    html.push_str(&media::render_attachments(&post.files));
+    html.push_str(&format!("<div class=\"post-message\">{}</div>", post.message));
+    html.push_str(&render_edited(post.edited_at));
+    html.push_str(actions);
+    html.push_str("</div>");
+    html
+}
@@ -0,0 +1,291 @@
+// Pushes new posts, bumps and deletions to open thread and board pages over
+// Server-Sent Events. Handlers publish into the hub once a change is
+// committed, and deletions made by the admin tool are relayed from the
+// database; every open event stream, and every board a WebSocket client
+// subscribed to (see ws.rs), holds a receiver for its board or thread.
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures_util::stream::{self, Stream, StreamExt as _};
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::db::{self, Post};
+use crate::store::{Store, StoreResult};
+use crate::error::AppError;
+use crate::{lock, render_thread_post};
+
+// Events a subscriber may fall behind by before it starts missing some
+const CHANNEL_CAPACITY: usize = 64;
+// Comment lines keep proxies from closing quiet streams and let us notice
+// clients that went away
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// How often deletions made by the admin tool are looked for
+const NOTICE_INTERVAL: Duration = Duration::from_secs(2);
+// Most posts replayed to a client reconnecting with Last-Event-ID
+const MAX_REPLAY: usize = 100;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Board(i32),
+    Thread(i32),
+}
+
+pub struct Event {
+    pub board_id: i32,
+    pub thread_id: i32,
+    pub id: i32,
+    pub name: &'static str,
+    // JSON payload
+    pub data: String,
+}
+
+impl Event {
+    fn to_sse(&self) -> Bytes {
+        // Deletions are of older posts, and would move a reconnecting
+        // client's Last-Event-ID back
+        if self.name == DELETE {
+            return Bytes::from(format!("event: {}\ndata: {}\n\n", self.name, self.data));
+        }
+        Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, self.name, self.data))
+    }
+
+    // The WebSocket message; `data` is already JSON
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"type":"{}","board_id":{},"thread_id":{},"id":{},"data":{}}}"#,
+            self.name, self.board_id, self.thread_id, self.id, self.data
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct PostPayload<'a> {
+    id: i32,
+    board_id: i32,
+    thread_id: i32,
+    html: &'a str,
+}
+
+// Builds the event for a committed post, rendered the same way the thread
+// page renders it
+pub fn post_event(store: &dyn Store, board_id: i32, post: Post) -> StoreResult<Event> {
+    let id = post.id;
+    let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+    let reply_number = store.reply_number(&post)?;
+    let html = render_thread_post(post, reply_number, "");
+    let data = serde_json::to_string(&PostPayload {
+        id,
+        board_id,
+        thread_id,
+        html: &html,
+    })
+    .unwrap_or_default();
+    Ok(Event {
+        board_id,
+        thread_id,
+        id,
+        name: "post",
+        data,
+    })
+}
+
+#[derive(Serialize)]
+struct BumpPayload {
+    board_id: i32,
+    thread_id: i32,
+    id: i32,
+}
+
+#[derive(Serialize)]
+struct DeletePayload {
+    board_id: i32,
+    thread_id: i32,
+    id: i32,
+    file_only: bool,
+}
+
+const DELETE: &str = "delete";
+
+// A post was deleted, or only its files when `file_only`. Deleting a thread
+// starter (`id` equal to `thread_id`) takes the whole thread with it.
+pub fn delete_event(board_id: i32, thread_id: i32, id: i32, file_only: bool) -> Event {
+    let data = serde_json::to_string(&DeletePayload {
+        board_id,
+        thread_id,
+        id,
+        file_only,
+    })
+    .unwrap_or_default();
+    Event {
+        board_id,
+        thread_id,
+        id,
+        name: DELETE,
+        data,
+    }
+}
+
+// A reply moved its thread to the top of the board; `id` is the reply
+pub fn bump_event(board_id: i32, thread_id: i32, id: i32) -> Event {
+    let data = serde_json::to_string(&BumpPayload { board_id, thread_id, id }).unwrap_or_default();
+    Event {
+        board_id,
+        thread_id,
+        id,
+        name: "bump",
+        data,
+    }
+}
+
+#[derive(Default)]
+pub struct Hub {
+    topics: Mutex<HashMap<Topic, broadcast::Sender<Arc<Event>>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, topic: Topic) -> broadcast::Receiver<Arc<Event>> {
+        let mut topics = lock(&self.topics);
+        topics
+            .entry(topic)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    // Sends the event to its thread and its board
+    pub fn publish(&self, event: Event) {
+        let event = Arc::new(event);
+        let topics = lock(&self.topics);
+        for topic in [Topic::Thread(event.thread_id), Topic::Board(event.board_id)] {
+            if let Some(sender) = topics.get(&topic) {
+                // Only fails when nobody is listening
+                let _ = sender.send(event.clone());
+            }
+        }
+    }
+
+    // Forgets topics whose subscribers have all disconnected
+    pub fn sweep(&self) {
+        lock(&self.topics).retain(|_, sender| sender.receiver_count() > 0);
+    }
+}
+
+// Runs for the life of the server
+pub async fn sweep_periodically(hub: web::Data<Hub>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        hub.sweep();
+    }
+}
+
+// The admin tool runs apart from the server, so its deletions reach the hub
+// through the database. Only those made after the server started are passed
+// on. Runs for the life of the server.
+pub async fn relay_deletion_notices(hub: web::Data<Hub>, conn: web::Data<Mutex<Connection>>) {
+    let mut last_id = match db::last_deletion_notice(&lock(&conn)) {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("deletion notices unavailable: {}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(NOTICE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let notices = db::deletion_notices_after(&lock(&conn), last_id);
+        match notices {
+            Ok(notices) => {
+                for notice in notices {
+                    last_id = notice.id;
+                    hub.publish(delete_event(notice.board_id, notice.thread_id, notice.post, notice.file_only));
+                }
+            }
+            Err(e) => log::error!("reading deletion notices: {}", e),
+        }
+    }
+}
+
+fn last_event_id(req: &HttpRequest) -> Option<i32> {
+    req.headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn event_stream(
+    replay: Vec<Bytes>,
+    receiver: broadcast::Receiver<Arc<Event>>,
+) -> impl Stream<Item = Result<Bytes>> {
+    let keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+    let live = stream::unfold((receiver, keepalive), |(mut receiver, mut keepalive)| async move {
+        loop {
+            tokio::select! {
+                message = receiver.recv() => match message {
+                    Ok(event) => return Some((event.to_sse(), (receiver, keepalive))),
+                    // A slow client skips what it missed rather than holding up the rest
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keepalive.tick() => {
+                    return Some((Bytes::from_static(b": keepalive\n\n"), (receiver, keepalive)));
+                }
+            }
+        }
+    });
+    stream::iter(replay).chain(live).map(Ok)
+}
+
+// Subscribes first so nothing committed during the replay query is lost
+fn respond(
+    req: &HttpRequest,
+    store: &dyn Store,
+    hub: &Hub,
+    board_id: i32,
+    topic: Topic,
+) -> StoreResult<HttpResponse> {
+    let receiver = hub.subscribe(topic);
+    let thread_id = match topic {
+        Topic::Thread(thread_id) => Some(thread_id),
+        Topic::Board(_) => None,
+    };
+
+    let mut replay = Vec::new();
+    if let Some(after_id) = last_event_id(req) {
+        for post in store.posts_after(board_id, thread_id, after_id, MAX_REPLAY)? {
+            replay.push(post_event(store, board_id, post)?.to_sse());
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(event_stream(replay, receiver)))
+}
+
+pub async fn thread_events(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    hub: web::Data<Hub>,
+    path: web::Path<(i32, i32)>,
+) -> Result<HttpResponse, AppError> {
+    let (board_id, thread_id) = path.into_inner();
+    if !store.thread_exists(board_id, thread_id)? {
+        return Err(AppError::NotFound);
+    }
+    Ok(respond(&req, store.get_ref(), &hub, board_id, Topic::Thread(thread_id))?)
+}
+
+pub async fn board_events(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    hub: web::Data<Hub>,
+    board_id: web::Path<i32>,
+) -> Result<HttpResponse, AppError> {
+    Ok(respond(&req, store.get_ref(), &hub, *board_id, Topic::Board(*board_id))?)
+}
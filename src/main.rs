@@ -1,71 +1,320 @@
 use actix_files as fs;
 use actix_multipart::Multipart;
-use actix_web::{web, App, HttpResponse, HttpServer, Result};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use futures_util::stream::StreamExt as _;
 use std::collections::HashMap;
 use std::fs::read_to_string;
-use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::sync::Mutex;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use actix_web::web::Data;
-use rusqlite::{params, Connection, Result as SqlResult};
+use rusqlite::Connection;
 use rand::{distributions::Alphanumeric, Rng};
-use std::collections::hash_map::DefaultHasher;
 use mime_guess::MimeGuess;
+use serde::{Deserialize, Serialize};
 
-// Define the MIME types manually
-const MIME_IMAGE_JPEG: &str = "image/jpeg";
-const MIME_IMAGE_PNG: &str = "image/png";
-const MIME_IMAGE_GIF: &str = "image/gif";
-const MIME_IMAGE_WEBP: &str = "image/webp";
-const MIME_VIDEO_MP4: &str = "video/mp4";
-const MIME_AUDIO_MPEG: &str = "audio/mpeg";
-const MIME_VIDEO_WEBM: &str = "video/webm";
-
-// Maximum file size (20 MB)
-const MAX_SIZE: usize = 20 * 1024 * 1024;
-const POSTS_PER_PAGE: usize = 30;
-
-fn render_template(path: &str, context: &HashMap<&str, String>) -> String {
-    let template = read_to_string(path).expect("Unable to read template file");
-    let mut rendered = template;
-    for (key, value) in context {
-        let placeholder = format!("{{{{{}}}}}", key);
-        rendered = rendered.replace(&placeholder, value);
-    }
-    rendered
+use adelia::config::Config;
+use adelia::error::{self, AppError};
+use adelia::security::SecurityHeaders;
+use adelia::store::{self, Store, StoreError};
+use adelia::{
+    admin, api, author, csrf, db, deletion, feeds, live, lock, media, media_info, metadata, poster_id, render_edited, render_id_box, render_poster, render_template,
+    render_thread_post, sanitize_input, search, tripcode, within_window, ws,
+};
+
+// The stylesheets and scripts /static serves, and nothing else from there
+const SITE_ASSETS: [&str; 2] = ["styles.css", "live.js"];
+
+// Key for tripcodes and anything else that must not be guessable by posters.
+struct ServerSecret(Vec<u8>);
+
+// Room in the name field for "##" and a tripcode password, which don't count
+// towards the name
+const TRIPCODE_ROOM: usize = 45;
+
+fn name_field_html(config: &Config, settings: &db::BoardSettings) -> String {
+    if settings.force_anonymous {
+        String::new()
+    } else {
+        format!(
+            r#"<input type="text" name="name" maxlength="{}" placeholder="Name (optional) - name#tripcode"><br>"#,
+            config.max_name_length + TRIPCODE_ROOM
+        )
+    }
+}
+
+fn password_field_html(config: &Config, password: &str) -> String {
+    if config.deletion_window == 0 {
+        String::new()
+    } else {
+        format!(
+            r#"<input type="password" name="password" maxlength="{}" value="{}" placeholder="Password (optional) - for deleting the post" autocomplete="off"><br>"#,
+            deletion::MAX_PASSWORD_LENGTH,
+            sanitize_input(password)
+        )
+    }
+}
+
+fn file_field_html(settings: &db::BoardSettings) -> String {
+    match settings.max_files {
+        0 => String::new(),
+        1 => r#"<input type="file" name="file"><br>"#.to_string(),
+        max_files => format!(
+            r#"<input type="file" name="file" multiple title="Up to {} files"><br>"#,
+            max_files
+        ),
+    }
+}
+
+// Everything `save_file` can refuse a post for, and `delete_post` and
+// `edit_post` a deletion or edit. Browsers get the message on the error page, API clients get the code
+// and message as JSON.
+enum PostError {
+    MissingFields,
+    TitleTooLong,
+    MessageTooLong,
+    NameTooLong,
+    PasswordTooLong,
+    FileTypeRejected,
+    FileTooLarge,
+    FilesTooLarge,
+    TooManyFiles(usize),
+    ImageUnreadable,
+    ThreadNotFound,
+    PostNotFound,
+    WrongPassword,
+    DeletionClosed,
+    NotAuthor,
+    EditClosed,
+    CrossSite,
+    BadToken,
+    Banned(String),
+    Database(StoreError),
+}
+
+impl PostError {
+    fn code(&self) -> &'static str {
+        match self {
+            PostError::MissingFields => "missing_fields",
+            PostError::TitleTooLong => "title_too_long",
+            PostError::MessageTooLong => "message_too_long",
+            PostError::NameTooLong => "name_too_long",
+            PostError::PasswordTooLong => "password_too_long",
+            PostError::FileTypeRejected => "file_type_rejected",
+            PostError::FileTooLarge => "file_too_large",
+            PostError::FilesTooLarge => "files_too_large",
+            PostError::TooManyFiles(_) => "too_many_files",
+            PostError::ImageUnreadable => "image_unreadable",
+            PostError::ThreadNotFound => "thread_not_found",
+            PostError::PostNotFound => "post_not_found",
+            PostError::WrongPassword => "wrong_password",
+            PostError::DeletionClosed => "deletion_closed",
+            PostError::NotAuthor => "not_author",
+            PostError::EditClosed => "edit_closed",
+            PostError::CrossSite => "cross_site",
+            PostError::BadToken => "bad_csrf_token",
+            PostError::Banned(_) => "banned",
+            PostError::Database(_) => "database_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            PostError::MissingFields => "Title and message are mandatory.".to_string(),
+            PostError::TitleTooLong => "Title is too long.".to_string(),
+            PostError::MessageTooLong => "Message is too long.".to_string(),
+            PostError::NameTooLong => "Name is too long.".to_string(),
+            PostError::PasswordTooLong => "Password is too long.".to_string(),
+            PostError::FileTypeRejected => "This file type is not allowed.".to_string(),
+            PostError::FileTooLarge => "File is too large.".to_string(),
+            PostError::FilesTooLarge => "Files are too large together.".to_string(),
+            PostError::TooManyFiles(max_files) => format!("Too many files. This board allows {} per post.", max_files),
+            PostError::ImageUnreadable => "The image could not be read.".to_string(),
+            PostError::ThreadNotFound => "The thread you are replying to does not exist.".to_string(),
+            PostError::PostNotFound => "That post does not exist.".to_string(),
+            PostError::WrongPassword => "Wrong password.".to_string(),
+            PostError::DeletionClosed => "This post can no longer be deleted.".to_string(),
+            PostError::NotAuthor => "Only the author of this post can edit it.".to_string(),
+            PostError::EditClosed => "This post can no longer be edited.".to_string(),
+            PostError::CrossSite => "Posts can only be made from this site.".to_string(),
+            PostError::BadToken => "The form has expired. Reload the page and try again.".to_string(),
+            PostError::Banned(reason) if reason.is_empty() => "You are banned from posting here.".to_string(),
+            PostError::Banned(reason) => format!("You are banned from posting here. Reason: {}", reason),
+            PostError::Database(_) => error::SERVER_ERROR_MESSAGE.to_string(),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            PostError::FileTooLarge | PostError::FilesTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            PostError::ThreadNotFound | PostError::PostNotFound => StatusCode::NOT_FOUND,
+            PostError::WrongPassword
+            | PostError::DeletionClosed
+            | PostError::NotAuthor
+            | PostError::EditClosed
+            | PostError::CrossSite
+            | PostError::BadToken
+            | PostError::Banned(_) => StatusCode::FORBIDDEN,
+            PostError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn respond(&self, json: bool) -> HttpResponse {
+        if let PostError::Database(e) = self {
+            log::error!("store error: {}", e);
+        }
+        if json {
+            HttpResponse::build(self.status()).json(ApiError {
+                error: ApiErrorBody {
+                    code: self.code(),
+                    message: self.message(),
+                },
+            })
+        } else {
+            error::error_page(self.status(), &self.message())
+        }
+    }
+}
+
+// Checks a title and message as they will be stored, HTML-escaped
+fn text_error(config: &Config, title: &str, message: &str) -> Option<PostError> {
+    if title.trim().is_empty() || message.trim().is_empty() {
+        Some(PostError::MissingFields)
+    } else if title.len() > config.max_title_length {
+        Some(PostError::TitleTooLong)
+    } else if message.len() > config.max_message_length {
+        Some(PostError::MessageTooLong)
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: ApiErrorBody,
 }
 
-fn generate_color_from_id(id: &str) -> String {
-    let mut hasher = DefaultHasher::new();
-    id.hash(&mut hasher);
-    let hash = hasher.finish();
-    let r = (hash & 0xFF) as u8;
-    let g = ((hash >> 8) & 0xFF) as u8;
-    let b = ((hash >> 16) & 0xFF) as u8;
-    format!("#{:02X}{:02X}{:02X}", r, g, b)
+#[derive(Serialize)]
+struct ApiErrorBody {
+    code: &'static str,
+    message: String,
 }
 
-fn sanitize_input(input: &str) -> String {
-    htmlescape::encode_minimal(input)
+#[derive(Serialize)]
+struct CreatedPost {
+    id: i32,
+    board_id: i32,
+    thread_id: i32,
+    url: String,
 }
 
+// Requests under /api or that ask for JSON get JSON back instead of redirects
+fn wants_json(req: &HttpRequest) -> bool {
+    req.path().starts_with("/api/")
+        || req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json"))
+}
+
+// Pages with a post form, starting the visitor's session if needed
+fn page_response(cookie: Option<actix_web::cookie::Cookie<'static>>) -> actix_web::HttpResponseBuilder {
+    let mut response = HttpResponse::Ok();
+    response.content_type("text/html");
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
+    }
+    response
+}
+
+// Files written for a post that is still being processed. They are deleted
+// again unless the post gets saved.
+#[derive(Default)]
+struct Uploads {
+    files: Vec<db::Attachment>,
+    saved: bool,
+}
+
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        if !self.saved {
+            for file in &self.files {
+                let _ = std::fs::remove_file(&file.file_path);
+                if let Some(thumbnail_path) = &file.thumbnail_path {
+                    let _ = std::fs::remove_file(thumbnail_path);
+                }
+            }
+        }
+    }
+}
+
+// Extractors, one per piece of app state
+#[allow(clippy::too_many_arguments)]
 async fn save_file(
+    req: HttpRequest,
     mut payload: Multipart,
     conn: web::Data<Mutex<Connection>>,
+    store: web::Data<dyn Store>,
+    secret: web::Data<ServerSecret>,
+    hub: web::Data<live::Hub>,
+    config: web::Data<Config>,
     board_id: web::Path<i32>,
-) -> Result<HttpResponse> {
+) -> Result<HttpResponse, AppError> {
+    let json = wants_json(&req);
+    let mut name = String::new();
     let mut title = String::new();
     let mut message = String::new();
-    let mut file_path = None;
+    let mut password = String::new();
+    let mut uploads = Uploads::default();
+    let mut total_size = 0;
     let mut parent_id: i32 = 0;
+    let settings = db::board_settings(&lock(&conn), *board_id);
+
+    // Forms must carry the token of the visitor's session as their first
+    // field. API clients have no session and only get the origin check.
+    if !csrf::same_origin(&req) {
+        return Ok(PostError::CrossSite.respond(json));
+    }
+    let mut token_checked = !csrf::token_required(&req);
+
+    // Checked before anything is read, so banned posters can't fill the disk
+    if let Some(addr) = req.peer_addr() {
+        match db::active_ban(&lock(&conn), &addr.ip().to_string(), *board_id) {
+            Ok(Some(ban)) => return Ok(PostError::Banned(ban.reason).respond(json)),
+            Ok(None) => {}
+            Err(e) => return Ok(PostError::Database(e.into()).respond(json)),
+        }
+    }
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
         let content_disposition = field.content_disposition().clone();
-        let name = content_disposition.get_name().unwrap_or("").to_string();
+        let field_name = content_disposition.get_name().unwrap_or("").to_string();
+        if !token_checked && field_name != csrf::FIELD_NAME {
+            return Ok(PostError::BadToken.respond(json));
+        }
 
-        match name.as_str() {
+        match field_name.as_str() {
+            csrf::FIELD_NAME => {
+                let mut token = String::new();
+                while let Some(chunk) = field.next().await {
+                    let data = chunk?;
+                    token.push_str(&String::from_utf8_lossy(&data));
+                }
+                if !csrf::verify(&req, &secret.0, &token) {
+                    return Ok(PostError::BadToken.respond(json));
+                }
+                token_checked = true;
+            }
+            "name" => {
+                while let Some(chunk) = field.next().await {
+                    let data = chunk?;
+                    name.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
             "title" => {
                 while let Some(chunk) = field.next().await {
                     let data = chunk?;
@@ -78,10 +327,21 @@ async fn save_file(
                     message.push_str(&String::from_utf8_lossy(&data));
                 }
             }
+            "password" => {
+                while let Some(chunk) = field.next().await {
+                    let data = chunk?;
+                    password.push_str(&String::from_utf8_lossy(&data));
+                }
+            }
             "file" => {
-                if let Some(filename) = content_disposition.get_filename() {
+                // Browsers send an empty file field when nothing was picked
+                if let Some(filename) = content_disposition.get_filename().filter(|f| !f.is_empty()) {
+                    if uploads.files.len() >= settings.max_files {
+                        return Ok(PostError::TooManyFiles(settings.max_files).respond(json));
+                    }
+
                     let mime_type = MimeGuess::from_path(filename).first_or_octet_stream();
-                    let sanitized_filename = sanitize_filename::sanitize(&filename);
+                    let sanitized_filename = sanitize_filename::sanitize(filename);
                     let unique_id: String = rand::thread_rng()
                         .sample_iter(&Alphanumeric)
                         .take(6)
@@ -89,28 +349,82 @@ async fn save_file(
                         .collect();
                     let unique_filename = format!("{}-{}", unique_id, sanitized_filename);
 
-                    let valid_mime_types = [
-                        MIME_IMAGE_JPEG,
-                        MIME_IMAGE_PNG,
-                        MIME_IMAGE_GIF,
-                        MIME_IMAGE_WEBP,
-                        MIME_VIDEO_MP4,
-                        MIME_AUDIO_MPEG,
-                        MIME_VIDEO_WEBM,
-                    ];
-
-                    if valid_mime_types.contains(&mime_type.as_ref()) {
-                        let file_path_string = format!("./static/{}", unique_filename);
-                        let file_path_clone = file_path_string.clone();
-                        let mut f =
-                            web::block(move || std::fs::File::create(file_path_clone)).await??;
-
-                        while let Some(chunk) = field.next().await {
-                            let data = chunk?;
+                    if !media::is_allowed(mime_type.as_ref()) {
+                        return Ok(PostError::FileTypeRejected.respond(json));
+                    }
+
+                    let file_path_string = format!("{}/{}", config.upload_dir.trim_end_matches('/'), unique_filename);
+                    let file_path_clone = file_path_string.clone();
+                    let mut f =
+                        web::block(move || std::fs::File::create(file_path_clone)).await??;
+                    uploads.files.push(db::Attachment {
+                        file_path: file_path_string.clone(),
+                        original_name: Some(filename.chars().take(255).collect()),
+                        mime_type: Some(mime_type.to_string()),
+                        size: None,
+                        width: None,
+                        height: None,
+                        duration: None,
+                        thumbnail_path: None,
+                        sha256: None,
+                        md5: None,
+                    });
+
+                    // Images are held in memory so their metadata never reaches the disk
+                    let clean_image = metadata::handles(mime_type.as_ref());
+                    let mut image = Vec::new();
+                    let mut size = 0;
+                    while let Some(chunk) = field.next().await {
+                        let data = chunk?;
+                        size += data.len();
+                        total_size += data.len();
+                        if size > config.max_file_size {
+                            return Ok(PostError::FileTooLarge.respond(json));
+                        }
+                        if total_size > config.max_total_size {
+                            return Ok(PostError::FilesTooLarge.respond(json));
+                        }
+                        if clean_image {
+                            image.extend_from_slice(&data);
+                        } else {
                             f = web::block(move || f.write_all(&data).map(|_| f)).await??;
                         }
+                    }
+
+                    if clean_image {
+                        let (reencode, keep_orientation) = (settings.reencode_images, settings.keep_orientation);
+                        let mime_type = mime_type.clone();
+                        let cleaned = web::block(move || {
+                            metadata::clean(&image, mime_type.as_ref(), reencode, keep_orientation)
+                        })
+                        .await?;
+                        match cleaned {
+                            Some(cleaned) => web::block(move || f.write_all(&cleaned)).await??,
+                            None => return Ok(PostError::ImageUnreadable.respond(json)),
+                        }
+                    }
 
-                        file_path = Some(file_path_string);
+                    // Read back what was stored, since cleaning may have changed it
+                    let (size, info, thumbnail_path, sha256, md5) = web::block(move || {
+                        std::fs::read(&file_path_string).map(|data| {
+                            (
+                                data.len(),
+                                media_info::probe(&data, mime_type.as_ref()),
+                                media::make_thumbnail(&data, mime_type.as_ref(), &file_path_string),
+                                media::content_hash(&data),
+                                media::content_md5(&data),
+                            )
+                        })
+                    })
+                    .await??;
+                    if let Some(file) = uploads.files.last_mut() {
+                        file.sha256 = Some(sha256);
+                        file.md5 = Some(md5);
+                        file.size = Some(size as i64);
+                        file.width = info.width.map(i64::from);
+                        file.height = info.height.map(i64::from);
+                        file.duration = info.duration;
+                        file.thumbnail_path = thumbnail_path;
                     }
                 }
             }
@@ -124,15 +438,26 @@ async fn save_file(
         }
     }
 
+    if !token_checked {
+        return Ok(PostError::BadToken.respond(json));
+    }
+
+    let (name, tripcode) = tripcode::parse_name(&name, &secret.0);
+    let name = sanitize_input(&name);
     let title = sanitize_input(&title);
     let message = sanitize_input(&message);
 
-    if title.trim().is_empty() || message.trim().is_empty() {
-        return Ok(HttpResponse::BadRequest().body("Title and message are mandatory."));
-    }
-
-    if title.len() > 30 || message.len() > 50000 {
-        return Ok(HttpResponse::BadRequest().body("Title or message is too long."));
+    let error = if let Some(error) = text_error(&config, &title, &message) {
+        Some(error)
+    } else if name.len() > config.max_name_length {
+        Some(PostError::NameTooLong)
+    } else if password.chars().count() > deletion::MAX_PASSWORD_LENGTH {
+        Some(PostError::PasswordTooLong)
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return Ok(error.respond(json));
     }
 
     let post_id: String = rand::thread_rng()
@@ -141,198 +466,393 @@ async fn save_file(
         .map(char::from)
         .collect();
 
-    let conn = conn.lock().unwrap();
-    match conn.execute(
-        "INSERT INTO files (post_id, parent_id, title, message, file_path, board_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![post_id, parent_id, title, message, file_path, *board_id],
-    ) {
-        Ok(_) => {
+    if parent_id != 0 {
+        match store.thread_exists(*board_id, parent_id) {
+            Ok(true) => {}
+            Ok(false) => return Ok(PostError::ThreadNotFound.respond(json)),
+            Err(e) => return Ok(PostError::Database(e).respond(json)),
+        }
+    }
+
+    let (name, tripcode) = if settings.force_anonymous {
+        (None, None)
+    } else {
+        (Some(name).filter(|name| !name.is_empty()), tripcode)
+    };
+
+    // Browsers get an author key with their first post. API clients only
+    // have one if they keep cookies.
+    let (author, author_cookie) = match author::author(&req, &secret.0) {
+        Some(author) => (Some(author), None),
+        None if !json && config.edit_window > 0 => {
+            let (author, cookie) = author::issue(&secret.0);
+            (Some(author), Some(cookie))
+        }
+        None => (None, None),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    let post = db::Post {
+        id: 0,
+        post_id,
+        parent_id,
+        title,
+        message,
+        files: uploads.files.clone(),
+        name,
+        tripcode,
+        poster_id: None,
+        created_at: now,
+        last_reply_at: now,
+        edited_at: None,
+    };
+
+    let key = &secret.0;
+    let derive_poster_id;
+    let poster = db::Poster {
+        deletion_password: Some(password.as_str())
+            .filter(|password| !password.is_empty())
+            .map(|password| deletion::hash_password(key, password)),
+        author,
+        poster_id: match req.peer_addr() {
+            Some(addr) if settings.poster_ids => {
+                let ip = addr.ip();
+                derive_poster_id = move |thread_id: i32| poster_id::poster_id(key, ip, thread_id as i64);
+                Some(&derive_poster_id as &dyn Fn(i32) -> String)
+            }
+            _ => None,
+        },
+    };
+
+    match store.insert_post(*board_id, &post, &poster) {
+        Ok(id) => {
+            uploads.saved = true;
+            let thread_id = if parent_id == 0 { id } else { parent_id };
+
+            // The post is saved by now, so a failed bump is logged rather
+            // than reported as a failed post
+            if parent_id != 0 {
+                if let Err(e) = store.bump(parent_id, now) {
+                    log::error!("thread {} not bumped: {}", parent_id, e);
+                }
+            }
+
+            // Open thread and board pages pick the post up from here
+            if let Ok(Some(post)) = store.fetch_post(id) {
+                if let Ok(event) = live::post_event(store.get_ref(), *board_id, post) {
+                    hub.publish(event);
+                }
+            }
             if parent_id != 0 {
-                conn.execute(
-                    "UPDATE files SET last_reply_at = CURRENT_TIMESTAMP WHERE id = ?1 OR parent_id = ?1",
-                    params![parent_id],
-                )
-                .unwrap();
+                hub.publish(live::bump_event(*board_id, parent_id, id));
+            }
+
+            if json {
+                Ok(HttpResponse::Created().json(CreatedPost {
+                    id,
+                    board_id: *board_id,
+                    thread_id,
+                    url: format!("/{}/post/{}", board_id, thread_id),
+                }))
+            } else {
+                let location = if parent_id == 0 {
+                    format!("/{}", board_id)
+                } else {
+                    format!("/{}/post/{}", board_id, parent_id)
+                };
+                let mut response = HttpResponse::SeeOther();
+                response.append_header(("Location", location));
+                // Filled into the next form, so one password covers all posts
+                if !password.is_empty() {
+                    response.cookie(deletion::password_cookie(&password));
+                }
+                if let Some(cookie) = author_cookie {
+                    response.cookie(cookie);
+                }
+                Ok(response.finish())
             }
+        }
+        Err(e) => Ok(PostError::Database(e).respond(json)),
+    }
+}
+
+// The form under each post that its poster can still delete
+fn delete_form_html(board_id: i32, post: &db::Post, csrf_token: &str, password: &str) -> String {
+    let file_only = if post.files.is_empty() {
+        ""
+    } else {
+        r#"<label><input type="checkbox" name="file_only" value="1"> File only</label>"#
+    };
+    format!(
+        r#"<details class="delete-post"><summary>Delete</summary><form action="/{}/post/{}/delete" method="post"><input type="hidden" name="csrf_token" value="{}"><input type="password" name="password" maxlength="{}" value="{}" placeholder="Password" autocomplete="off" required>{}<button type="submit">Delete</button></form></details>"#,
+        board_id,
+        post.id,
+        csrf_token,
+        deletion::MAX_PASSWORD_LENGTH,
+        sanitize_input(password),
+        file_only
+    )
+}
+
+#[derive(Deserialize)]
+struct DeleteForm {
+    csrf_token: String,
+    password: String,
+    // Present when only the files should go
+    file_only: Option<String>,
+}
+
+// Lets posters delete their own post, or just its files, with the password
+// they posted it with, for `deletion_window` seconds after posting
+async fn delete_post(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    hub: web::Data<live::Hub>,
+    secret: web::Data<ServerSecret>,
+    config: web::Data<Config>,
+    path: web::Path<(i32, i32)>,
+    form: web::Form<DeleteForm>,
+) -> Result<HttpResponse, AppError> {
+    let json = wants_json(&req);
+    let (board_id, id) = path.into_inner();
+    if !csrf::same_origin(&req) {
+        return Ok(PostError::CrossSite.respond(json));
+    }
+    if !csrf::verify(&req, &secret.0, &form.csrf_token) {
+        return Ok(PostError::BadToken.respond(json));
+    }
+
+    let post = match store.fetch_post(id) {
+        Ok(Some(post)) => post,
+        Ok(None) => return Ok(PostError::PostNotFound.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    };
+    let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+    match store.thread_exists(board_id, thread_id) {
+        Ok(true) => {}
+        Ok(false) => return Ok(PostError::PostNotFound.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    }
+    if !within_window(post.created_at, config.deletion_window, chrono::Utc::now().timestamp()) {
+        return Ok(PostError::DeletionClosed.respond(json));
+    }
+    match store.deletion_password(id) {
+        Ok(Some(hash)) if deletion::verify_password(&secret.0, &hash, &form.password) => {}
+        // Posts without a password can't be deleted by anyone
+        Ok(_) => return Ok(PostError::WrongPassword.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    }
 
-            if parent_id == 0 {
-                Ok(HttpResponse::SeeOther().append_header(("Location", format!("/{}", board_id))).finish())
+    let file_only = form.file_only.is_some();
+    let deleted = if file_only { store.delete_files(id) } else { store.delete_post(id) };
+    match deleted {
+        Ok(deleted) => {
+            hub.publish(live::delete_event(board_id, thread_id, id, file_only));
+            web::block(move || admin::remove_files(&deleted.files)).await?;
+            if json {
+                Ok(HttpResponse::NoContent().finish())
             } else {
-                Ok(HttpResponse::SeeOther().append_header(("Location", format!("/{}/post/{}", board_id, parent_id))).finish())
+                let location = if post.parent_id == 0 && !file_only {
+                    format!("/{}", board_id)
+                } else {
+                    format!("/{}/post/{}", board_id, thread_id)
+                };
+                Ok(HttpResponse::SeeOther().append_header(("Location", location)).finish())
             }
         }
-        Err(e) => Ok(HttpResponse::InternalServerError().body(format!("Database error: {}", e))),
+        Err(e) => Ok(PostError::Database(e).respond(json)),
+    }
+}
+
+// The form under each post that its author can still edit. The title and
+// message are stored escaped, which is what the field and textarea want.
+fn edit_form_html(board_id: i32, post: &db::Post, csrf_token: &str, config: &Config) -> String {
+    format!(
+        r#"<details class="edit-post"><summary>Edit</summary><form action="/{}/post/{}/edit" method="post"><input type="hidden" name="csrf_token" value="{}"><input type="text" name="title" maxlength="{}" value="{}" required><textarea name="message" maxlength="{}" required>{}</textarea><button type="submit">Save</button></form></details>"#,
+        board_id,
+        post.id,
+        csrf_token,
+        config.max_title_length,
+        post.title,
+        config.max_message_length,
+        post.message
+    )
+}
+
+#[derive(Deserialize)]
+struct EditForm {
+    csrf_token: String,
+    title: String,
+    message: String,
+}
+
+// Lets authors change the title and message of their post for
+// `edit_window` seconds after posting. Earlier versions are kept.
+async fn edit_post(
+    req: HttpRequest,
+    store: web::Data<dyn Store>,
+    secret: web::Data<ServerSecret>,
+    config: web::Data<Config>,
+    path: web::Path<(i32, i32)>,
+    form: web::Form<EditForm>,
+) -> Result<HttpResponse, AppError> {
+    let json = wants_json(&req);
+    let (board_id, id) = path.into_inner();
+    if !csrf::same_origin(&req) {
+        return Ok(PostError::CrossSite.respond(json));
+    }
+    if !csrf::verify(&req, &secret.0, &form.csrf_token) {
+        return Ok(PostError::BadToken.respond(json));
+    }
+
+    let post = match store.fetch_post(id) {
+        Ok(Some(post)) => post,
+        Ok(None) => return Ok(PostError::PostNotFound.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    };
+    let thread_id = if post.parent_id == 0 { post.id } else { post.parent_id };
+    match store.thread_exists(board_id, thread_id) {
+        Ok(true) => {}
+        Ok(false) => return Ok(PostError::PostNotFound.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    }
+    let now = chrono::Utc::now().timestamp();
+    if !within_window(post.created_at, config.edit_window, now) {
+        return Ok(PostError::EditClosed.respond(json));
+    }
+    let author = author::author(&req, &secret.0);
+    match store.author(id) {
+        Ok(Some(stored)) if author.as_deref() == Some(stored.as_str()) => {}
+        Ok(_) => return Ok(PostError::NotAuthor.respond(json)),
+        Err(e) => return Ok(PostError::Database(e).respond(json)),
+    }
+
+    let title = sanitize_input(&form.title);
+    let message = sanitize_input(&form.message);
+    if let Some(error) = text_error(&config, &title, &message) {
+        return Ok(error.respond(json));
+    }
+    match store.edit_post(id, &title, &message, now) {
+        Ok(_) if json => Ok(HttpResponse::NoContent().finish()),
+        Ok(_) => Ok(HttpResponse::SeeOther()
+            .append_header(("Location", format!("/{}/post/{}#p{}", board_id, thread_id, id)))
+            .finish()),
+        Err(e) => Ok(PostError::Database(e).respond(json)),
     }
 }
 
 async fn view_post(
+    req: HttpRequest,
     conn: web::Data<Mutex<Connection>>,
+    store: web::Data<dyn Store>,
+    secret: web::Data<ServerSecret>,
+    config: web::Data<Config>,
     path: web::Path<(i32, i32)>,
-) -> Result<HttpResponse> {
-    let conn = conn.lock().unwrap();
+) -> Result<HttpResponse, AppError> {
     let (board_id, post_id) = path.into_inner();
 
-    let mut stmt = conn
-        .prepare(
-            "SELECT id, post_id, parent_id, title, message, file_path FROM files WHERE (id = ?1 OR parent_id = ?1) AND board_id = ?2 ORDER BY id ASC",
-        )
-        .unwrap();
-    let posts = stmt
-        .query_map(params![post_id, board_id], |row| {
-            Ok((
-                row.get::<_, i32>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, i32>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, Option<String>>(5)?,
-            ))
-        })
-        .unwrap();
+    let posts = store.fetch_thread(board_id, post_id)?;
+    if posts.is_empty() {
+        return Err(AppError::NotFound);
+    }
+    let settings = db::board_settings(&lock(&conn), board_id);
 
-    let mut posts_html = String::new();
-    let mut is_original_post = true;
-    let mut reply_count = 1;
+    let (session, cookie) = csrf::session(&req);
+    let csrf_token = csrf::token(&secret.0, &session);
+    let password = deletion::remembered_password(&req);
+    let author = author::author(&req, &secret.0);
+    let now = chrono::Utc::now().timestamp();
 
-    for post in posts {
-        let (_id, _post_id, _parent_id, title, message, file_path) = post.unwrap();
-        posts_html.push_str("<div class=\"post\">");
-        if is_original_post {
-            posts_html.push_str("<div class=\"post-id\">Original Post</div>");
-            is_original_post = false;
-        } else {
-            posts_html.push_str(&format!("<div class=\"post-id\">Reply {}</div>", reply_count));
-            reply_count += 1;
+    let mut posts_html = String::new();
+    for (reply_number, post) in posts.into_iter().enumerate() {
+        let mut actions = String::new();
+        if within_window(post.created_at, config.edit_window, now)
+            && author.is_some()
+            && store.author(post.id)? == author
+        {
+            actions.push_str(&edit_form_html(board_id, &post, &csrf_token, &config));
         }
-        posts_html.push_str(&format!("<div class=\"post-title\">{}</div>", title));
-        if let Some(file_path) = file_path {
-            if file_path.ends_with(".jpg")
-                || file_path.ends_with(".jpeg")
-                || file_path.ends_with(".png")
-                || file_path.ends_with(".gif")
-                || file_path.ends_with(".webp")
-            {
-                posts_html.push_str(&format!(
-                    r#"<img src="/static/{}"><br>"#,
-                    file_path.trim_start_matches("./static/")
-                ));
-            } else if file_path.ends_with(".mp4")
-                || file_path.ends_with(".mp3")
-                || file_path.ends_with(".webm")
-            {
-                posts_html.push_str(&format!(
-                    r#"<video controls><source src="/static/{}"></video><br>"#,
-                    file_path.trim_start_matches("./static/")
-                ));
-            }
+        if within_window(post.created_at, config.deletion_window, now) {
+            actions.push_str(&delete_form_html(board_id, &post, &csrf_token, &password));
         }
-        posts_html.push_str(&format!("<div class=\"post-message\">{}</div>", message));
-        posts_html.push_str("</div>");
+        posts_html.push_str(&render_thread_post(post, reply_number as i64, &actions));
     }
 
     let context = HashMap::from([
         ("PARENT_ID", post_id.to_string()),
         ("POSTS", posts_html),
         ("BOARD_ID", board_id.to_string()),
+        ("NAME_FIELD", name_field_html(&config, &settings)),
+        ("FILE_FIELD", file_field_html(&settings)),
+        ("MAX_TITLE_LENGTH", config.max_title_length.to_string()),
+        ("MAX_MESSAGE_LENGTH", config.max_message_length.to_string()),
+        ("PASSWORD_FIELD", password_field_html(&config, &password)),
+        ("CSRF_TOKEN", csrf_token),
     ]);
 
-    let body = render_template("templates/view_post.html", &context);
+    let body = render_template("templates/view_post.html", &context)?;
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+    Ok(page_response(cookie).body(body))
 }
 
 
 async fn board(
+    req: HttpRequest,
     conn: web::Data<Mutex<Connection>>,
+    store: web::Data<dyn Store>,
+    secret: web::Data<ServerSecret>,
+    config: web::Data<Config>,
     board_id: web::Path<i32>,
     query: web::Query<HashMap<String, String>>,
-) -> Result<HttpResponse> {
-    let conn = conn.lock().unwrap();
-    let page: usize = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1);
-    let offset = (page - 1) * POSTS_PER_PAGE;
+) -> Result<HttpResponse, AppError> {
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+    let per_page = config.posts_per_page;
+    let offset = (page - 1).saturating_mul(per_page);
 
     // Get the total number of posts
-    let total_posts: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM files WHERE parent_id = 0 AND board_id = ?1",
-        params![*board_id],
-        |row| row.get(0),
-    ).unwrap_or(0);
+    let total_posts = store.count_threads(*board_id)?;
 
     // Determine if there is a next page
-    let total_pages = (total_posts as f64 / POSTS_PER_PAGE as f64).ceil() as usize;
+    let total_pages = (total_posts as f64 / per_page as f64).ceil() as usize;
     let has_next_page = page < total_pages;
 
-    let mut stmt = conn.prepare(
-        "SELECT id, post_id, title, message, file_path FROM files WHERE parent_id = 0 AND board_id = ?1 ORDER BY last_reply_at DESC LIMIT ?2 OFFSET ?3",
-    ).unwrap();
-    let posts = stmt.query_map(params![*board_id, POSTS_PER_PAGE as i64, offset as i64], |row| {
-        Ok((
-            row.get::<_, i32>(0)?,
-            row.get::<_, String>(1)?,
-            row.get::<_, String>(2)?,
-            row.get::<_, String>(3)?,
-            row.get::<_, Option<String>>(4)?,
-        ))
-    }).unwrap();
+    let posts = store.list_threads(*board_id, per_page, offset)?;
+    let settings = db::board_settings(&lock(&conn), *board_id);
+    let (session, cookie) = csrf::session(&req);
 
     let mut posts_html = String::new();
 
     for post in posts {
-        let (id, post_id, title, message, file_path) = post.unwrap();
-
-        let reply_count: i32 = conn.query_row(
-            "SELECT COUNT(*) FROM files WHERE parent_id = ?1",
-            params![id],
-            |row| row.get(0),
-        ).unwrap_or(0);
+        let (reply_count, _) = store.count_replies(post.id)?;
 
-        let truncated_message = if message.len() > 2700 {
+        let truncated_message = if post.message.len() > config.preview_length {
+            let mut end = config.preview_length;
+            while !post.message.is_char_boundary(end) {
+                end -= 1;
+            }
             format!(
                 "{}... <a href=\"/{}/post/{}\" class=\"view-full-post\">Click here to open full post</a>",
-                &message[..2700],
+                &post.message[..end],
                 *board_id,
-                id
+                post.id
             )
         } else {
-            message.clone()
+            post.message.clone()
         };
 
-        let post_color = generate_color_from_id(&post_id);
-
         posts_html.push_str("<div class=\"post\">");
-        posts_html.push_str(&format!(
-            "<div class=\"post-id-box\" style=\"background-color: {}\">{}</div>",
-            post_color, post_id
-        ));
+        posts_html.push_str(&render_id_box(&post));
+        posts_html.push_str(&render_poster(post.name, post.tripcode));
         posts_html.push_str(&format!(
             "<div class=\"post-title title-green\">{}</div>",
-            title
+            post.title
         ));
-        if let Some(file_path) = file_path {
-            if file_path.ends_with(".jpg")
-                || file_path.ends_with(".jpeg")
-                || file_path.ends_with(".png")
-                || file_path.ends_with(".gif")
-                || file_path.ends_with(".webp")
-            {
-                posts_html.push_str(&format!(
-                    r#"<img src="/static/{}"><br>"#,
-                    file_path.trim_start_matches("./static/")
-                ));
-            } else if file_path.ends_with(".mp4")
-                || file_path.ends_with(".mp3")
-                || file_path.ends_with(".webm")
-            {
-                posts_html.push_str(&format!(
-                    r#"<video controls><source src="/static/{}"></video><br>"#,
-                    file_path.trim_start_matches("./static/")
-                ));
-            }
-        }
+        posts_html.push_str(&media::render_attachments(&post.files));
         posts_html.push_str(&format!("<div class=\"post-message\">{}</div>", truncated_message));
+        posts_html.push_str(&render_edited(post.edited_at));
         posts_html.push_str(&format!(
             "<a class=\"reply-button\" href=\"/{}/post/{}\">Reply ({})</a>",
-            *board_id, id, reply_count
+            *board_id, post.id, reply_count
         ));
         posts_html.push_str("</div>");
     }
@@ -351,61 +871,189 @@ async fn board(
         ("POSTS", posts_html),
         ("PAGINATION", pagination_html),
         ("BOARD_ID", board_id.to_string()),
+        ("NAME_FIELD", name_field_html(&config, &settings)),
+        ("FILE_FIELD", file_field_html(&settings)),
+        ("MAX_TITLE_LENGTH", config.max_title_length.to_string()),
+        ("MAX_MESSAGE_LENGTH", config.max_message_length.to_string()),
+        ("PASSWORD_FIELD", password_field_html(&config, &deletion::remembered_password(&req))),
+        ("CSRF_TOKEN", csrf::token(&secret.0, &session)),
     ]);
 
-    let body = render_template("templates/board.html", &context);
+    let body = render_template("templates/board.html", &context)?;
 
-    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+    Ok(page_response(cookie).body(body))
 }
 
-fn initialize_db() -> SqlResult<Connection> {
-    let conn = Connection::open("my_database.db")?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS files (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            post_id TEXT NOT NULL,
-            parent_id INTEGER,
-            title TEXT NOT NULL,
-            message TEXT NOT NULL,
-            file_path TEXT,
-            board_id INTEGER NOT NULL,
-            last_reply_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-        )",
-        [],
-    )?;
-    Ok(conn)
+fn load_or_create_secret(path: &str) -> std::io::Result<ServerSecret> {
+    match read_to_string(path) {
+        Ok(secret) => Ok(ServerSecret(secret.trim().as_bytes().to_vec())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let secret: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(64)
+                .map(char::from)
+                .collect();
+            std::fs::write(path, &secret)?;
+            Ok(ServerSecret(secret.into_bytes()))
+        }
+        Err(e) => Err(e),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let conn = initialize_db().unwrap();
-    let conn_data = Data::new(Mutex::new(conn));
+    // RUST_LOG overrides the level
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = Config::load()?;
+    std::fs::create_dir_all(&config.upload_dir)?;
+    let conn = db::initialize_db(&config.database_path)
+        .map_err(|e| std::io::Error::other(format!("{}: {}", config.database_path, e)))?;
+    let conn = Arc::new(Mutex::new(conn));
+    let store_data: Data<dyn Store> = Data::from(store::open(&config, conn.clone())?);
+    let moved = media::move_old_uploads(store_data.get_ref(), &config.upload_dir)?;
+    if moved > 0 {
+        log::info!("moved {} uploads from {} to {}", moved, media::OLD_UPLOAD_DIR, config.upload_dir);
+    }
+    let conn_data = Data::from(conn);
+    let secret_data = Data::new(load_or_create_secret(&config.secret_path)?);
+    let hub_data = Data::new(live::Hub::default());
+    actix_web::rt::spawn(live::sweep_periodically(hub_data.clone()));
+    actix_web::rt::spawn(live::relay_deletion_notices(hub_data.clone(), conn_data.clone()));
+    let bind = config.bind.clone();
+    // Headers for each group of routes
+    let pages = SecurityHeaders::pages(&config);
+    let data = SecurityHeaders::data(&config);
+    let uploads = SecurityHeaders::media(&config);
+    let config_data = Data::new(config);
 
     HttpServer::new(move || {
         App::new()
             .app_data(conn_data.clone())
-            .app_data(Data::new(web::JsonConfig::default().limit(MAX_SIZE)))
+            .app_data(store_data.clone())
+            .app_data(secret_data.clone())
+            .app_data(hub_data.clone())
+            .app_data(config_data.clone())
+            .app_data(Data::new(web::JsonConfig::default().limit(config_data.max_file_size)))
+            // Malformed ids and forms get the error page like everything else
+            .app_data(web::PathConfig::default().error_handler(|_, _| AppError::NotFound.into()))
+            .app_data(web::FormConfig::default().error_handler(|_, _| {
+                AppError::BadRequest("The form could not be read.".to_string()).into()
+            }))
             .service(
                 web::resource("/")
-                    .route(web::get().to(|| async { 
-                        fs::NamedFile::open_async("./static/index.html").await.unwrap()
+                    .wrap(pages.middleware())
+                    .route(web::get().to(|| async {
+                        fs::NamedFile::open_async("./static/index.html").await.map_err(AppError::from)
                     }))
             )
+            .service(
+                web::resource("/boards.json")
+                    .wrap(data.middleware())
+                    .route(web::get().to(api::boards))
+            )
+            .service(
+                web::resource("/search")
+                    .wrap(pages.middleware())
+                    .route(web::get().to(search::search))
+            )
+            .service(
+                web::resource("/live")
+                    .wrap(data.middleware())
+                    .route(web::get().to(ws::board_activity))
+            )
             .service(
                 web::resource("/{board_id}")
+                    .wrap(pages.middleware())
                     .route(web::get().to(board))
             )
+            .service(
+                web::resource("/{board_id}/page/{page}.json")
+                    .wrap(data.middleware())
+                    .route(web::get().to(api::board_page))
+            )
+            .service(
+                web::resource("/{board_id}/thread/{id}.json")
+                    .wrap(data.middleware())
+                    .route(web::get().to(api::thread))
+            )
+            .service(
+                web::resource("/{board_id}/catalog.json")
+                    .wrap(data.middleware())
+                    .route(web::get().to(api::catalog))
+            )
+            .service(
+                web::resource("/{board_id}/search")
+                    .wrap(pages.middleware())
+                    .route(web::get().to(search::board_search))
+            )
+            .service(
+                web::resource("/{board_id}/feed.atom")
+                    .wrap(data.middleware())
+                    .route(web::get().to(feeds::board_atom))
+            )
+            .service(
+                web::resource("/{board_id}/feed.rss")
+                    .wrap(data.middleware())
+                    .route(web::get().to(feeds::board_rss))
+            )
+            .service(
+                web::resource("/{board_id}/events")
+                    .wrap(data.middleware())
+                    .route(web::get().to(live::board_events))
+            )
             .service(
                 web::resource("/{board_id}/upload")
+                    .wrap(pages.middleware())
+                    .route(web::post().to(save_file))
+            )
+            .service(
+                web::resource("/api/{board_id}/upload")
+                    .wrap(pages.middleware())
                     .route(web::post().to(save_file))
             )
             .service(
                 web::resource("/{board_id}/post/{id}")
+                    .wrap(pages.middleware())
                     .route(web::get().to(view_post))
             )
-            .service(fs::Files::new("/static", "./static").show_files_listing())
+            .service(
+                web::resource("/{board_id}/post/{id}/delete")
+                    .wrap(pages.middleware())
+                    .route(web::post().to(delete_post))
+            )
+            .service(
+                web::resource("/{board_id}/post/{id}/edit")
+                    .wrap(pages.middleware())
+                    .route(web::post().to(edit_post))
+            )
+            .service(
+                web::resource("/{board_id}/post/{id}/feed.atom")
+                    .wrap(data.middleware())
+                    .route(web::get().to(feeds::thread_atom))
+            )
+            .service(
+                web::resource("/{board_id}/post/{id}/events")
+                    .wrap(data.middleware())
+                    .route(web::get().to(live::thread_events))
+            )
+            .service(
+                web::resource("/media/{name}")
+                    .wrap(uploads.middleware())
+                    .route(web::get().to(media::serve))
+                    .route(web::head().to(media::serve))
+            )
+            .service(
+                web::scope("/static")
+                    .wrap(pages.middleware())
+                    .service(
+                        fs::Files::new("", "./static")
+                            .path_filter(|path, _| SITE_ASSETS.iter().any(|asset| path == Path::new(asset)))
+                            .default_handler(web::to(|| async { Err::<HttpResponse, _>(AppError::NotFound) })),
+                    )
+            )
+            .default_service(web::to(|| async { Err::<HttpResponse, _>(AppError::NotFound) }).wrap(pages.middleware()))
     })
-    .bind("0.0.0.0:8082")?
+    .bind(bind)?
     .run()
     .await
 }
@@ -0,0 +1,427 @@
+// Everything that depends on the kind of an uploaded file: which types are
+// accepted, how each is shown in a post, thumbnails, and serving the files.
+// Attachments are rendered and served with their stored MIME type, never
+// one guessed from their file name.
+use actix_files::NamedFile;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue, HeaderValue,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use mime_guess::MimeGuess;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io::{self, Cursor};
+use std::path::Path;
+
+use crate::db::Attachment;
+use crate::error::AppError;
+use crate::media_info::mp4_child;
+use crate::sanitize_input;
+use crate::store::Store;
+
+pub const MIME_IMAGE_JPEG: &str = "image/jpeg";
+pub const MIME_IMAGE_PNG: &str = "image/png";
+pub const MIME_IMAGE_GIF: &str = "image/gif";
+pub const MIME_IMAGE_WEBP: &str = "image/webp";
+pub const MIME_VIDEO_MP4: &str = "video/mp4";
+pub const MIME_VIDEO_WEBM: &str = "video/webm";
+pub const MIME_AUDIO_MPEG: &str = "audio/mpeg";
+
+// Longest side of a thumbnail, in pixels
+const THUMBNAIL_SIZE: u32 = 200;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Image,
+    Video,
+    Audio,
+}
+
+pub struct MediaType {
+    pub mime_type: &'static str,
+    pub kind: Kind,
+}
+
+// The types `save_file` accepts
+pub const REGISTRY: [MediaType; 7] = [
+    MediaType { mime_type: MIME_IMAGE_JPEG, kind: Kind::Image },
+    MediaType { mime_type: MIME_IMAGE_PNG, kind: Kind::Image },
+    MediaType { mime_type: MIME_IMAGE_GIF, kind: Kind::Image },
+    MediaType { mime_type: MIME_IMAGE_WEBP, kind: Kind::Image },
+    MediaType { mime_type: MIME_VIDEO_MP4, kind: Kind::Video },
+    MediaType { mime_type: MIME_VIDEO_WEBM, kind: Kind::Video },
+    MediaType { mime_type: MIME_AUDIO_MPEG, kind: Kind::Audio },
+];
+
+pub fn lookup(mime_type: &str) -> Option<&'static MediaType> {
+    REGISTRY.iter().find(|media_type| media_type.mime_type == mime_type)
+}
+
+pub fn is_allowed(mime_type: &str) -> bool {
+    lookup(mime_type).is_some()
+}
+
+// Files from before MIME types were stored only have their name to go by
+pub(crate) fn mime_type_of(file: &Attachment) -> Cow<'_, str> {
+    match &file.mime_type {
+        Some(mime_type) => Cow::Borrowed(mime_type),
+        None => Cow::Owned(MimeGuess::from_path(&file.file_path).first_or_octet_stream().to_string()),
+    }
+}
+
+// Where a stored file is served, whichever upload directory it is in
+pub fn public_path(file_path: &str) -> String {
+    format!("/media/{}", file_name(file_path))
+}
+
+fn file_name(file_path: &str) -> &str {
+    Path::new(file_path).file_name().and_then(|name| name.to_str()).unwrap_or("")
+}
+
+fn url(file_path: &str) -> String {
+    sanitize_input(&public_path(file_path))
+}
+
+// "1.2 MB"
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// "0:42", or "1:02:03" from an hour on
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+// "cat.png (1.2 MB, 1920x1080)", linking to the file
+fn render_file_info(file: &Attachment) -> String {
+    let mut details = Vec::new();
+    if let Some(size) = file.size {
+        details.push(format_size(size));
+    }
+    if let (Some(width), Some(height)) = (file.width, file.height) {
+        details.push(format!("{}x{}", width, height));
+    }
+    if let Some(duration) = file.duration {
+        details.push(format_duration(duration));
+    }
+    let mut html = format!(
+        r#"<div class="file-info"><a href="{}" target="_blank">{}</a>"#,
+        url(&file.file_path),
+        sanitize_input(file.display_name())
+    );
+    if !details.is_empty() {
+        html.push_str(&format!(" ({})", details.join(", ")));
+    }
+    html.push_str("</div>");
+    html
+}
+
+fn render_media(file: &Attachment) -> String {
+    let src = url(&file.file_path);
+    let mime_type = mime_type_of(file);
+    match lookup(&mime_type).map(|media_type| media_type.kind) {
+        // The thumbnail links to the full image
+        Some(Kind::Image) => match &file.thumbnail_path {
+            Some(thumbnail) => format!(
+                r#"<a href="{}" target="_blank"><img src="{}" loading="lazy"></a>"#,
+                src,
+                url(thumbnail)
+            ),
+            None => format!(r#"<img src="{}" loading="lazy">"#, src),
+        },
+        // Without a poster image, the fragment has browsers show an early
+        // frame instead of a black box
+        Some(Kind::Video) => match &file.thumbnail_path {
+            Some(poster) => format!(
+                r#"<video controls preload="none" poster="{}"><source src="{}" type="{}"></video>"#,
+                url(poster),
+                src,
+                mime_type
+            ),
+            None => format!(
+                r##"<video controls preload="metadata"><source src="{}#t=0.1" type="{}"></video>"##,
+                src, mime_type
+            ),
+        },
+        Some(Kind::Audio) => {
+            let cover = match &file.thumbnail_path {
+                Some(cover) => format!(r#"<img class="cover-art" src="{}" loading="lazy">"#, url(cover)),
+                None => String::new(),
+            };
+            format!(
+                r#"{}<audio controls preload="metadata"><source src="{}" type="{}"></audio>"#,
+                cover, src, mime_type
+            )
+        }
+        None => format!(r#"<a class="download" href="{}" download>Download</a>"#, src),
+    }
+}
+
+fn render_attachment(file: &Attachment) -> String {
+    format!("<div class=\"attachment\">{}{}</div>", render_file_info(file), render_media(file))
+}
+
+// A single file on its own, several side by side as a gallery
+pub fn render_attachments(files: &[Attachment]) -> String {
+    if files.is_empty() {
+        return String::new();
+    }
+    let class = if files.len() > 1 { "attachments gallery" } else { "attachments" };
+    let mut html = format!("<div class=\"{}\">", class);
+    for file in files {
+        html.push_str(&render_attachment(file));
+    }
+    html.push_str("</div>");
+    html
+}
+
+// Hex SHA-256 of a file's content, to recognize the same file again
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// Base64 MD5 of a file's content, the form API clients expect
+pub fn content_md5(data: &[u8]) -> String {
+    STANDARD.encode(Md5::digest(data))
+}
+
+// Writes a thumbnail next to the file and returns its path: a smaller copy
+// of large images, and the cover art embedded in MP3 and MP4 files. Small
+// images are shown as they are and get none.
+pub fn make_thumbnail(data: &[u8], mime_type: &str, file_path: &str) -> Option<String> {
+    let kind = lookup(mime_type)?.kind;
+    let source = match kind {
+        Kind::Image => data,
+        Kind::Audio => id3_cover_art(data)?,
+        Kind::Video if mime_type == MIME_VIDEO_MP4 => mp4_cover_art(data)?,
+        Kind::Video => return None,
+    };
+
+    let mut decoder = ImageReader::new(Cursor::new(source))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let orientation = decoder.orientation().ok()?;
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    image.apply_orientation(orientation);
+    if kind == Kind::Image && image.width() <= THUMBNAIL_SIZE && image.height() <= THUMBNAIL_SIZE {
+        return None;
+    }
+
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let (thumbnail, extension) = if thumbnail.color().has_alpha() {
+        (DynamicImage::ImageRgba8(thumbnail.to_rgba8()), "png")
+    } else {
+        (DynamicImage::ImageRgb8(thumbnail.to_rgb8()), "jpg")
+    };
+    let thumbnail_path = format!("{}.thumb.{}", file_path, extension);
+    thumbnail.save(&thumbnail_path).ok()?;
+    Some(thumbnail_path)
+}
+
+// iTunes-style cover art, in moov/udta/meta/ilst/covr
+fn mp4_cover_art(data: &[u8]) -> Option<&[u8]> {
+    let udta = mp4_child(mp4_child(data, b"moov")?, b"udta")?;
+    // `meta` starts with a version and flags
+    let ilst = mp4_child(mp4_child(udta, b"meta")?.get(4..)?, b"ilst")?;
+    // `data` starts with a type and a locale
+    mp4_child(mp4_child(ilst, b"covr")?, b"data")?.get(8..)
+}
+
+// ID3v2 sizes use 7 bits per byte
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |size, &byte| (size << 7) | (byte & 0x7f) as usize)
+}
+
+// The picture from an MP3's ID3v2 tag, preferring the front cover
+fn id3_cover_art(data: &[u8]) -> Option<&[u8]> {
+    if data.get(0..3)? != b"ID3" {
+        return None;
+    }
+    let version = *data.get(3)?;
+    let flags = *data.get(5)?;
+    // Unsynchronised tags would need undoing first; they are rare
+    if flags & 0x80 != 0 {
+        return None;
+    }
+    let tag = data.get(10..10 + syncsafe(data.get(6..10)?))?;
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        // Extended header; version 4 counts its own size field, version 3 doesn't
+        pos = match version {
+            4 => syncsafe(tag.get(0..4)?),
+            _ => u32::from_be_bytes(tag.get(0..4)?.try_into().ok()?) as usize + 4,
+        };
+    }
+
+    let (header_length, id_length) = if version == 2 { (6, 3) } else { (10, 4) };
+    let mut pictures = Vec::new();
+    while let Some(header) = tag.get(pos..pos + header_length) {
+        if header[0] == 0 {
+            // Padding
+            break;
+        }
+        let size = match version {
+            2 => header[3..6].iter().fold(0, |size, &byte| (size << 8) | byte as usize),
+            3 => u32::from_be_bytes(header[4..8].try_into().ok()?) as usize,
+            _ => syncsafe(&header[4..8]),
+        };
+        let body = tag.get(pos + header_length..pos + header_length + size)?;
+        let id = &header[..id_length];
+        if id == b"APIC" || id == b"PIC" {
+            if let Some(picture) = id3_picture(body, version == 2) {
+                pictures.push(picture);
+            }
+        }
+        pos += header_length + size;
+    }
+
+    const FRONT_COVER: u8 = 3;
+    let index = pictures.iter().position(|(kind, _)| *kind == FRONT_COVER).unwrap_or(0);
+    pictures.get(index).map(|(_, picture)| *picture)
+}
+
+// Picture type and image data of an APIC (or version 2 PIC) frame
+fn id3_picture(body: &[u8], version_2: bool) -> Option<(u8, &[u8])> {
+    let encoding = *body.first()?;
+    // The MIME type is text ending in a zero byte; version 2 has a fixed
+    // three-letter format instead
+    let mut pos = if version_2 { 4 } else { 1 + body.get(1..)?.iter().position(|&b| b == 0)? + 1 };
+    let picture_type = *body.get(pos)?;
+    pos += 1;
+    // The description ends in one zero byte, or two in UTF-16
+    pos += if encoding == 1 || encoding == 2 {
+        (0..)
+            .map(|i| pos + i * 2)
+            .take_while(|&i| i + 1 < body.len())
+            .find(|&i| body[i] == 0 && body[i + 1] == 0)?
+            - pos
+            + 2
+    } else {
+        body.get(pos..)?.iter().position(|&b| b == 0)? + 1
+    };
+    Some((picture_type, body.get(pos..)?))
+}
+
+// Names with anything but printable ASCII go in `filename*` as well
+fn disposition(name: &str) -> ContentDisposition {
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '_' })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(fallback.clone())];
+    if fallback != name {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: name.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition { disposition: DispositionType::Inline, parameters }
+}
+
+// Stored names start with six random characters and a dash; anything else
+// in the upload directory (stylesheets, scripts) isn't an upload
+pub fn looks_uploaded(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() > 7 && bytes[..6].iter().all(u8::is_ascii_alphanumeric) && bytes[6] == b'-'
+}
+
+// Where uploads were kept, and served from as they were, before they had a
+// directory of their own
+pub const OLD_UPLOAD_DIR: &str = "./static";
+
+// Moves uploads left in the old directory into `upload_dir` and points
+// their posts at them there, returning how many attachments moved. Files
+// go first: a move cut short leaves posts pointing at the old directory,
+// which the next run fixes.
+pub fn move_old_uploads(store: &dyn Store, upload_dir: &str) -> io::Result<usize> {
+    let same_dir = match (Path::new(OLD_UPLOAD_DIR).canonicalize(), Path::new(upload_dir).canonicalize()) {
+        (Ok(old), Ok(new)) => old == new,
+        (Err(e), _) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    if same_dir {
+        return Ok(0);
+    }
+    for entry in std::fs::read_dir(OLD_UPLOAD_DIR)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_file() || !name.to_str().is_some_and(looks_uploaded) {
+            continue;
+        }
+        let target = Path::new(upload_dir).join(&name);
+        // Renaming fails across file systems
+        if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(store.move_files(OLD_UPLOAD_DIR, upload_dir)?)
+}
+
+// Uploaded files and thumbnails, by stored name. Only files of posts that
+// still exist are served; stored names never get reused, so they can be
+// cached for good.
+pub async fn serve(req: HttpRequest, store: web::Data<dyn Store>, name: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let file = store.find_file(&name)?.ok_or(AppError::NotFound)?;
+    let (path, mime_type) = if file_name(&file.file_path) == name.as_str() {
+        (file.file_path.clone(), mime_type_of(&file).into_owned())
+    } else {
+        let thumbnail = file.thumbnail_path.clone().unwrap_or_default();
+        let mime_type = MimeGuess::from_path(&thumbnail).first_or_octet_stream().to_string();
+        (thumbnail, mime_type)
+    };
+    let named_file = match NamedFile::open_async(&path).await {
+        Ok(named_file) => named_file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(AppError::NotFound),
+        Err(e) => return Err(e.into()),
+    };
+    let content_type = mime_type.parse().unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
+    let mut response = named_file
+        .set_content_type(content_type)
+        .set_content_disposition(disposition(file.display_name()))
+        .into_response(&req);
+    let headers = response.headers_mut();
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=31536000, immutable"));
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id3_truncated_headers() {
+        // Version 3, no flags, a 16 byte tag
+        let header = b"ID3\x03\x00\x00\x00\x00\x00\x10";
+        for length in [3, 4, 9] {
+            assert_eq!(id3_cover_art(&header[..length]), None, "{} bytes", length);
+        }
+        // A tag longer than the file
+        assert_eq!(id3_cover_art(header), None);
+        // What an upload of the same bytes goes through
+        assert_eq!(make_thumbnail(b"ID3", MIME_AUDIO_MPEG, "unused.mp3"), None);
+    }
+}
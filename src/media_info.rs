@@ -0,0 +1,376 @@
+// Dimensions and duration of uploaded media, read from the file headers.
+// Images go through `imagesize`; MP4 and WebM are walked just far enough to
+// find the movie header and the first video track.
+use std::convert::TryInto;
+
+#[derive(Default)]
+pub struct MediaInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // Seconds
+    pub duration: Option<f64>,
+}
+
+// Whatever could be read; formats without a parser, and files that don't
+// parse, give an empty `MediaInfo`
+pub fn probe(data: &[u8], mime_type: &str) -> MediaInfo {
+    match mime_type {
+        "video/mp4" => mp4_info(data),
+        "video/webm" => webm_info(data),
+        _ if mime_type.starts_with("image/") => imagesize::blob_size(data).ok().map(|size| MediaInfo {
+            width: Some(size.width as u32),
+            height: Some(size.height as u32),
+            duration: None,
+        }),
+        _ => None,
+    }
+    .unwrap_or_default()
+}
+
+fn u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_be(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+// ISO base media boxes directly inside `data`, as (type, body)
+fn mp4_boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let size = u32_be(data, pos)? as usize;
+        let kind = data.get(pos + 4..pos + 8)?;
+        let (header, size) = match size {
+            // 64-bit size follows the type
+            1 => (16, u64_be(data, pos + 8)? as usize),
+            // Extends to the end of the enclosing box
+            0 => (8, data.len() - pos),
+            size => (8, size),
+        };
+        if size < header {
+            return None;
+        }
+        let body = data.get(pos + header..pos.checked_add(size)?)?;
+        pos += size;
+        Some((kind, body))
+    })
+}
+
+pub fn mp4_child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    mp4_boxes(data).find(|(k, _)| *k == kind).map(|(_, body)| body)
+}
+
+fn mp4_info(data: &[u8]) -> Option<MediaInfo> {
+    let moov = mp4_child(data, b"moov")?;
+    let mut info = MediaInfo::default();
+
+    if let Some(mvhd) = mp4_child(moov, b"mvhd") {
+        // Version 1 uses 64-bit times
+        let (timescale, duration) = if mvhd.first() == Some(&1) {
+            (u32_be(mvhd, 20)?, u64_be(mvhd, 24)?)
+        } else {
+            (u32_be(mvhd, 12)?, u32_be(mvhd, 16)? as u64)
+        };
+        if timescale > 0 {
+            info.duration = Some(duration as f64 / timescale as f64);
+        }
+    }
+
+    let video_track = mp4_boxes(moov).filter(|(kind, _)| *kind == b"trak").find(|(_, trak)| {
+        mp4_child(trak, b"mdia")
+            .and_then(|mdia| mp4_child(mdia, b"hdlr"))
+            .and_then(|hdlr| hdlr.get(8..12))
+            == Some(b"vide")
+    });
+    if let Some(tkhd) = video_track.and_then(|(_, trak)| mp4_child(trak, b"tkhd")) {
+        // 16.16 fixed point, after the matrix
+        let offset = if tkhd.first() == Some(&1) { 88 } else { 76 };
+        info.width = Some(u32_be(tkhd, offset)? >> 16);
+        info.height = Some(u32_be(tkhd, offset + 4)? >> 16);
+    }
+    Some(info)
+}
+
+const EBML_SEGMENT: u64 = 0x1853_8067;
+const EBML_INFO: u64 = 0x1549_a966;
+const EBML_TIMECODE_SCALE: u64 = 0x2a_d7b1;
+const EBML_DURATION: u64 = 0x4489;
+const EBML_TRACKS: u64 = 0x1654_ae6b;
+const EBML_TRACK_ENTRY: u64 = 0xae;
+const EBML_TRACK_TYPE: u64 = 0x83;
+const EBML_VIDEO: u64 = 0xe0;
+const EBML_PIXEL_WIDTH: u64 = 0xb0;
+const EBML_PIXEL_HEIGHT: u64 = 0xba;
+const EBML_CLUSTER: u64 = 0x1f43_b675;
+const TRACK_TYPE_VIDEO: u64 = 1;
+
+// A variable-length EBML number and its length in bytes. IDs keep their
+// length marker, sizes don't.
+fn ebml_vint(data: &[u8], pos: usize, keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.get(pos)?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 {
+        return None;
+    }
+    let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xff >> length) };
+    for i in 1..length {
+        value = (value << 8) | *data.get(pos + i)? as u64;
+    }
+    Some((value, length))
+}
+
+// EBML elements directly inside `data`, as (ID, body). Stops at the first
+// cluster, since everything wanted here comes before the media data.
+fn ebml_elements(data: &[u8]) -> impl Iterator<Item = (u64, &[u8])> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let (id, id_length) = ebml_vint(data, pos, true)?;
+        let (size, size_length) = ebml_vint(data, pos + id_length, false)?;
+        if id == EBML_CLUSTER {
+            return None;
+        }
+        let start = pos + id_length + size_length;
+        // All ones means unknown size: the element runs to the end
+        let unknown = size == (1u64 << (7 * size_length)) - 1;
+        let end = if unknown { data.len() } else { start.checked_add(size as usize)?.min(data.len()) };
+        pos = end;
+        Some((id, data.get(start..end)?))
+    })
+}
+
+fn ebml_child(data: &[u8], id: u64) -> Option<&[u8]> {
+    ebml_elements(data).find(|(i, _)| *i == id).map(|(_, body)| body)
+}
+
+fn ebml_uint(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+    Some(data.iter().fold(0, |value, &byte| (value << 8) | byte as u64))
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+fn webm_info(data: &[u8]) -> Option<MediaInfo> {
+    let segment = ebml_child(data, EBML_SEGMENT)?;
+    let mut info = MediaInfo::default();
+
+    if let Some(segment_info) = ebml_child(segment, EBML_INFO) {
+        let scale = ebml_child(segment_info, EBML_TIMECODE_SCALE)
+            .and_then(ebml_uint)
+            .unwrap_or(1_000_000);
+        // Live recordings may not know their duration
+        info.duration = ebml_child(segment_info, EBML_DURATION)
+            .and_then(ebml_float)
+            .map(|duration| duration * scale as f64 / 1e9);
+    }
+
+    let video = ebml_child(segment, EBML_TRACKS).and_then(|tracks| {
+        ebml_elements(tracks)
+            .filter(|(id, _)| *id == EBML_TRACK_ENTRY)
+            .find(|(_, entry)| ebml_child(entry, EBML_TRACK_TYPE).and_then(ebml_uint) == Some(TRACK_TYPE_VIDEO))
+            .and_then(|(_, entry)| ebml_child(entry, EBML_VIDEO))
+    });
+    if let Some(video) = video {
+        info.width = ebml_child(video, EBML_PIXEL_WIDTH).and_then(ebml_uint).map(|w| w as u32);
+        info.height = ebml_child(video, EBML_PIXEL_HEIGHT).and_then(ebml_uint).map(|h| h as u32);
+    }
+    Some(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&((8 + body.len()) as u32).to_be_bytes()[..], kind, body].concat()
+    }
+
+    fn mp4_box_64(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&1u32.to_be_bytes()[..], kind, &((16 + body.len()) as u64).to_be_bytes(), body].concat()
+    }
+
+    // Runs to the end of what holds it
+    fn mp4_box_open(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&0u32.to_be_bytes()[..], kind, body].concat()
+    }
+
+    fn mvhd(version: u8, timescale: u32, duration: u64) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        if version == 1 {
+            body.extend_from_slice(&[0; 16]);
+            body.extend_from_slice(&timescale.to_be_bytes());
+            body.extend_from_slice(&duration.to_be_bytes());
+        } else {
+            body.extend_from_slice(&[0; 8]);
+            body.extend_from_slice(&timescale.to_be_bytes());
+            body.extend_from_slice(&(duration as u32).to_be_bytes());
+        }
+        // Rate, volume, matrix and the rest
+        body.extend_from_slice(&[0; 80]);
+        mp4_box(b"mvhd", &body)
+    }
+
+    fn trak(version: u8, handler: &[u8; 4], width: u32, height: u32) -> Vec<u8> {
+        let mut tkhd = vec![version, 0, 0, 0];
+        // Times, track id and duration, then layer, volume and the matrix
+        tkhd.extend_from_slice(&vec![0; if version == 1 { 32 } else { 20 }]);
+        tkhd.extend_from_slice(&[0; 52]);
+        tkhd.extend_from_slice(&(width << 16).to_be_bytes());
+        tkhd.extend_from_slice(&(height << 16).to_be_bytes());
+        let hdlr = [&[0; 8][..], handler, &[0; 12]].concat();
+        let mdia = mp4_box(b"mdia", &mp4_box(b"hdlr", &hdlr));
+        mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat())
+    }
+
+    fn mp4(version: u8, timescale: u32, duration: u64) -> Vec<u8> {
+        let moov = [
+            mvhd(version, timescale, duration),
+            trak(version, b"soun", 0, 0),
+            trak(version, b"vide", 640, 360),
+        ]
+        .concat();
+        [mp4_box(b"ftyp", b"isom\0\0\0\0"), mp4_box(b"moov", &moov)].concat()
+    }
+
+    #[test]
+    fn mp4_boxes_follow_every_size() {
+        let data = [mp4_box(b"aaaa", b"one"), mp4_box_64(b"bbbb", b"two"), mp4_box_open(b"cccc", b"three")].concat();
+        let boxes: Vec<_> = mp4_boxes(&data).collect();
+        assert_eq!(boxes, [(&b"aaaa"[..], &b"one"[..]), (b"bbbb", b"two"), (b"cccc", b"three")]);
+    }
+
+    #[test]
+    fn mp4_boxes_stop_at_bad_sizes() {
+        // Smaller than its own header
+        let data = [mp4_box(b"aaaa", b""), 4u32.to_be_bytes().to_vec(), b"bbbb".to_vec()].concat();
+        assert_eq!(mp4_boxes(&data).count(), 1);
+        // Longer than what is left
+        let mut data = mp4_box(b"aaaa", b"body");
+        data.truncate(10);
+        assert_eq!(mp4_boxes(&data).count(), 0);
+        let data = [&1u32.to_be_bytes()[..], b"aaaa", &u64::MAX.to_be_bytes()].concat();
+        assert_eq!(mp4_boxes(&data).count(), 0);
+        assert_eq!(mp4_boxes(b"\0\0").count(), 0);
+    }
+
+    #[test]
+    fn mp4_version_0() {
+        let info = probe(&mp4(0, 1000, 10_500), "video/mp4");
+        assert_eq!((info.width, info.height), (Some(640), Some(360)));
+        assert_eq!(info.duration, Some(10.5));
+    }
+
+    #[test]
+    fn mp4_version_1() {
+        let info = probe(&mp4(1, 1 << 20, 1 << 33), "video/mp4");
+        assert_eq!((info.width, info.height), (Some(640), Some(360)));
+        assert_eq!(info.duration, Some(8192.0));
+    }
+
+    #[test]
+    fn mp4_moov_in_64_bit_and_open_boxes() {
+        let moov = [mvhd(0, 10, 25), trak(0, b"vide", 320, 240)].concat();
+        for data in [mp4_box_64(b"moov", &moov), mp4_box_open(b"moov", &moov)] {
+            let info = probe(&[mp4_box(b"ftyp", b"isom"), data].concat(), "video/mp4");
+            assert_eq!((info.width, info.height, info.duration), (Some(320), Some(240), Some(2.5)));
+        }
+    }
+
+    #[test]
+    fn mp4_without_much() {
+        let info = probe(&mp4_box(b"moov", &mvhd(0, 0, 100)), "video/mp4");
+        assert_eq!((info.width, info.height, info.duration), (None, None, None));
+        let data = mp4(0, 1000, 10_500);
+        for end in 0..data.len() {
+            probe(&data[..end], "video/mp4");
+        }
+    }
+
+    #[test]
+    fn ebml_vint_lengths() {
+        assert_eq!(ebml_vint(&[0x81], 0, false), Some((1, 1)));
+        assert_eq!(ebml_vint(&[0x81], 0, true), Some((0x81, 1)));
+        assert_eq!(ebml_vint(&[0x40, 0x02], 0, false), Some((2, 2)));
+        assert_eq!(ebml_vint(&[0, 0x1a, 0x45, 0xdf, 0xa3], 1, true), Some((0x1a45_dfa3, 4)));
+        assert_eq!(ebml_vint(&[0x01, 0, 0, 0, 0, 0, 0x01, 0x05], 0, false), Some((0x105, 8)));
+        // No length marker in the first byte
+        assert_eq!(ebml_vint(&[0x00, 0x81], 0, false), None);
+        assert_eq!(ebml_vint(&[0x40], 0, false), None);
+        assert_eq!(ebml_vint(&[], 0, false), None);
+    }
+
+    // The ID as written, with its length marker, and an eight byte size
+    fn element(id: u64, body: &[u8]) -> Vec<u8> {
+        let id = id.to_be_bytes();
+        let start = id.iter().position(|&byte| byte != 0).unwrap_or(7);
+        [&id[start..], &[1], &(body.len() as u64).to_be_bytes()[1..], body].concat()
+    }
+
+    // An eight byte size of all ones
+    fn element_unknown(id: u64, body: &[u8]) -> Vec<u8> {
+        let mut data = element(id, body);
+        let size_at = data.len() - body.len() - 7;
+        data[size_at..size_at + 7].fill(0xff);
+        data
+    }
+
+    #[test]
+    fn ebml_elements_sizes() {
+        let data = [element(0xa1, b"one"), vec![0xa2, 0x83], b"two".to_vec(), element_unknown(0xa3, b"rest")].concat();
+        let elements: Vec<_> = ebml_elements(&data).collect();
+        assert_eq!(elements, [(0xa1, &b"one"[..]), (0xa2, b"two"), (0xa3, b"rest")]);
+
+        // Sizes past the end are cut short, and a cluster ends the walk
+        let data = [element(0xa1, b"one"), element(EBML_CLUSTER, b"media"), element(0xa2, b"two")].concat();
+        assert_eq!(ebml_elements(&data).count(), 1);
+        let data = [&[0xa1, 0x88][..], b"short"].concat();
+        assert_eq!(ebml_elements(&data).collect::<Vec<_>>(), [(0xa1, &b"short"[..])]);
+    }
+
+    fn webm(duration: &[u8]) -> Vec<u8> {
+        let info = [element(EBML_TIMECODE_SCALE, &[0x0f, 0x42, 0x40]), element(EBML_DURATION, duration)].concat();
+        let audio = element(EBML_TRACK_ENTRY, &element(EBML_TRACK_TYPE, &[2]));
+        let video = [
+            element(EBML_TRACK_TYPE, &[1]),
+            element(EBML_VIDEO, &[element(EBML_PIXEL_WIDTH, &[0x01, 0x40]), element(EBML_PIXEL_HEIGHT, &[0xf0])].concat()),
+        ]
+        .concat();
+        let tracks = [audio, element(EBML_TRACK_ENTRY, &video)].concat();
+        let segment = [
+            element(EBML_INFO, &info),
+            element(EBML_TRACKS, &tracks),
+            element(EBML_CLUSTER, b"media"),
+        ]
+        .concat();
+        // As streamed, with the segment size unknown
+        [element(0x1a45_dfa3, &element(0x4282, b"webm")), element_unknown(EBML_SEGMENT, &segment)].concat()
+    }
+
+    #[test]
+    fn webm_dimensions_and_duration() {
+        let info = probe(&webm(&4500f64.to_be_bytes()), "video/webm");
+        assert_eq!((info.width, info.height), (Some(320), Some(240)));
+        assert_eq!(info.duration, Some(4.5));
+        let info = probe(&webm(&1250f32.to_be_bytes()), "video/webm");
+        assert_eq!(info.duration, Some(1.25));
+        let info = probe(&webm(b""), "video/webm");
+        assert_eq!((info.width, info.duration), (Some(320), None));
+    }
+
+    #[test]
+    fn webm_cut_short() {
+        let data = webm(&4500f64.to_be_bytes());
+        for end in 0..data.len() {
+            probe(&data[..end], "video/webm");
+        }
+        assert!(probe(b"\x1a\x45\xdf\xa3", "video/webm").width.is_none());
+    }
+}
@@ -0,0 +1,538 @@
+// Removes EXIF, XMP, comments and other metadata from uploaded JPEG, PNG and
+// WebP images before they are written to disk. By default only the container
+// is rewritten and the image data is copied as is; boards can instead have
+// images decoded and encoded again, which drops anything that could be hidden
+// in the image data itself.
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use std::convert::TryInto;
+use std::io::Cursor;
+
+use crate::media::{MIME_IMAGE_JPEG, MIME_IMAGE_PNG, MIME_IMAGE_WEBP};
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+// Whether uploads of this type go through `clean`
+pub fn handles(mime_type: &str) -> bool {
+    matches!(mime_type, MIME_IMAGE_JPEG | MIME_IMAGE_PNG | MIME_IMAGE_WEBP)
+}
+
+// The image without its metadata, or None if it could not be read. With
+// `keep_orientation` an EXIF orientation survives: as a minimal EXIF block
+// when stripping, or applied to the pixels when re-encoding.
+pub fn clean(data: &[u8], mime_type: &str, reencode: bool, keep_orientation: bool) -> Option<Vec<u8>> {
+    let format = match mime_type {
+        MIME_IMAGE_JPEG => ImageFormat::Jpeg,
+        MIME_IMAGE_PNG => ImageFormat::Png,
+        MIME_IMAGE_WEBP => ImageFormat::WebP,
+        _ => return None,
+    };
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(data, keep_orientation)?,
+        ImageFormat::Png => strip_png(data, keep_orientation)?,
+        _ => strip_webp(data, keep_orientation)?,
+    };
+    // Decoding only yields the first frame, so animations are stripped instead
+    if reencode && !is_animated(&stripped, format) {
+        reencode_image(&stripped, format, keep_orientation)
+    } else {
+        Some(stripped)
+    }
+}
+
+fn reencode_image(data: &[u8], format: ImageFormat, keep_orientation: bool) -> Option<Vec<u8>> {
+    let mut decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder().ok()?;
+    let orientation = decoder.orientation().ok()?;
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    if keep_orientation {
+        image.apply_orientation(orientation);
+    }
+    // JPEG has no alpha channel
+    if format == ImageFormat::Jpeg {
+        image = DynamicImage::ImageRgb8(image.to_rgb8());
+    } else if format == ImageFormat::WebP {
+        image = DynamicImage::ImageRgba8(image.to_rgba8());
+    }
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, format).ok()?;
+    Some(out.into_inner())
+}
+
+fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png => png_chunks(data).is_some_and(|chunks| chunks.iter().any(|c| c.kind == *b"acTL")),
+        ImageFormat::WebP => webp_chunks(data).is_some_and(|chunks| {
+            chunks
+                .iter()
+                .any(|c| c.kind == *b"VP8X" && c.data.first().is_some_and(|flags| flags & WEBP_ANIMATION != 0))
+        }),
+        _ => false,
+    }
+}
+
+// The orientation from a TIFF structure, the body of every EXIF block
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| -> Option<u16> {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |offset: usize| -> Option<u32> {
+        let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+        // 1 is the default and needs no EXIF at all
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+// A TIFF structure holding nothing but the orientation
+fn orientation_tiff(orientation: u16) -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"MM\0\x2a");
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    // SHORT, one value, padded to four bytes
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // No further IFDs
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff
+}
+
+// Keeps the segments needed to display the image: JFIF, ICC profiles and
+// Adobe color information among the application segments, and everything
+// that is not an application segment or a comment. Data after the end of
+// the image is dropped.
+fn strip_jpeg(data: &[u8], keep_orientation: bool) -> Option<Vec<u8>> {
+    if data.get(0..2)? != [0xff, 0xd8] {
+        return None;
+    }
+    let mut out = vec![0xff, 0xd8];
+    // Where a minimal EXIF segment goes: after JFIF, which must come first
+    let mut exif_at = out.len();
+    let mut orientation = None;
+    let mut pos = 2;
+
+    loop {
+        if *data.get(pos)? != 0xff {
+            return None;
+        }
+        while *data.get(pos)? == 0xff {
+            pos += 1;
+        }
+        let marker = data[pos];
+        let start = pos - 1;
+        pos += 1;
+
+        match marker {
+            // Start of scan: the rest is image data, up to the end of image
+            0xda => {
+                let end = (pos..data.len().saturating_sub(1))
+                    .find(|&i| data[i] == 0xff && data[i + 1] == 0xd9)
+                    .map(|i| i + 2)
+                    .unwrap_or(data.len());
+                out.push(0xff);
+                out.extend_from_slice(&data[start + 1..end]);
+                break;
+            }
+            0xd9 => {
+                out.extend_from_slice(&[0xff, 0xd9]);
+                break;
+            }
+            // Markers without a length
+            0x01 | 0xd0..=0xd7 => out.extend_from_slice(&[0xff, marker]),
+            _ => {
+                let length = u16::from_be_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+                let end = pos + length;
+                let payload = data.get(pos + 2..end)?;
+                let keep = match marker {
+                    0xe0 => payload.starts_with(b"JFIF\0"),
+                    0xe1 => {
+                        if let Some(tiff) = payload.strip_prefix(EXIF_HEADER) {
+                            orientation = orientation.or_else(|| tiff_orientation(tiff));
+                        }
+                        false
+                    }
+                    0xe2 => payload.starts_with(b"ICC_PROFILE\0"),
+                    0xee => payload.starts_with(b"Adobe"),
+                    0xe3..=0xef | 0xfe => false,
+                    _ => true,
+                };
+                if keep {
+                    out.push(0xff);
+                    out.extend_from_slice(&data[start + 1..end]);
+                    if marker == 0xe0 && exif_at == 2 {
+                        exif_at = out.len();
+                    }
+                }
+                pos = end;
+            }
+        }
+    }
+
+    if let Some(orientation) = orientation.filter(|_| keep_orientation) {
+        let tiff = orientation_tiff(orientation);
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(EXIF_HEADER);
+        segment.extend_from_slice(&tiff);
+        out.splice(exif_at..exif_at, segment);
+    }
+    Some(out)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// Chunks that affect how the image looks, including animation
+const PNG_KEPT_CHUNKS: [&[u8; 4]; 19] = [
+    b"IHDR", b"PLTE", b"IDAT", b"IEND", b"tRNS", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP",
+    b"mDCV", b"cLLI", b"bKGD", b"hIST", b"pHYs", b"acTL", b"fcTL", b"fdAT",
+];
+
+struct Chunk<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+    // The whole chunk as found in the file
+    raw: &'a [u8],
+}
+
+// Chunks up to and including IEND
+fn png_chunks(data: &[u8]) -> Option<Vec<Chunk<'_>>> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let kind: [u8; 4] = data.get(pos + 4..pos + 8)?.try_into().ok()?;
+        let end = pos.checked_add(12)?.checked_add(length)?;
+        chunks.push(Chunk {
+            kind,
+            data: data.get(pos + 8..pos + 8 + length)?,
+            raw: data.get(pos..end)?,
+        });
+        pos = end;
+        if &kind == b"IEND" {
+            return Some(chunks);
+        }
+    }
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(12 + data.len());
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn strip_png(data: &[u8], keep_orientation: bool) -> Option<Vec<u8>> {
+    let chunks = png_chunks(data)?;
+    let orientation = chunks
+        .iter()
+        .filter(|c| &c.kind == b"eXIf")
+        .find_map(|c| tiff_orientation(c.data));
+
+    let mut out = PNG_SIGNATURE.to_vec();
+    for chunk in &chunks {
+        if PNG_KEPT_CHUNKS.contains(&&chunk.kind) {
+            out.extend_from_slice(chunk.raw);
+        }
+        // eXIf has to come before the image data
+        if &chunk.kind == b"IHDR" {
+            if let Some(orientation) = orientation.filter(|_| keep_orientation) {
+                out.extend_from_slice(&png_chunk(b"eXIf", &orientation_tiff(orientation)));
+            }
+        }
+    }
+    Some(out)
+}
+
+// VP8X feature flags
+const WEBP_EXIF: u8 = 0x08;
+const WEBP_XMP: u8 = 0x04;
+const WEBP_ANIMATION: u8 = 0x02;
+
+// Chunks that affect how the image looks, including animation
+const WEBP_KEPT_CHUNKS: [&[u8; 4]; 7] = [b"VP8X", b"VP8 ", b"VP8L", b"ALPH", b"ANIM", b"ANMF", b"ICCP"];
+
+fn webp_chunks(data: &[u8]) -> Option<Vec<Chunk<'_>>> {
+    if data.get(0..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let riff_end = (u32::from_le_bytes(data.get(4..8)?.try_into().ok()?) as usize)
+        .checked_add(8)?
+        .min(data.len());
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= riff_end {
+        let kind: [u8; 4] = data.get(pos..pos + 4)?.try_into().ok()?;
+        let length = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // Chunks are padded to an even length
+        let end = pos.checked_add(8)?.checked_add(length + (length & 1))?.min(riff_end);
+        chunks.push(Chunk {
+            kind,
+            data: data.get(pos + 8..pos + 8 + length)?,
+            raw: data.get(pos..end)?,
+        });
+        pos = end;
+    }
+    Some(chunks)
+}
+
+fn strip_webp(data: &[u8], keep_orientation: bool) -> Option<Vec<u8>> {
+    let chunks = webp_chunks(data)?;
+    let orientation = chunks
+        .iter()
+        .filter(|c| &c.kind == b"EXIF")
+        .find_map(|c| tiff_orientation(c.data.strip_prefix(EXIF_HEADER).unwrap_or(c.data)));
+    // Only the extended format can carry EXIF at all
+    let orientation = orientation.filter(|_| keep_orientation && chunks.iter().any(|c| &c.kind == b"VP8X"));
+
+    let mut body = b"WEBP".to_vec();
+    for chunk in &chunks {
+        if !WEBP_KEPT_CHUNKS.contains(&&chunk.kind) {
+            continue;
+        }
+        let start = body.len();
+        body.extend_from_slice(chunk.raw);
+        if &chunk.kind == b"VP8X" {
+            let flags = body.get_mut(start + 8)?;
+            *flags &= !(WEBP_EXIF | WEBP_XMP);
+            if orientation.is_some() {
+                *flags |= WEBP_EXIF;
+            }
+        }
+    }
+    // EXIF goes after the image data
+    if let Some(orientation) = orientation {
+        let tiff = orientation_tiff(orientation);
+        body.extend_from_slice(b"EXIF");
+        body.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+        body.extend_from_slice(&tiff);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::metadata::Orientation;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    // What the metadata says that nothing after stripping may
+    const SECRET: &[u8] = b"serial 0042";
+    const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+    fn encode(format: ImageFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(4, 2, |x, y| Rgb([x as u8 * 60, y as u8 * 120, 200]));
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    // EXIF saying the image is turned a quarter, followed by more of it
+    fn exif_tiff() -> Vec<u8> {
+        [orientation_tiff(6).as_slice(), SECRET].concat()
+    }
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|window| window == part)
+    }
+
+    fn pixels(data: &[u8], format: ImageFormat) -> Vec<u8> {
+        image::load_from_memory_with_format(data, format).unwrap().to_rgb8().into_raw()
+    }
+
+    fn orientation(data: &[u8], format: ImageFormat) -> Orientation {
+        let mut decoder = ImageReader::with_format(Cursor::new(data), format).into_decoder().unwrap();
+        decoder.orientation().unwrap()
+    }
+
+    fn dimensions(data: &[u8], format: ImageFormat) -> (u32, u32) {
+        image::load_from_memory_with_format(data, format).unwrap().dimensions()
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let plain = encode(ImageFormat::Jpeg);
+        let mut jpeg = plain[..2].to_vec();
+        jpeg.extend(jpeg_segment(0xe1, &[EXIF_HEADER, &exif_tiff()].concat()));
+        jpeg.extend(jpeg_segment(0xe1, &[XMP_HEADER, SECRET].concat()));
+        jpeg.extend(jpeg_segment(0xfe, SECRET));
+        jpeg.extend_from_slice(&plain[2..]);
+        jpeg
+    }
+
+    fn png() -> Vec<u8> {
+        let plain = encode(ImageFormat::Png);
+        let mut png = PNG_SIGNATURE.to_vec();
+        for chunk in png_chunks(&plain).unwrap() {
+            png.extend_from_slice(chunk.raw);
+            if &chunk.kind == b"IHDR" {
+                png.extend(png_chunk(b"tEXt", &[b"Comment\0", SECRET].concat()));
+                png.extend(png_chunk(b"eXIf", &exif_tiff()));
+            }
+        }
+        png
+    }
+
+    fn webp_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = kind.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    // The extended format, which is the one that can carry metadata
+    fn webp() -> Vec<u8> {
+        let plain = encode(ImageFormat::WebP);
+        // Flags, three reserved bytes, then the canvas size less one
+        let vp8x = [WEBP_EXIF | WEBP_XMP, 0, 0, 0, 3, 0, 0, 1, 0, 0];
+        let mut body = b"WEBP".to_vec();
+        body.extend(webp_chunk(b"VP8X", &vp8x));
+        for chunk in webp_chunks(&plain).unwrap() {
+            body.extend_from_slice(chunk.raw);
+        }
+        body.extend(webp_chunk(b"EXIF", &exif_tiff()));
+        body.extend(webp_chunk(b"XMP ", SECRET));
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        webp.extend(body);
+        webp
+    }
+
+    fn samples() -> [(&'static str, ImageFormat, Vec<u8>); 3] {
+        [
+            (MIME_IMAGE_JPEG, ImageFormat::Jpeg, jpeg()),
+            (MIME_IMAGE_PNG, ImageFormat::Png, png()),
+            (MIME_IMAGE_WEBP, ImageFormat::WebP, webp()),
+        ]
+    }
+
+    #[test]
+    fn jpeg_loses_exif_xmp_and_comments() {
+        let jpeg = jpeg();
+        let clean = clean(&jpeg, MIME_IMAGE_JPEG, false, false).unwrap();
+        assert!(!contains(&clean, SECRET));
+        assert!(!contains(&clean, EXIF_HEADER));
+        assert!(!contains(&clean, XMP_HEADER));
+        assert!(contains(&clean, b"JFIF\0"));
+    }
+
+    #[test]
+    fn png_loses_text_and_exif() {
+        let clean = clean(&png(), MIME_IMAGE_PNG, false, false).unwrap();
+        let kinds: Vec<[u8; 4]> = png_chunks(&clean).unwrap().iter().map(|chunk| chunk.kind).collect();
+        assert!(!kinds.contains(b"tEXt"));
+        assert!(!kinds.contains(b"eXIf"));
+        assert!(!contains(&clean, SECRET));
+    }
+
+    #[test]
+    fn webp_loses_exif_and_xmp() {
+        let clean = clean(&webp(), MIME_IMAGE_WEBP, false, false).unwrap();
+        let chunks = webp_chunks(&clean).unwrap();
+        assert!(!chunks.iter().any(|chunk| &chunk.kind == b"EXIF" || &chunk.kind == b"XMP "));
+        assert_eq!(chunks[0].data[0] & (WEBP_EXIF | WEBP_XMP), 0);
+        assert!(!contains(&clean, SECRET));
+        assert_eq!(u32::from_le_bytes(clean[4..8].try_into().unwrap()) as usize, clean.len() - 8);
+    }
+
+    #[test]
+    fn stripping_leaves_the_pixels() {
+        for (mime_type, format, data) in samples() {
+            let clean = clean(&data, mime_type, false, false).unwrap();
+            assert_eq!(pixels(&clean, format), pixels(&data, format), "{}", mime_type);
+            assert_eq!(orientation(&clean, format), Orientation::NoTransforms, "{}", mime_type);
+        }
+    }
+
+    #[test]
+    fn stripping_can_keep_the_orientation() {
+        for (mime_type, format, data) in samples() {
+            let clean = clean(&data, mime_type, false, true).unwrap();
+            assert!(!contains(&clean, SECRET), "{}", mime_type);
+            assert_eq!(orientation(&clean, format), Orientation::Rotate90, "{}", mime_type);
+            assert_eq!(pixels(&clean, format), pixels(&data, format), "{}", mime_type);
+        }
+    }
+
+    #[test]
+    fn reencoding_applies_the_orientation() {
+        for (mime_type, format, data) in samples() {
+            let turned = clean(&data, mime_type, true, true).unwrap();
+            assert_eq!(dimensions(&turned, format), (2, 4), "{}", mime_type);
+            assert_eq!(orientation(&turned, format), Orientation::NoTransforms, "{}", mime_type);
+            let kept = clean(&data, mime_type, true, false).unwrap();
+            assert_eq!(dimensions(&kept, format), (4, 2), "{}", mime_type);
+            assert!(!contains(&kept, SECRET), "{}", mime_type);
+        }
+    }
+
+    #[test]
+    fn orientation_reads_either_byte_order() {
+        let big = orientation_tiff(8);
+        assert_eq!(tiff_orientation(&big), Some(8));
+        let little: Vec<u8> = [&b"II\x2a\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x03\0\0\0"[..], &[0; 4]].concat();
+        assert_eq!(tiff_orientation(&little), Some(3));
+        // The default needs no EXIF
+        assert_eq!(tiff_orientation(&orientation_tiff(1)), None);
+        assert_eq!(tiff_orientation(b"MM\0\x2a\xff\xff\xff\xff"), None);
+    }
+
+    #[test]
+    fn broken_images_are_refused() {
+        for (mime_type, _, data) in samples() {
+            for end in 0..data.len() {
+                for reencode in [false, true] {
+                    // Whatever comes out, nothing panics
+                    let _ = clean(&data[..end], mime_type, reencode, true);
+                }
+            }
+            let mut garbled = data.clone();
+            let half = garbled.len() / 2;
+            garbled[half..].iter_mut().for_each(|byte| *byte = 0xa5);
+            let _ = clean(&garbled, mime_type, true, true);
+            assert!(clean(b"not an image", mime_type, false, false).is_none(), "{}", mime_type);
+        }
+        // Cut off before the image data
+        assert!(clean(&jpeg()[..40], MIME_IMAGE_JPEG, false, false).is_none());
+        let png = png();
+        assert!(clean(&png[..png.len() - 4], MIME_IMAGE_PNG, false, false).is_none());
+        assert!(clean(&encode(ImageFormat::Png), MIME_IMAGE_JPEG, false, false).is_none());
+        assert!(clean(&png, "image/gif", false, false).is_none());
+    }
+}
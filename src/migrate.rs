@@ -0,0 +1,341 @@
+// One-shot move from the old single-board sled edition into SQLite. That
+// edition kept every post in the default tree of its `my_database` sled
+// database, as JSON keyed by a random six character id, with files under
+// its own `./static`.
+//
+// Posts get integer ids in the order they were written, so threads come
+// before their replies. The old id stays on as the post's `post_id`, which
+// keeps the colour of its id box.
+use mime_guess::MimeGuess;
+use rand::{distributions::Alphanumeric, Rng};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::db::{self, Attachment, BoardSettings, Post, Poster};
+use crate::{media, media_info};
+
+// A post as the sled edition stored it
+#[derive(Deserialize)]
+struct LegacyPost {
+    id: String,
+    // "0" for thread starters
+    parent_id: String,
+    // HTML-escaped, as stored
+    title: String,
+    message: String,
+    // `./static/{6 random chars}-{sanitized name}`
+    file_path: Option<String>,
+    // Unix milliseconds; when the post was written, or a thread's last bump
+    last_reply_at: u64,
+}
+
+pub enum MigrateError {
+    Sled(sled::Error),
+    Database(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrateError::Sled(e) => write!(f, "Unable to read the sled database: {}", e),
+            MigrateError::Database(e) => write!(f, "Database error: {}", e),
+            MigrateError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sled::Error> for MigrateError {
+    fn from(e: sled::Error) -> Self {
+        MigrateError::Sled(e)
+    }
+}
+
+impl From<rusqlite::Error> for MigrateError {
+    fn from(e: rusqlite::Error) -> Self {
+        MigrateError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for MigrateError {
+    fn from(e: std::io::Error) -> Self {
+        MigrateError::Io(e)
+    }
+}
+
+#[derive(Serialize)]
+pub struct MigrateReport {
+    pub board_id: i32,
+    pub threads: usize,
+    pub replies: usize,
+    // Files copied into the upload directory
+    pub files: usize,
+    // Files that were already stored and are shared now
+    pub reused: usize,
+    // Stored paths whose file isn't there, or that don't name a file
+    // directly inside the old `./static`; their posts come over without it
+    pub missing_files: Vec<String>,
+    // Entries that aren't posts, and replies whose thread is gone
+    pub skipped: Vec<String>,
+}
+
+// Files copied during a migration, removed again unless it succeeds
+#[derive(Default)]
+struct Written {
+    files: Vec<String>,
+    keep: bool,
+}
+
+impl Drop for Written {
+    fn drop(&mut self) {
+        if !self.keep {
+            for file in &self.files {
+                let _ = std::fs::remove_file(file);
+            }
+        }
+    }
+}
+
+// The stored name of a legacy file, when the path is one the sled edition
+// could have written
+fn legacy_name(file_path: &str) -> Option<&str> {
+    let name = file_path.strip_prefix("./static/")?;
+    let plain = !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']);
+    Some(name).filter(|_| plain)
+}
+
+// Copies a legacy file into the upload directory, unless the same file is
+// stored already. None when it can't be found.
+fn store_file(
+    conn: &Connection,
+    file_path: &str,
+    files_dir: &Path,
+    upload_dir: &str,
+    written: &mut Written,
+    copied: &mut HashMap<String, (String, Option<String>)>,
+    report: &mut MigrateReport,
+) -> Result<Option<Attachment>, MigrateError> {
+    let name = match legacy_name(file_path) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let data = match std::fs::read(files_dir.join(name)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mime_type = MimeGuess::from_path(name).first_or_octet_stream().to_string();
+    let sha256 = media::content_hash(&data);
+    let info = media_info::probe(&data, &mime_type);
+    let existing = match copied.get(&sha256) {
+        Some(existing) => Some(existing.clone()),
+        None => db::file_with_hash(conn, &sha256)?.filter(|(file_path, _)| Path::new(file_path).is_file()),
+    };
+    let (stored_path, thumbnail_path) = match existing {
+        Some(existing) => {
+            report.reused += 1;
+            existing
+        }
+        None => {
+            let unique_id: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(6)
+                .map(char::from)
+                .collect();
+            let original = name.split_once('-').map(|(_, original)| original).unwrap_or(name);
+            let stored_path = format!("{}/{}-{}", upload_dir.trim_end_matches('/'), unique_id, original);
+            written.files.push(stored_path.clone());
+            std::fs::write(&stored_path, &data)?;
+            let thumbnail_path = media::make_thumbnail(&data, &mime_type, &stored_path);
+            written.files.extend(thumbnail_path.clone());
+            report.files += 1;
+            copied.insert(sha256.clone(), (stored_path.clone(), thumbnail_path.clone()));
+            (stored_path, thumbnail_path)
+        }
+    };
+    Ok(Some(Attachment {
+        file_path: stored_path,
+        original_name: None,
+        mime_type: Some(mime_type),
+        size: Some(data.len() as i64),
+        width: info.width.map(i64::from),
+        height: info.height.map(i64::from),
+        duration: info.duration,
+        thumbnail_path,
+        sha256: Some(sha256),
+        md5: Some(media::content_md5(&data)),
+    }))
+}
+
+// Unix seconds for each thread's bump time. Seconds are coarser than the
+// milliseconds the sled edition kept, and threads bumped within the same
+// second would otherwise be ordered by id; those move up a second where
+// needed so the board lists threads in the order it did before.
+fn bump_times(threads: &[(&LegacyPost, i64)]) -> HashMap<i64, i64> {
+    let mut by_bump: Vec<_> = threads.iter().collect();
+    by_bump.sort_by_key(|(post, id)| (post.last_reply_at, *id));
+    let mut times = HashMap::new();
+    let mut previous: Option<(i64, i64)> = None;
+    for (post, id) in by_bump {
+        let mut seconds = (post.last_reply_at / 1000) as i64;
+        if let Some((previous_seconds, previous_id)) = previous {
+            seconds = seconds.max(previous_seconds);
+            if seconds == previous_seconds && *id < previous_id {
+                seconds += 1;
+            }
+        }
+        times.insert(*id, seconds);
+        previous = Some((seconds, *id));
+    }
+    times
+}
+
+// Reads the sled database at `sled_path` and adds its posts to `board_id`,
+// which is created if it doesn't exist. Files are looked up by name in
+// `files_dir`, the sled edition's `./static`.
+pub fn migrate_sled(
+    conn: &Connection,
+    sled_path: &Path,
+    files_dir: &Path,
+    upload_dir: &str,
+    board_id: i32,
+) -> Result<MigrateReport, MigrateError> {
+    // Opening creates a database where there is none, which would only
+    // migrate nothing
+    if !sled_path.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} is not a sled database", sled_path.display()),
+        )
+        .into());
+    }
+    let legacy = sled::open(sled_path)?;
+
+    let mut report = MigrateReport {
+        board_id,
+        threads: 0,
+        replies: 0,
+        files: 0,
+        reused: 0,
+        missing_files: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut posts: HashMap<String, LegacyPost> = HashMap::new();
+    for entry in legacy.iter() {
+        let (key, value) = entry?;
+        match serde_json::from_slice::<LegacyPost>(&value) {
+            Ok(post) => {
+                posts.insert(post.id.clone(), post);
+            }
+            Err(e) => report.skipped.push(format!("{}: {}", String::from_utf8_lossy(&key), e)),
+        }
+    }
+
+    // A reply was written at its `last_reply_at`. A thread was written no
+    // later than its first reply, and one without replies was never bumped.
+    let mut first_reply: HashMap<&str, u64> = HashMap::new();
+    for post in posts.values().filter(|post| post.parent_id != "0") {
+        let at = first_reply.entry(&post.parent_id).or_insert(post.last_reply_at);
+        *at = (*at).min(post.last_reply_at);
+    }
+    let mut written_at: HashMap<&str, u64> = HashMap::new();
+    for post in posts.values() {
+        if post.parent_id == "0" {
+            let at = first_reply
+                .get(post.id.as_str())
+                .map_or(post.last_reply_at, |&at| at.min(post.last_reply_at));
+            written_at.insert(&post.id, at);
+        } else if posts.get(&post.parent_id).is_some_and(|parent| parent.parent_id == "0") {
+            written_at.insert(&post.id, post.last_reply_at);
+        } else {
+            report.skipped.push(format!(
+                "{}: replies to {}, which isn't a thread",
+                post.id, post.parent_id
+            ));
+        }
+    }
+    // Threads before their replies when written in the same millisecond
+    let mut order: Vec<&LegacyPost> = written_at.keys().map(|id| &posts[*id]).collect();
+    order.sort_by_key(|post| (written_at[post.id.as_str()], post.parent_id != "0", post.id.clone()));
+
+    let mut written = Written::default();
+    // Hash -> where the file was copied, for files several posts carry
+    let mut copied = HashMap::new();
+    let mut files = HashMap::new();
+    for post in &order {
+        if let Some(file_path) = &post.file_path {
+            match store_file(
+                conn,
+                file_path,
+                files_dir,
+                upload_dir,
+                &mut written,
+                &mut copied,
+                &mut report,
+            )? {
+                Some(attachment) => {
+                    files.insert(post.id.as_str(), attachment);
+                }
+                None => report.missing_files.push(file_path.clone()),
+            }
+        }
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    if !db::board_exists(&tx, board_id)? {
+        db::create_board(&tx, board_id, "", &BoardSettings::default())?;
+    }
+    let mut ids: HashMap<&str, i64> = HashMap::new();
+    for post in &order {
+        let parent_id = if post.parent_id == "0" {
+            0
+        } else {
+            ids[post.parent_id.as_str()] as i32
+        };
+        let created_at = (written_at[post.id.as_str()] / 1000) as i64;
+        let id = db::insert_post(
+            &tx,
+            board_id,
+            &Post {
+                id: 0,
+                post_id: post.id.clone(),
+                parent_id,
+                title: post.title.clone(),
+                message: post.message.clone(),
+                files: files.remove(post.id.as_str()).into_iter().collect(),
+                name: None,
+                tripcode: None,
+                poster_id: None,
+                created_at,
+                last_reply_at: created_at,
+                edited_at: None,
+            },
+            &Poster::default(),
+        )?;
+        ids.insert(&post.id, id);
+        if parent_id == 0 {
+            report.threads += 1;
+        } else {
+            report.replies += 1;
+        }
+    }
+    let threads: Vec<_> = order
+        .iter()
+        .filter(|post| post.parent_id == "0")
+        .map(|post| (*post, ids[post.id.as_str()]))
+        .collect();
+    for (thread_id, at) in bump_times(&threads) {
+        db::bump(&tx, thread_id as i32, at)?;
+    }
+    tx.commit()?;
+    written.keep = true;
+    Ok(report)
+}
+
+// The sled edition kept its files in `./static` next to its database
+pub fn default_files_dir(sled_path: &Path) -> PathBuf {
+    sled_path.parent().unwrap_or_else(|| Path::new(".")).join("static")
+}
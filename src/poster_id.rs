@@ -0,0 +1,23 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const POSTER_ID_LENGTH: usize = 8;
+
+// Same poster, same thread, same UTC day gives the same ID. Anything else,
+// including the same poster in another thread, gives an unrelated one.
+pub fn poster_id(secret: &[u8], ip: IpAddr, thread_id: i64) -> String {
+    let day = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() / 86400)
+        .unwrap_or(0);
+
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}|{}|{}", ip, thread_id, day).as_bytes());
+    let digest = STANDARD.encode(mac.finalize().into_bytes());
+    digest[..POSTER_ID_LENGTH].to_string()
+}
@@ -0,0 +1,153 @@
+use actix_web::{web, HttpResponse};
+use std::collections::HashMap;
+
+use crate::db::{self, SearchQuery};
+use crate::config::Config;
+use crate::error::AppError;
+use crate::render_template;
+use crate::store::Store;
+
+pub async fn search(
+    store: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    let board_id = query.get("board").and_then(|b| b.trim().parse().ok());
+    render_search(store.get_ref(), config.posts_per_page, board_id, false, &query)
+}
+
+pub async fn board_search(
+    store: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    board_id: web::Path<i32>,
+    query: web::Query<HashMap<String, String>>,
+) -> Result<HttpResponse, AppError> {
+    render_search(store.get_ref(), config.posts_per_page, Some(*board_id), true, &query)
+}
+
+// YYYY-MM-DD, as sent by <input type="date">
+fn is_date(value: &str) -> bool {
+    value.len() == 10
+        && value.char_indices().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() })
+}
+
+fn render_search(
+    store: &dyn Store,
+    per_page: usize,
+    board_id: Option<i32>,
+    board_page: bool,
+    query: &HashMap<String, String>,
+) -> Result<HttpResponse, AppError> {
+    let text = query.get("q").map(|q| q.trim().to_string()).unwrap_or_default();
+    let from = query.get("from").filter(|d| is_date(d)).cloned();
+    let to = query.get("to").filter(|d| is_date(d)).cloned();
+    let has_file = query.get("has_file").is_some_and(|v| v == "1" || v == "on");
+    let page: usize = query.get("page").and_then(|p| p.parse().ok()).filter(|&p| p > 0).unwrap_or(1);
+
+    let action = match board_id {
+        Some(board_id) if board_page => format!("/{}/search", board_id),
+        _ => "/search".to_string(),
+    };
+
+    let mut results_html = String::new();
+    let mut pagination_html = String::new();
+
+    let terms = db::search_terms(&text);
+    if !terms.is_empty() {
+        let search = SearchQuery {
+            terms,
+            board_id,
+            from: from.clone(),
+            to: to.clone(),
+            has_file,
+        };
+        let (total, hits) = store.search(&search, per_page, (page - 1).saturating_mul(per_page))?;
+
+        results_html.push_str(&format!(
+            "<div class=\"search-summary\">{} result{}</div>",
+            total,
+            if total == 1 { "" } else { "s" }
+        ));
+        for hit in hits {
+            let thread_id = if hit.parent_id == 0 { hit.id } else { hit.parent_id };
+            results_html.push_str("<div class=\"post\">");
+            results_html.push_str(&format!(
+                "<div class=\"post-id\"><a href=\"/{}/post/{}\">/{}/ No. {}</a> {}{}</div>",
+                hit.board_id,
+                thread_id,
+                hit.board_id,
+                hit.id,
+                hit.created_at,
+                if hit.has_file { " (file)" } else { "" }
+            ));
+            results_html.push_str(&format!("<div class=\"post-title\">{}</div>", hit.title));
+            results_html.push_str(&format!("<div class=\"post-message\">{}</div>", hit.message));
+            results_html.push_str("</div>");
+        }
+
+        // Page links keep every filter that was applied
+        let mut params = vec![("q", text.clone())];
+        if let (Some(board_id), false) = (board_id, board_page) {
+            params.push(("board", board_id.to_string()));
+        }
+        if let Some(from) = &from {
+            params.push(("from", from.clone()));
+        }
+        if let Some(to) = &to {
+            params.push(("to", to.clone()));
+        }
+        if has_file {
+            params.push(("has_file", "1".to_string()));
+        }
+        let page_link = |page: usize, label: &str| {
+            let mut params = params.clone();
+            params.push(("page", page.to_string()));
+            format!(
+                r#"<a href="{}?{}">{}</a>"#,
+                action,
+                htmlescape::encode_attribute(&serde_urlencoded::to_string(&params).unwrap_or_default()),
+                label
+            )
+        };
+        if page > 1 {
+            pagination_html.push_str(&page_link(page - 1, "Previous"));
+        }
+        if page.saturating_mul(per_page) < total as usize {
+            pagination_html.push_str(&page_link(page + 1, "Next"));
+        }
+    }
+
+    let board_field = if board_page {
+        String::new()
+    } else {
+        format!(
+            r#"<input type="number" name="board" min="1" placeholder="Board (optional)" value="{}"><br>"#,
+            board_id.map(|b| b.to_string()).unwrap_or_default()
+        )
+    };
+    let heading = match board_id {
+        Some(board_id) if board_page => format!("Search /{}/", board_id),
+        _ => "Search all boards".to_string(),
+    };
+    let back_link = match board_id {
+        Some(board_id) if board_page => format!("/{}", board_id),
+        _ => "/".to_string(),
+    };
+
+    let context = HashMap::from([
+        ("HEADING", heading),
+        ("BACK_LINK", back_link),
+        ("ACTION", action),
+        ("QUERY", htmlescape::encode_attribute(&text)),
+        ("BOARD_FIELD", board_field),
+        ("FROM", from.unwrap_or_default()),
+        ("TO", to.unwrap_or_default()),
+        ("HAS_FILE", if has_file { "checked".to_string() } else { String::new() }),
+        ("RESULTS", results_html),
+        ("PAGINATION", pagination_html),
+    ]);
+
+    let body = render_template("templates/search.html", &context)?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
@@ -0,0 +1,100 @@
+// Security headers, set per group of routes: pages, the data made for other
+// programs (JSON and feeds), and uploaded files. The content security,
+// framing, referrer and permissions headers of each group come from the
+// config, with the values below as defaults; an empty value leaves that header
+// out. A handler that sets one
+// of these headers itself keeps its own.
+use actix_web::middleware::DefaultHeaders;
+
+use crate::config::Config;
+
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    pub content_security_policy: String,
+    pub frame_options: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+}
+
+// Pages: scripts, styles and media only from files we serve; no inline
+// scripts or styles, no plugins, and no framing
+pub const PAGES_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'self'; style-src 'self'; \
+                                                 img-src 'self'; media-src 'self'; connect-src 'self'; \
+                                                 form-action 'self'; base-uri 'none'; frame-ancestors 'none'";
+pub const PAGES_FRAME_OPTIONS: &str = "DENY";
+pub const PAGES_REFERRER_POLICY: &str = "same-origin";
+// Features no page uses
+pub const PAGES_PERMISSIONS_POLICY: &str =
+    "camera=(), microphone=(), geolocation=(), payment=(), usb=(), display-capture=(), fullscreen=(self)";
+
+// Data: JSON and feeds are never rendered as pages of ours
+pub const DATA_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+pub const DATA_FRAME_OPTIONS: &str = "DENY";
+pub const DATA_REFERRER_POLICY: &str = "no-referrer";
+pub const DATA_PERMISSIONS_POLICY: &str = PAGES_PERMISSIONS_POLICY;
+
+// Media: uploads open on their own, sandboxed so a file that slipped through
+// as something else can't run anything
+pub const MEDIA_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; img-src 'self'; media-src 'self'; \
+                                                 style-src 'unsafe-inline'; sandbox; frame-ancestors 'self'";
+pub const MEDIA_FRAME_OPTIONS: &str = "SAMEORIGIN";
+pub const MEDIA_REFERRER_POLICY: &str = "no-referrer";
+pub const MEDIA_PERMISSIONS_POLICY: &str = PAGES_PERMISSIONS_POLICY;
+
+impl SecurityHeaders {
+    pub fn pages(config: &Config) -> SecurityHeaders {
+        SecurityHeaders::new(
+            &config.pages_content_security_policy,
+            &config.pages_frame_options,
+            &config.pages_referrer_policy,
+            &config.pages_permissions_policy,
+        )
+    }
+
+    pub fn data(config: &Config) -> SecurityHeaders {
+        SecurityHeaders::new(
+            &config.data_content_security_policy,
+            &config.data_frame_options,
+            &config.data_referrer_policy,
+            &config.data_permissions_policy,
+        )
+    }
+
+    pub fn media(config: &Config) -> SecurityHeaders {
+        SecurityHeaders::new(
+            &config.media_content_security_policy,
+            &config.media_frame_options,
+            &config.media_referrer_policy,
+            &config.media_permissions_policy,
+        )
+    }
+
+    fn new(
+        content_security_policy: &str,
+        frame_options: &str,
+        referrer_policy: &str,
+        permissions_policy: &str,
+    ) -> SecurityHeaders {
+        SecurityHeaders {
+            content_security_policy: content_security_policy.trim().to_string(),
+            frame_options: frame_options.trim().to_string(),
+            referrer_policy: referrer_policy.trim().to_string(),
+            permissions_policy: permissions_policy.trim().to_string(),
+        }
+    }
+
+    pub fn middleware(&self) -> DefaultHeaders {
+        let mut headers = DefaultHeaders::new();
+        for (name, value) in [
+            ("Content-Security-Policy", &self.content_security_policy),
+            ("X-Frame-Options", &self.frame_options),
+            ("Referrer-Policy", &self.referrer_policy),
+            ("Permissions-Policy", &self.permissions_policy),
+        ] {
+            if !value.is_empty() {
+                headers = headers.add((name, value.as_str()));
+            }
+        }
+        headers.add(("X-Content-Type-Options", "nosniff"))
+    }
+}
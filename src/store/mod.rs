@@ -0,0 +1,175 @@
+// Where posts are kept. Everything that reads or changes posts goes through
+// `Store`, so it works the same on either backend:
+//
+//   sqlite  posts in the `files` and `attachments` tables (see db.rs)
+//   sled    posts in an embedded sled database
+//
+// Each backend is a cargo feature; the `storage` setting picks one at startup.
+// Board names and settings and bans are always kept in the SQLite database.
+// Board archives, the sled migration and the admin tool's rebuilds and checks
+// work on the SQLite tables directly, so `adelia-admin` refuses them when
+// another backend is configured.
+use rusqlite::Connection;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{Config, Storage};
+use crate::db::{self, Attachment, Deleted, Post, Poster, Revision, SearchHit, SearchQuery};
+
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+#[cfg(not(any(feature = "sqlite", feature = "sled")))]
+compile_error!("enable at least one storage backend: the `sqlite` or `sled` feature");
+
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "sled")]
+    Sled(::sled::Error),
+    // A stored record that doesn't deserialize
+    Corrupt(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "{}", e),
+            #[cfg(feature = "sled")]
+            StoreError::Sled(e) => write!(f, "{}", e),
+            StoreError::Corrupt(message) => write!(f, "corrupt record: {}", message),
+        }
+    }
+}
+
+impl fmt::Debug for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+// Timestamps are unix seconds. Ids are handed out by the store, start at 1
+// and grow with every post; a `parent_id` of 0 marks a thread starter.
+pub trait Store: Send + Sync {
+    // Stores `post` (its `id` is ignored) with its files and what `poster`
+    // says, all at once, and returns its id
+    fn insert_post(&self, board_id: i32, post: &Post, poster: &Poster) -> StoreResult<i32>;
+
+    // As made by deletion::hash_password
+    fn deletion_password(&self, id: i32) -> StoreResult<Option<String>>;
+
+    // The author id of author.rs
+    fn author(&self, id: i32) -> StoreResult<Option<String>>;
+
+    // Replaces the title and message as of `at`, keeping the old ones as a
+    // revision. False when there is no such post.
+    fn edit_post(&self, id: i32, title: &str, message: &str, at: i64) -> StoreResult<bool>;
+
+    // Earlier versions of the post, oldest first
+    fn revisions(&self, id: i32) -> StoreResult<Vec<Revision>>;
+
+    fn fetch_post(&self, id: i32) -> StoreResult<Option<Post>>;
+
+    // The board the post is on
+    fn post_board(&self, id: i32) -> StoreResult<Option<i32>>;
+
+    // The thread starter followed by its replies, oldest first; empty when
+    // the board has no such post
+    fn fetch_thread(&self, board_id: i32, thread_id: i32) -> StoreResult<Vec<Post>>;
+
+    // Whether `thread_id` starts a thread on this board
+    fn thread_exists(&self, board_id: i32, thread_id: i32) -> StoreResult<bool>;
+
+    // Thread starters, most recently bumped first
+    fn list_threads(&self, board_id: i32, limit: usize, offset: usize) -> StoreResult<Vec<Post>>;
+
+    fn count_threads(&self, board_id: i32) -> StoreResult<i64>;
+
+    // Boards with at least one post, in order
+    fn board_ids(&self) -> StoreResult<Vec<i32>>;
+
+    // Thread starters, newest first regardless of bumps
+    fn recent_threads(&self, board_id: i32, limit: usize) -> StoreResult<Vec<Post>>;
+
+    // Number of replies and how many of them carry a file
+    fn count_replies(&self, thread_id: i32) -> StoreResult<(i64, i64)>;
+
+    // The newest `limit` replies of a thread, oldest first
+    fn last_replies(&self, thread_id: i32, limit: usize) -> StoreResult<Vec<Post>>;
+
+    // Moves the thread to the top of its board as of `at`
+    fn bump(&self, thread_id: i32, at: i64) -> StoreResult<()>;
+
+    // 0 for a thread starter, otherwise the reply's position in its thread
+    fn reply_number(&self, post: &Post) -> StoreResult<i64>;
+
+    // Posts on a board, or in one thread of it, newer than `after_id`
+    fn posts_after(&self, board_id: i32, thread_id: Option<i32>, after_id: i32, limit: usize) -> StoreResult<Vec<Post>>;
+
+    // Total number of posts matching `query`, and one page of them with the
+    // matches marked. SQLite orders them by relevance, sled newest first.
+    fn search(&self, query: &SearchQuery, limit: usize, offset: usize) -> StoreResult<(i64, Vec<SearchHit>)>;
+
+    // The attachment of some post whose file or thumbnail has this stored name
+    fn find_file(&self, name: &str) -> StoreResult<Option<Attachment>>;
+
+    // A reply on its own, or a thread starter with its whole thread, with
+    // their revisions. The files are left on disk for the caller to remove.
+    fn delete_post(&self, id: i32) -> StoreResult<Deleted>;
+
+    // Everything posted to the board, leaving the files on disk like
+    // `delete_post`. Its name and settings are not kept here.
+    fn delete_board(&self, board_id: i32) -> StoreResult<Deleted>;
+
+    // Takes the post's files off it, keeping the post
+    fn delete_files(&self, id: i32) -> StoreResult<Deleted>;
+
+    // Points files and thumbnails kept directly in directory `from` at the
+    // same names in `to`, returning how many attachments changed
+    fn move_files(&self, from: &str, to: &str) -> StoreResult<usize>;
+}
+
+// The store `config` asks for; the SQLite one shares `conn`
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+pub fn open(config: &Config, conn: Arc<Mutex<Connection>>) -> StoreResult<Arc<dyn Store>> {
+    match config.storage {
+        #[cfg(feature = "sqlite")]
+        Storage::Sqlite => Ok(Arc::new(SqliteStore::new(conn))),
+        #[cfg(feature = "sled")]
+        Storage::Sled => Ok(Arc::new(SledStore::open(&config.sled_path)?)),
+        #[allow(unreachable_patterns)]
+        _ => unreachable!("Config::load turns down backends that weren't built"),
+    }
+}
+
+// Boards with a name or settings or with posts, in order, with their names
+// ("" for none)
+pub fn list_boards(conn: &Connection, store: &dyn Store) -> StoreResult<Vec<(i32, String)>> {
+    let mut boards = db::board_names(conn)?;
+    for board_id in store.board_ids()? {
+        if let Err(index) = boards.binary_search_by_key(&board_id, |&(id, _)| id) {
+            boards.insert(index, (board_id, String::new()));
+        }
+    }
+    Ok(boards)
+}
+
+impl From<StoreError> for std::io::Error {
+    fn from(e: StoreError) -> Self {
+        std::io::Error::other(e.to_string())
+    }
+}
@@ -0,0 +1,844 @@
+use ::sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree,
+};
+use ::sled::Tree;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::convert::TryInto;
+use std::path::Path;
+
+use super::{Store, StoreError, StoreResult};
+use crate::db::{Attachment, Deleted, Post, Poster, Revision, SearchHit, SearchQuery};
+use crate::sanitize_input;
+
+impl From<::sled::Error> for StoreError {
+    fn from(e: ::sled::Error) -> Self {
+        StoreError::Sled(e)
+    }
+}
+
+impl From<TransactionError<StoreError>> for StoreError {
+    fn from(e: TransactionError<StoreError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StoreError::Sled(e),
+        }
+    }
+}
+
+// What is kept per post: the post as JSON, with the board it belongs to
+#[derive(Serialize, Deserialize)]
+struct Record {
+    board_id: i32,
+    post: Post,
+    // See deletion.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deletion_password: Option<String>,
+    // See author.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+}
+
+// Layout version, kept in the default tree. Databases written before
+// there was one keep all posts in a single `posts` tree, and those of
+// version 1 index them there too; they get moved into boards when opened.
+// Version 2 lacks the `files` tree.
+const VERSION_KEY: &[u8] = b"version";
+// The newest post id, also in the default tree
+const LAST_ID_KEY: &[u8] = b"last_id";
+const VERSION: u32 = 3;
+const OLD_TREES: [&str; 5] = ["posts", "threads", "replies", "board_posts", "counters"];
+const BOARD_PREFIX: &str = "board/";
+const BOARD_TREES: [&str; 4] = ["posts", "threads", "replies", "counters"];
+
+// Every board has trees of its own, indexing its posts for every page that
+// lists some, so no page reads more posts than it shows:
+//
+//   board/{id}/posts     id -> Record
+//   board/{id}/threads   (bump time, id) -> (), newest last
+//   board/{id}/replies   (thread, id) -> ()
+//   board/{id}/counters  thread -> (replies, replies with files)
+//
+// Four trees are shared by all boards:
+//
+//   boards     board -> number of threads, for every board with posts
+//   locations  id -> board, for looking up posts by id alone
+//   files      stored name of a file or thumbnail -> id of the post with it
+//   revisions  id -> the post's earlier versions, oldest first, as JSON
+//
+// Numbers are big-endian so keys sort like the numbers do. Every change to
+// a post and its index entries happens in one transaction.
+pub struct SledStore {
+    db: ::sled::Db,
+    boards: Tree,
+    locations: Tree,
+    files: Tree,
+    revisions: Tree,
+}
+
+// The trees of one board
+struct Board {
+    id: i32,
+    posts: Tree,
+    threads: Tree,
+    replies: Tree,
+    counters: Tree,
+}
+
+// Paths of a post's files and thumbnails
+fn file_paths(post: &Post) -> impl Iterator<Item = &String> {
+    post.files
+        .iter()
+        .flat_map(|file| std::iter::once(&file.file_path).chain(&file.thumbnail_path))
+}
+
+fn file_name(path: &str) -> Option<&str> {
+    Path::new(path).file_name()?.to_str()
+}
+
+// Stored names of a post's files and thumbnails
+fn file_names(post: &Post) -> impl Iterator<Item = &str> {
+    file_paths(post).filter_map(|path| file_name(path))
+}
+
+fn key(id: i32) -> [u8; 4] {
+    id.to_be_bytes()
+}
+
+fn pair(first: i32, second: i32) -> [u8; 8] {
+    let mut key = [0; 8];
+    key[..4].copy_from_slice(&first.to_be_bytes());
+    key[4..].copy_from_slice(&second.to_be_bytes());
+    key
+}
+
+// Flipping the sign bit makes negative times sort before positive ones
+fn thread_key(bumped_at: i64, id: i32) -> [u8; 12] {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&((bumped_at as u64) ^ (1 << 63)).to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+// The id at the end of an index key
+fn last_id(key: &[u8]) -> i32 {
+    let mut id = [0; 4];
+    id.copy_from_slice(&key[key.len() - 4..]);
+    i32::from_be_bytes(id)
+}
+
+fn number(value: Option<impl AsRef<[u8]>>) -> i64 {
+    value
+        .and_then(|value| value.as_ref().try_into().ok())
+        .map_or(0, i64::from_be_bytes)
+}
+
+fn decode_id(value: Option<impl AsRef<[u8]>>) -> Option<i32> {
+    value.and_then(|value| value.as_ref().try_into().ok()).map(i32::from_be_bytes)
+}
+
+fn counts(value: Option<impl AsRef<[u8]>>) -> (i64, i64) {
+    match value {
+        Some(value) if value.as_ref().len() == 16 => {
+            let (replies, with_files) = value.as_ref().split_at(8);
+            (number(Some(replies)), number(Some(with_files)))
+        }
+        _ => (0, 0),
+    }
+}
+
+fn encode_counts((replies, with_files): (i64, i64)) -> Vec<u8> {
+    [replies.to_be_bytes(), with_files.to_be_bytes()].concat()
+}
+
+fn decode(value: &[u8]) -> StoreResult<Record> {
+    serde_json::from_slice(value).map_err(|e| StoreError::Corrupt(e.to_string()))
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    serde_json::to_vec(record).expect("posts serialize to JSON")
+}
+
+// `text` with every occurrence of a term wrapped in <mark>, ignoring ASCII
+// case, noting in `found` which terms it has. Both are HTML-escaped; tags
+// and character references are never matched into.
+fn mark_terms(text: &str, terms: &[String], found: &mut [bool]) -> String {
+    let mut marked = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let hit = terms
+            .iter()
+            .enumerate()
+            .filter(|(_, term)| {
+                !term.is_empty()
+                    && rest.as_bytes().get(..term.len()).is_some_and(|start| start.eq_ignore_ascii_case(term.as_bytes()))
+            })
+            .max_by_key(|(_, term)| term.len());
+        if let Some((index, term)) = hit {
+            found[index] = true;
+            marked.push_str("<mark>");
+            marked.push_str(&rest[..term.len()]);
+            marked.push_str("</mark>");
+            rest = &rest[term.len()..];
+            continue;
+        }
+        let end = match c {
+            '<' => rest.find('>'),
+            '&' => rest.find(';'),
+            _ => None,
+        };
+        let skipped = end.map_or(c.len_utf8(), |end| end + 1);
+        marked.push_str(&rest[..skipped]);
+        rest = &rest[skipped..];
+    }
+    marked
+}
+
+// As SQLite keeps it
+fn date_time(seconds: i64) -> String {
+    let time = chrono::DateTime::from_timestamp(seconds, 0).unwrap_or_default();
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn abort<T>(e: StoreError) -> ConflictableTransactionResult<T, StoreError> {
+    Err(ConflictableTransactionError::Abort(e))
+}
+
+// Drops the `files` entries of a post that is going away, returning the
+// paths of the files only it had
+fn unindex_files(files: &TransactionalTree, post: &Post) -> ConflictableTransactionResult<Vec<String>, StoreError> {
+    let mut unused = Vec::new();
+    for path in file_paths(post) {
+        let name = match file_name(path) {
+            Some(name) => name,
+            None => continue,
+        };
+        if decode_id(files.get(name)?) == Some(post.id) {
+            files.remove(name)?;
+            unused.push(path.clone());
+        }
+    }
+    Ok(unused)
+}
+
+fn get_in(posts: &TransactionalTree, id: i32) -> ConflictableTransactionResult<Option<Record>, StoreError> {
+    match posts.get(key(id))? {
+        Some(value) => decode(&value).map(Some).or_else(abort),
+        None => Ok(None),
+    }
+}
+
+impl Board {
+    fn get(&self, id: i32) -> StoreResult<Option<Record>> {
+        match self.posts.get(key(id))? {
+            Some(value) => Ok(Some(decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The posts at the ends of `keys`, in that order
+    fn posts_for<I>(&self, keys: I) -> StoreResult<Vec<Post>>
+    where
+        I: Iterator<Item = ::sled::Result<::sled::IVec>>,
+    {
+        let mut posts = Vec::new();
+        for index_key in keys {
+            if let Some(record) = self.get(last_id(&index_key?))? {
+                posts.push(record.post);
+            }
+        }
+        Ok(posts)
+    }
+
+    fn reply_keys(&self, thread_id: i32) -> impl DoubleEndedIterator<Item = ::sled::Result<::sled::IVec>> {
+        self.replies.scan_prefix(key(thread_id)).keys()
+    }
+
+    fn thread(&self, thread_id: i32) -> StoreResult<Option<Post>> {
+        Ok(self.get(thread_id)?.map(|record| record.post).filter(|post| post.parent_id == 0))
+    }
+}
+
+impl SledStore {
+    pub fn open(path: &str) -> StoreResult<SledStore> {
+        SledStore::new(::sled::open(path)?)
+    }
+
+    // A database that is deleted again once dropped
+    pub fn temporary() -> StoreResult<SledStore> {
+        SledStore::new(::sled::Config::new().temporary(true).open()?)
+    }
+
+    pub fn new(db: ::sled::Db) -> StoreResult<SledStore> {
+        let store = SledStore {
+            boards: db.open_tree("boards")?,
+            locations: db.open_tree("locations")?,
+            files: db.open_tree("files")?,
+            revisions: db.open_tree("revisions")?,
+            db,
+        };
+        let version = store.db.get(VERSION_KEY)?.and_then(|value| value.as_ref().try_into().ok());
+        match version.map(u32::from_be_bytes) {
+            Some(VERSION) => {}
+            Some(2) => {
+                store.index_files()?;
+                store.set_version()?;
+            }
+            _ => store.upgrade()?,
+        }
+        // What an upgrade cut short after writing the version left behind
+        store.drop_old_trees()?;
+        Ok(store)
+    }
+
+    // Written to disk before the old trees go, so that a crash in between
+    // never leaves a database with neither
+    fn set_version(&self) -> StoreResult<()> {
+        self.db.insert(VERSION_KEY, &VERSION.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn drop_old_trees(&self) -> StoreResult<()> {
+        for name in OLD_TREES {
+            self.db.drop_tree(name)?;
+        }
+        Ok(())
+    }
+
+    // Moves the posts of an older layout into their boards. Until the
+    // version is written it starts from scratch every time, since the old
+    // trees are still there to start from.
+    fn upgrade(&self) -> StoreResult<()> {
+        for name in self.db.tree_names() {
+            if name.starts_with(BOARD_PREFIX.as_bytes()) {
+                self.db.drop_tree(name)?;
+            }
+        }
+        self.boards.clear()?;
+        self.locations.clear()?;
+        self.files.clear()?;
+        for value in self.db.open_tree("posts")?.iter().values() {
+            let Record { board_id, post, .. } = decode(&value?)?;
+            self.add(&self.board_trees(board_id)?, post, &Poster::default(), false)?;
+        }
+        self.set_version()
+    }
+
+    fn index_files(&self) -> StoreResult<()> {
+        self.files.clear()?;
+        for board_key in self.boards.iter().keys() {
+            let board = match decode_id(Some(board_key?)) {
+                Some(board_id) => self.board_trees(board_id)?,
+                None => continue,
+            };
+            for value in board.posts.iter().values() {
+                let post = decode(&value?)?.post;
+                for name in file_names(&post) {
+                    self.files.insert(name, &key(post.id))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn board_trees(&self, board_id: i32) -> StoreResult<Board> {
+        let tree = |name: &str| self.db.open_tree(format!("{}{}/{}", BOARD_PREFIX, board_id, name));
+        Ok(Board {
+            id: board_id,
+            posts: tree("posts")?,
+            threads: tree("threads")?,
+            replies: tree("replies")?,
+            counters: tree("counters")?,
+        })
+    }
+
+    // The trees of a board that has posts; others have none yet
+    fn board(&self, board_id: i32) -> StoreResult<Option<Board>> {
+        if self.boards.contains_key(key(board_id))? {
+            Ok(Some(self.board_trees(board_id)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // The board a post is on
+    fn locate(&self, id: i32) -> StoreResult<Option<Board>> {
+        match decode_id(self.locations.get(key(id))?) {
+            Some(board_id) => self.board(board_id),
+            None => Ok(None),
+        }
+    }
+
+    // Changes what is kept of a post other than its index entries
+    fn update(&self, id: i32, change: impl Fn(&mut Record)) -> StoreResult<()> {
+        if let Some(board) = self.locate(id)? {
+            board.posts.transaction(|posts| {
+                if let Some(mut record) = get_in(posts, id)? {
+                    change(&mut record);
+                    posts.insert(&key(id), encode(&record))?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, id: i32) -> StoreResult<Option<Record>> {
+        match self.locate(id)? {
+            Some(board) => board.get(id),
+            None => Ok(None),
+        }
+    }
+
+    // Stores a post with its index entries, under a new id or the one it has,
+    // and returns the id
+    fn add(&self, board: &Board, post: Post, poster: &Poster, new_id: bool) -> StoreResult<i32> {
+        let trees = (
+            &*self.db,
+            &self.boards,
+            &self.locations,
+            &self.files,
+            &board.posts,
+            &board.threads,
+            &board.replies,
+            &board.counters,
+        );
+        let id = trees.transaction(|(meta, boards, locations, files, posts, threads, replies, counters)| {
+            let last_id = number(meta.get(LAST_ID_KEY)?) as i32;
+            let id = if new_id { last_id + 1 } else { post.id };
+            meta.insert(LAST_ID_KEY, &(id.max(last_id) as i64).to_be_bytes())?;
+
+            let mut record = Record {
+                board_id: board.id,
+                post: post.clone(),
+                deletion_password: poster.deletion_password.clone(),
+                author: poster.author.clone(),
+            };
+            record.post.id = id;
+            record.post.poster_id = poster.poster_id(&post, id);
+            posts.insert(&key(id), encode(&record))?;
+            let post = &record.post;
+            for name in file_names(post) {
+                files.insert(name, &key(id))?;
+            }
+            locations.insert(&key(id), &key(board.id))?;
+            let count = number(boards.get(key(board.id))?);
+            if post.parent_id == 0 {
+                threads.insert(&thread_key(post.last_reply_at, id), &[])?;
+                boards.insert(&key(board.id), &(count + 1).to_be_bytes())?;
+            } else {
+                // Makes sure the board is listed
+                boards.insert(&key(board.id), &count.to_be_bytes())?;
+                replies.insert(&pair(post.parent_id, id), &[])?;
+                let (count, with_files) = counts(counters.get(key(post.parent_id))?);
+                let with_file = !post.files.is_empty() as i64;
+                counters.insert(&key(post.parent_id), encode_counts((count + 1, with_files + with_file)))?;
+            }
+            Ok(id)
+        })?;
+        Ok(id)
+    }
+}
+
+impl Store for SledStore {
+    fn insert_post(&self, board_id: i32, post: &Post, poster: &Poster) -> StoreResult<i32> {
+        self.add(&self.board_trees(board_id)?, post.clone(), poster, true)
+    }
+
+    fn deletion_password(&self, id: i32) -> StoreResult<Option<String>> {
+        Ok(self.record(id)?.and_then(|record| record.deletion_password))
+    }
+
+    fn author(&self, id: i32) -> StoreResult<Option<String>> {
+        Ok(self.record(id)?.and_then(|record| record.author))
+    }
+
+    fn edit_post(&self, id: i32, title: &str, message: &str, at: i64) -> StoreResult<bool> {
+        let board = match self.locate(id)? {
+            Some(board) => board,
+            None => return Ok(false),
+        };
+        let edited = (&board.posts, &self.revisions).transaction(|(posts, revisions)| {
+            let mut record = match get_in(posts, id)? {
+                Some(record) => record,
+                None => return Ok(false),
+            };
+            let mut earlier: Vec<Revision> = match revisions.get(key(id))? {
+                Some(value) => serde_json::from_slice(&value).or_else(|e| abort(StoreError::Corrupt(e.to_string())))?,
+                None => Vec::new(),
+            };
+            earlier.push(Revision {
+                title: std::mem::replace(&mut record.post.title, title.to_string()),
+                message: std::mem::replace(&mut record.post.message, message.to_string()),
+                replaced_at: at,
+            });
+            record.post.edited_at = Some(at);
+            posts.insert(&key(id), encode(&record))?;
+            revisions.insert(&key(id), serde_json::to_vec(&earlier).expect("revisions serialize to JSON"))?;
+            Ok(true)
+        })?;
+        Ok(edited)
+    }
+
+    fn revisions(&self, id: i32) -> StoreResult<Vec<Revision>> {
+        match self.revisions.get(key(id))? {
+            Some(value) => serde_json::from_slice(&value).map_err(|e| StoreError::Corrupt(e.to_string())),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn fetch_post(&self, id: i32) -> StoreResult<Option<Post>> {
+        Ok(self.record(id)?.map(|record| record.post))
+    }
+
+    fn post_board(&self, id: i32) -> StoreResult<Option<i32>> {
+        Ok(decode_id(self.locations.get(key(id))?))
+    }
+
+    fn fetch_thread(&self, board_id: i32, thread_id: i32) -> StoreResult<Vec<Post>> {
+        let board = match self.board(board_id)? {
+            Some(board) => board,
+            None => return Ok(Vec::new()),
+        };
+        match board.get(thread_id)? {
+            Some(record) => {
+                let mut posts = vec![record.post];
+                posts.extend(board.posts_for(board.reply_keys(thread_id))?);
+                Ok(posts)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn thread_exists(&self, board_id: i32, thread_id: i32) -> StoreResult<bool> {
+        match self.board(board_id)? {
+            Some(board) => Ok(board.thread(thread_id)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    fn list_threads(&self, board_id: i32, limit: usize, offset: usize) -> StoreResult<Vec<Post>> {
+        match self.board(board_id)? {
+            Some(board) => board.posts_for(board.threads.iter().keys().rev().skip(offset).take(limit)),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn count_threads(&self, board_id: i32) -> StoreResult<i64> {
+        Ok(number(self.boards.get(key(board_id))?))
+    }
+
+    fn board_ids(&self) -> StoreResult<Vec<i32>> {
+        let mut board_ids = Vec::new();
+        for entry in self.boards.iter() {
+            let (board_key, count) = entry?;
+            // Boards stay listed with no threads once they are all deleted
+            if number(Some(count)) > 0 {
+                board_ids.extend(decode_id(Some(board_key)));
+            }
+        }
+        Ok(board_ids)
+    }
+
+    // Ids grow with every post, so the newest threads have the highest
+    fn recent_threads(&self, board_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        let board = match self.board(board_id)? {
+            Some(board) => board,
+            None => return Ok(Vec::new()),
+        };
+        let mut ids = Vec::new();
+        for index_key in board.threads.iter().keys() {
+            ids.push(last_id(&index_key?));
+        }
+        ids.sort_unstable_by_key(|&id| Reverse(id));
+        let mut threads = Vec::new();
+        for id in ids.into_iter().take(limit) {
+            threads.extend(board.get(id)?.map(|record| record.post));
+        }
+        Ok(threads)
+    }
+
+    fn count_replies(&self, thread_id: i32) -> StoreResult<(i64, i64)> {
+        match self.locate(thread_id)? {
+            Some(board) => Ok(counts(board.counters.get(key(thread_id))?)),
+            None => Ok((0, 0)),
+        }
+    }
+
+    fn last_replies(&self, thread_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        match self.locate(thread_id)? {
+            Some(board) => {
+                let mut replies = board.posts_for(board.reply_keys(thread_id).rev().take(limit))?;
+                replies.reverse();
+                Ok(replies)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn bump(&self, thread_id: i32, at: i64) -> StoreResult<()> {
+        let board = match self.locate(thread_id)? {
+            Some(board) => board,
+            None => return Ok(()),
+        };
+        // A reply written after this listing is newer than `at` anyway
+        let mut reply_ids = Vec::new();
+        for index_key in board.reply_keys(thread_id) {
+            reply_ids.push(last_id(&index_key?));
+        }
+        (&board.posts, &board.threads).transaction(|(posts, threads)| {
+            let mut thread = match get_in(posts, thread_id)? {
+                Some(record) => record,
+                None => return Ok(()),
+            };
+            threads.remove(&thread_key(thread.post.last_reply_at, thread_id))?;
+            threads.insert(&thread_key(at, thread_id), &[])?;
+            thread.post.last_reply_at = at;
+            posts.insert(&key(thread_id), encode(&thread))?;
+            for &id in &reply_ids {
+                if let Some(mut reply) = get_in(posts, id)? {
+                    reply.post.last_reply_at = at;
+                    posts.insert(&key(id), encode(&reply))?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn reply_number(&self, post: &Post) -> StoreResult<i64> {
+        if post.parent_id == 0 {
+            return Ok(0);
+        }
+        match self.locate(post.parent_id)? {
+            Some(board) => {
+                let earlier = board.replies.range(pair(post.parent_id, 0)..=pair(post.parent_id, post.id));
+                Ok(earlier.count() as i64)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn posts_after(&self, board_id: i32, thread_id: Option<i32>, after_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        let board = match self.board(board_id)? {
+            Some(board) => board,
+            None => return Ok(Vec::new()),
+        };
+        let after = after_id.saturating_add(1).max(0);
+        match thread_id {
+            Some(thread_id) => {
+                let thread = match board.get(thread_id)? {
+                    Some(record) => record.post,
+                    None => return Ok(Vec::new()),
+                };
+                let mut posts = Vec::new();
+                if thread_id >= after {
+                    posts.push(thread);
+                }
+                let keys = board.replies.range(pair(thread_id, after)..=pair(thread_id, i32::MAX)).keys();
+                posts.extend(board.posts_for(keys.take(limit.saturating_sub(posts.len())))?);
+                posts.truncate(limit);
+                Ok(posts)
+            }
+            None => {
+                let records = board.posts.range(key(after)..).values().take(limit);
+                records.map(|value| Ok(decode(&value?)?.post)).collect()
+            }
+        }
+    }
+
+    // Reads every post of the boards searched. Hits show the whole title and
+    // message rather than a snippet.
+    fn search(&self, query: &SearchQuery, limit: usize, offset: usize) -> StoreResult<(i64, Vec<SearchHit>)> {
+        let terms: Vec<String> = query.terms.iter().map(|term| sanitize_input(term)).collect();
+        let board_ids = match query.board_id {
+            Some(board_id) => vec![board_id],
+            None => self.board_ids()?,
+        };
+        let mut hits = Vec::new();
+        for board_id in board_ids {
+            let board = match self.board(board_id)? {
+                Some(board) => board,
+                None => continue,
+            };
+            for value in board.posts.iter().values() {
+                let post = decode(&value?)?.post;
+                let created_at = date_time(post.created_at);
+                let day = &created_at[..10];
+                if query.from.as_deref().is_some_and(|from| day < from)
+                    || query.to.as_deref().is_some_and(|to| day > to)
+                    || (query.has_file && post.files.is_empty())
+                {
+                    continue;
+                }
+                let mut found = vec![false; terms.len()];
+                let title = mark_terms(&post.title, &terms, &mut found);
+                let message = mark_terms(&post.message, &terms, &mut found);
+                if found.iter().all(|&found| found) {
+                    hits.push(SearchHit {
+                        id: post.id,
+                        parent_id: post.parent_id,
+                        board_id,
+                        title,
+                        message,
+                        created_at,
+                        has_file: !post.files.is_empty(),
+                    });
+                }
+            }
+        }
+        hits.sort_unstable_by_key(|hit| Reverse(hit.id));
+        let total = hits.len() as i64;
+        Ok((total, hits.into_iter().skip(offset).take(limit).collect()))
+    }
+
+    fn find_file(&self, name: &str) -> StoreResult<Option<Attachment>> {
+        let id = match decode_id(self.files.get(name)?) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let post = match self.fetch_post(id)? {
+            Some(post) => post,
+            None => return Ok(None),
+        };
+        let named = |path: &str| Path::new(path).file_name().and_then(|file| file.to_str()) == Some(name);
+        Ok(post
+            .files
+            .into_iter()
+            .find(|file| named(&file.file_path) || file.thumbnail_path.as_deref().is_some_and(named)))
+    }
+
+    fn delete_post(&self, id: i32) -> StoreResult<Deleted> {
+        let board = match self.locate(id)? {
+            Some(board) => board,
+            None => return Ok(Deleted::default()),
+        };
+        let whole_thread = match board.get(id)? {
+            Some(record) => record.post.parent_id == 0,
+            None => return Ok(Deleted::default()),
+        };
+        // Like a reply to a thread deleted just before, one posted while
+        // this runs is left without its thread
+        let mut ids = vec![id];
+        if whole_thread {
+            for index_key in board.reply_keys(id) {
+                ids.push(last_id(&index_key?));
+            }
+        }
+        let trees = (
+            &self.boards,
+            &self.locations,
+            &self.files,
+            &self.revisions,
+            &board.posts,
+            &board.threads,
+            &board.replies,
+            &board.counters,
+        );
+        let mut deleted = trees.transaction(|(boards, locations, files, revisions, posts, threads, replies, counters)| {
+            let mut deleted = Deleted::default();
+            for &id in &ids {
+                let post = match get_in(posts, id)? {
+                    Some(record) => record.post,
+                    None => continue,
+                };
+                posts.remove(&key(id))?;
+                locations.remove(&key(id))?;
+                revisions.remove(&key(id))?;
+                deleted.files.extend(unindex_files(files, &post)?);
+                deleted.posts += 1;
+                if post.parent_id == 0 {
+                    threads.remove(&thread_key(post.last_reply_at, id))?;
+                    counters.remove(&key(id))?;
+                    let count = number(boards.get(key(board.id))?);
+                    boards.insert(&key(board.id), &(count - 1).max(0).to_be_bytes())?;
+                } else {
+                    replies.remove(&pair(post.parent_id, id))?;
+                    if !whole_thread {
+                        let (count, with_files) = counts(counters.get(key(post.parent_id))?);
+                        let with_file = !post.files.is_empty() as i64;
+                        counters.insert(&key(post.parent_id), encode_counts((count - 1, with_files - with_file)))?;
+                    }
+                }
+            }
+            Ok(deleted)
+        })?;
+        deleted.files.sort();
+        deleted.files.dedup();
+        Ok(deleted)
+    }
+
+    // Post by post, threads before their replies, so the shared trees stay
+    // consistent if it is cut short; the board's own trees go last
+    fn delete_board(&self, board_id: i32) -> StoreResult<Deleted> {
+        let board = match self.board(board_id)? {
+            Some(board) => board,
+            None => return Ok(Deleted::default()),
+        };
+        let mut ids = Vec::new();
+        for post_key in board.posts.iter().keys() {
+            ids.extend(decode_id(Some(post_key?)));
+        }
+        let mut deleted = Deleted::default();
+        for id in ids {
+            let post = self.delete_post(id)?;
+            deleted.posts += post.posts;
+            deleted.files.extend(post.files);
+        }
+        self.boards.remove(key(board_id))?;
+        for name in BOARD_TREES {
+            self.db.drop_tree(format!("{}{}/{}", BOARD_PREFIX, board_id, name))?;
+        }
+        deleted.files.sort();
+        deleted.files.dedup();
+        Ok(deleted)
+    }
+
+    fn delete_files(&self, id: i32) -> StoreResult<Deleted> {
+        let board = match self.locate(id)? {
+            Some(board) => board,
+            None => return Ok(Deleted::default()),
+        };
+        let deleted = (&self.files, &board.posts, &board.counters).transaction(|(files, posts, counters)| {
+            let mut record = match get_in(posts, id)? {
+                Some(record) if !record.post.files.is_empty() => record,
+                _ => return Ok(Deleted::default()),
+            };
+            let unused = unindex_files(files, &record.post)?;
+            record.post.files.clear();
+            posts.insert(&key(id), encode(&record))?;
+            let parent_id = record.post.parent_id;
+            if parent_id != 0 {
+                let (count, with_files) = counts(counters.get(key(parent_id))?);
+                counters.insert(&key(parent_id), encode_counts((count, with_files - 1)))?;
+            }
+            Ok(Deleted { posts: 0, files: unused })
+        })?;
+        Ok(deleted)
+    }
+
+    // Stored names stay the same, so the `files` tree is left as it is
+    fn move_files(&self, from: &str, to: &str) -> StoreResult<usize> {
+        let mut moved = 0;
+        for board_key in self.boards.iter().keys() {
+            let board = match decode_id(Some(board_key?)) {
+                Some(board_id) => self.board_trees(board_id)?,
+                None => continue,
+            };
+            for value in board.posts.iter().values() {
+                let mut post = decode(&value?)?.post;
+                let count = post.files.iter_mut().map(|file| file.move_dir(from, to)).filter(|&moved| moved).count();
+                if count > 0 {
+                    self.update(post.id, |record| {
+                        for file in &mut record.post.files {
+                            file.move_dir(from, to);
+                        }
+                    })?;
+                    moved += count;
+                }
+            }
+        }
+        Ok(moved)
+    }
+}
@@ -0,0 +1,139 @@
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+
+use super::{Store, StoreResult};
+use crate::db::{self, Attachment, Deleted, Post, Poster, Revision, SearchHit, SearchQuery};
+use crate::lock;
+
+// Shares the connection the rest of the server uses
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn new(conn: Arc<Mutex<Connection>>) -> SqliteStore {
+        SqliteStore { conn }
+    }
+}
+
+impl Store for SqliteStore {
+    fn insert_post(&self, board_id: i32, post: &Post, poster: &Poster) -> StoreResult<i32> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let id = db::insert_post(&tx, board_id, post, poster)?;
+        tx.commit()?;
+        Ok(id as i32)
+    }
+
+    fn deletion_password(&self, id: i32) -> StoreResult<Option<String>> {
+        Ok(db::deletion_password(&lock(&self.conn), id)?)
+    }
+
+    fn author(&self, id: i32) -> StoreResult<Option<String>> {
+        Ok(db::author(&lock(&self.conn), id)?)
+    }
+
+    fn edit_post(&self, id: i32, title: &str, message: &str, at: i64) -> StoreResult<bool> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let edited = db::edit_post(&tx, id, title, message, at)?;
+        tx.commit()?;
+        Ok(edited)
+    }
+
+    fn revisions(&self, id: i32) -> StoreResult<Vec<Revision>> {
+        Ok(db::revisions(&lock(&self.conn), id)?)
+    }
+
+    fn fetch_post(&self, id: i32) -> StoreResult<Option<Post>> {
+        Ok(db::fetch_post(&lock(&self.conn), id)?)
+    }
+
+    fn post_board(&self, id: i32) -> StoreResult<Option<i32>> {
+        Ok(db::post_board(&lock(&self.conn), id)?)
+    }
+
+    fn fetch_thread(&self, board_id: i32, thread_id: i32) -> StoreResult<Vec<Post>> {
+        Ok(db::fetch_thread(&lock(&self.conn), board_id, thread_id)?)
+    }
+
+    fn thread_exists(&self, board_id: i32, thread_id: i32) -> StoreResult<bool> {
+        Ok(db::thread_exists(&lock(&self.conn), board_id, thread_id)?)
+    }
+
+    fn list_threads(&self, board_id: i32, limit: usize, offset: usize) -> StoreResult<Vec<Post>> {
+        Ok(db::list_threads(&lock(&self.conn), board_id, limit, offset)?)
+    }
+
+    fn count_threads(&self, board_id: i32) -> StoreResult<i64> {
+        Ok(db::count_threads(&lock(&self.conn), board_id)?)
+    }
+
+    fn board_ids(&self) -> StoreResult<Vec<i32>> {
+        Ok(db::posted_boards(&lock(&self.conn))?)
+    }
+
+    fn recent_threads(&self, board_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        Ok(db::list_recent_threads(&lock(&self.conn), board_id, limit)?)
+    }
+
+    fn count_replies(&self, thread_id: i32) -> StoreResult<(i64, i64)> {
+        Ok(db::count_replies(&lock(&self.conn), thread_id)?)
+    }
+
+    fn last_replies(&self, thread_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        Ok(db::last_replies(&lock(&self.conn), thread_id, limit)?)
+    }
+
+    fn bump(&self, thread_id: i32, at: i64) -> StoreResult<()> {
+        Ok(db::bump(&lock(&self.conn), thread_id, at)?)
+    }
+
+    fn reply_number(&self, post: &Post) -> StoreResult<i64> {
+        Ok(db::reply_number(&lock(&self.conn), post)?)
+    }
+
+    fn posts_after(&self, board_id: i32, thread_id: Option<i32>, after_id: i32, limit: usize) -> StoreResult<Vec<Post>> {
+        Ok(db::posts_after(&lock(&self.conn), board_id, thread_id, after_id, limit)?)
+    }
+
+    fn search(&self, query: &SearchQuery, limit: usize, offset: usize) -> StoreResult<(i64, Vec<SearchHit>)> {
+        Ok(db::search(&lock(&self.conn), query, limit, offset)?)
+    }
+
+    fn find_file(&self, name: &str) -> StoreResult<Option<Attachment>> {
+        Ok(db::find_attachment(&lock(&self.conn), name)?)
+    }
+
+    fn delete_post(&self, id: i32) -> StoreResult<Deleted> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let deleted = db::delete_post(&tx, id)?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn delete_board(&self, board_id: i32) -> StoreResult<Deleted> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let deleted = db::delete_board_posts(&tx, board_id)?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn delete_files(&self, id: i32) -> StoreResult<Deleted> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let deleted = db::delete_files(&tx, id)?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn move_files(&self, from: &str, to: &str) -> StoreResult<usize> {
+        let conn = lock(&self.conn);
+        let tx = conn.unchecked_transaction()?;
+        let moved = db::move_files(&tx, from, to)?;
+        tx.commit()?;
+        Ok(moved)
+    }
+}
@@ -0,0 +1,112 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DEFAULT_NAME: &str = "Anonymous";
+
+// Splits the raw name field into the display name and an optional tripcode.
+// `name#password` gives a classic tripcode, `name##password` a secure one
+// keyed with the server secret.
+pub fn parse_name(raw: &str, secret: &[u8]) -> (String, Option<String>) {
+    let (name, password) = match raw.find('#') {
+        Some(pos) => (&raw[..pos], &raw[pos + 1..]),
+        None => (raw, ""),
+    };
+
+    let tripcode = if let Some(secure) = password.strip_prefix('#') {
+        if secure.is_empty() {
+            None
+        } else {
+            Some(secure_tripcode(secure, secret))
+        }
+    } else if password.is_empty() {
+        None
+    } else {
+        classic_tripcode(password)
+    };
+
+    (name.trim().to_string(), tripcode)
+}
+
+// The traditional futaba/2channel algorithm: DES crypt() with a salt taken
+// from the second and third characters of the password.
+fn classic_tripcode(password: &str) -> Option<String> {
+    #[allow(deprecated)]
+    let hash = pwhash::unix_crypt::hash_with(&salt(password), password).ok()?;
+    Some(format!("!{}", &hash[hash.len() - 10..]))
+}
+
+// Short passwords are padded with "H..", and characters crypt() has no use
+// for in a salt are mapped onto ones it has
+fn salt(password: &str) -> String {
+    let mut padded = password.as_bytes().to_vec();
+    padded.extend_from_slice(b"H..");
+    padded[1..3]
+        .iter()
+        .map(|&b| match b {
+            b':'..=b'@' => (b - b':' + b'A') as char,
+            b'['..=b'`' => (b - b'[' + b'a') as char,
+            b'.'..=b'z' => b as char,
+            _ => '.',
+        })
+        .collect()
+}
+
+fn secure_tripcode(password: &str, secret: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(password.as_bytes());
+    let digest = STANDARD.encode(mac.finalize().into_bytes());
+    format!("!!{}", &digest[..10])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"server secret";
+
+    #[test]
+    fn classic_known_vectors() {
+        assert_eq!(classic_tripcode("a").as_deref(), Some("!ZnBI2EKkq."));
+        assert_eq!(classic_tripcode("tripcode").as_deref(), Some("!3GqYIJ3Obs"));
+    }
+
+    #[test]
+    fn salt_substitutions() {
+        assert_eq!(salt(""), "..");
+        assert_eq!(salt("a"), "H.");
+        assert_eq!(salt("ab"), "bH");
+        assert_eq!(salt("abcd"), "bc");
+        // : through @ become A through G, [ through ` become a through f
+        assert_eq!(salt("a:@"), "AG");
+        assert_eq!(salt("a[`"), "af");
+        assert_eq!(salt("a.z"), ".z");
+        // Anything else, including bytes of other characters, becomes a dot
+        assert_eq!(salt("a!{"), "..");
+        assert_eq!(salt("aé"), "..");
+    }
+
+    #[test]
+    fn secure_tripcodes_depend_on_the_secret() {
+        let trip = secure_tripcode("password", SECRET);
+        assert!(trip.starts_with("!!"));
+        assert_eq!(trip.len(), 12);
+        assert_eq!(secure_tripcode("password", SECRET), trip);
+        assert_ne!(secure_tripcode("password", b"another secret"), trip);
+        assert_ne!(secure_tripcode("passwore", SECRET), trip);
+    }
+
+    #[test]
+    fn names_and_tripcodes() {
+        assert_eq!(parse_name(" name ", SECRET), ("name".to_string(), None));
+        assert_eq!(parse_name("name#a", SECRET), ("name".to_string(), Some("!ZnBI2EKkq.".to_string())));
+        assert_eq!(parse_name("#a", SECRET), (String::new(), Some("!ZnBI2EKkq.".to_string())));
+        let secure = Some(secure_tripcode("a", SECRET));
+        assert_eq!(parse_name("name##a", SECRET), ("name".to_string(), secure));
+        // Empty passwords give no tripcode
+        assert_eq!(parse_name("name#", SECRET), ("name".to_string(), None));
+        assert_eq!(parse_name("name##", SECRET), ("name".to_string(), None));
+    }
+}
@@ -0,0 +1,168 @@
+// One WebSocket connection carrying activity from any number of boards.
+//
+// Clients send JSON messages:
+//   {"type":"subscribe","board_id":1}
+//   {"type":"unsubscribe","board_id":1}
+//   {"type":"ping"}
+// and receive "subscribed", "unsubscribed", "pong" and "error" replies plus
+// one message per hub event on their boards, e.g.
+//   {"type":"post","board_id":1,"thread_id":5,"id":9,"data":{...}}
+//   {"type":"bump","board_id":1,"thread_id":5,"id":9,"data":{...}}
+//   {"type":"delete","board_id":1,"thread_id":5,"id":9,"data":{...,"file_only":false}}
+//
+// A "delete" with `id` equal to `thread_id` is the whole thread; with
+// `file_only` set the post stays and only its files are gone.
+//
+// The server pings every HEARTBEAT_INTERVAL and closes connections it has
+// not heard from within CLIENT_TIMEOUT. Events go through a small outbox per
+// connection; a client that lets it fill up is disconnected rather than
+// buffered for.
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use actix_ws::{CloseCode, CloseReason, Message, MessageStream, Session};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::live::{Event, Hub, Topic};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+// How long one message may wait for room in the socket's send buffer
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+// Events queued for a connection before it counts as too slow
+const OUTBOX_CAPACITY: usize = 64;
+const MAX_SUBSCRIPTIONS: usize = 64;
+const MAX_FRAME_SIZE: usize = 4 * 1024;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe { board_id: i32 },
+    Unsubscribe { board_id: i32 },
+    Ping,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Reply<'a> {
+    Subscribed { board_id: i32 },
+    Unsubscribed { board_id: i32 },
+    Pong,
+    Error { message: &'a str },
+}
+
+pub async fn board_activity(req: HttpRequest, body: web::Payload, hub: web::Data<Hub>) -> Result<HttpResponse> {
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(run(session, stream.max_frame_size(MAX_FRAME_SIZE), hub));
+    Ok(response)
+}
+
+// Copies one board's events into the connection's outbox until the
+// connection goes away or falls behind
+fn forward(hub: &Hub, board_id: i32, outbox: mpsc::Sender<Arc<Event>>, too_slow: Arc<Notify>) -> JoinHandle<()> {
+    let mut receiver = hub.subscribe(Topic::Board(board_id));
+    actix_web::rt::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => match outbox.try_send(event) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        too_slow.notify_one();
+                        return;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => return,
+                },
+                Err(RecvError::Lagged(_)) => {
+                    too_slow.notify_one();
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+async fn send(session: &mut Session, text: String) -> bool {
+    matches!(tokio::time::timeout(SEND_TIMEOUT, session.text(text)).await, Ok(Ok(())))
+}
+
+async fn reply(session: &mut Session, reply: Reply<'_>) -> bool {
+    send(session, serde_json::to_string(&reply).unwrap_or_default()).await
+}
+
+async fn run(mut session: Session, mut stream: MessageStream, hub: web::Data<Hub>) {
+    let (outbox, mut events) = mpsc::channel(OUTBOX_CAPACITY);
+    let too_slow = Arc::new(Notify::new());
+    let mut subscriptions: HashMap<i32, JoinHandle<()>> = HashMap::new();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    let close_reason = loop {
+        tokio::select! {
+            message = stream.recv() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(_)) => break Some(CloseCode::Protocol.into()),
+                    None => break None,
+                };
+                last_seen = Instant::now();
+                let ok = match message {
+                    Message::Text(text) => match serde_json::from_str(&text) {
+                        Ok(ClientMessage::Subscribe { board_id }) => {
+                            if subscriptions.contains_key(&board_id) {
+                                reply(&mut session, Reply::Subscribed { board_id }).await
+                            } else if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                                reply(&mut session, Reply::Error { message: "Too many subscriptions" }).await
+                            } else {
+                                subscriptions.insert(board_id, forward(&hub, board_id, outbox.clone(), too_slow.clone()));
+                                reply(&mut session, Reply::Subscribed { board_id }).await
+                            }
+                        }
+                        Ok(ClientMessage::Unsubscribe { board_id }) => {
+                            if let Some(task) = subscriptions.remove(&board_id) {
+                                task.abort();
+                            }
+                            reply(&mut session, Reply::Unsubscribed { board_id }).await
+                        }
+                        Ok(ClientMessage::Ping) => reply(&mut session, Reply::Pong).await,
+                        Err(_) => reply(&mut session, Reply::Error { message: "Unrecognized message" }).await,
+                    },
+                    Message::Ping(bytes) => session.pong(&bytes).await.is_ok(),
+                    Message::Close(reason) => break reason,
+                    _ => true,
+                };
+                if !ok {
+                    break None;
+                }
+            }
+            Some(event) = events.recv() => {
+                if !send(&mut session, event.to_json()).await {
+                    break None;
+                }
+            }
+            _ = too_slow.notified() => {
+                break Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some("Too slow to keep up".to_string()),
+                });
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    break Some(CloseCode::Away.into());
+                }
+                if session.ping(b"").await.is_err() {
+                    break None;
+                }
+            }
+        }
+    };
+
+    for task in subscriptions.into_values() {
+        task.abort();
+    }
+    let _ = session.close(close_reason).await;
+}
@@ -0,0 +1,35 @@
+// Appends replies to the open thread as they are posted, and takes deleted
+// ones away, using the thread's Server-Sent Events stream. Without
+// JavaScript the page still works, it just needs a reload.
+(function () {
+    var thread = document.querySelector("[data-live-url]");
+    if (!thread || !window.EventSource) {
+        return;
+    }
+
+    var source = new EventSource(thread.getAttribute("data-live-url"));
+    source.addEventListener("post", function (event) {
+        var post = JSON.parse(event.data);
+        if (!document.getElementById("p" + post.id)) {
+            thread.insertAdjacentHTML("beforeend", post.html);
+        }
+    });
+    source.addEventListener("delete", function (event) {
+        var deleted = JSON.parse(event.data);
+        var post = document.getElementById("p" + deleted.id);
+        if (!post) {
+            return;
+        }
+        if (deleted.file_only) {
+            var files = post.querySelector(".attachments");
+            if (files) {
+                files.remove();
+            }
+        } else if (deleted.id === deleted.thread_id) {
+            thread.innerHTML = "<div class=\"post\">This thread has been deleted.</div>";
+            source.close();
+        } else {
+            post.remove();
+        }
+    });
+})();
@@ -0,0 +1,572 @@
+// One set of tests for every storage backend: each enabled backend gets a
+// module running all of the `conformance` checks against a fresh store.
+use adelia::db::{Attachment, Post, Poster, SearchQuery};
+use adelia::store::Store;
+
+fn post(parent_id: i32, title: &str, at: i64) -> Post {
+    Post {
+        id: 0,
+        post_id: format!("p{}", title),
+        parent_id,
+        title: title.to_string(),
+        message: format!("message of {}", title),
+        files: Vec::new(),
+        name: None,
+        tripcode: None,
+        poster_id: None,
+        created_at: at,
+        last_reply_at: at,
+        edited_at: None,
+    }
+}
+
+fn attachment(name: &str) -> Attachment {
+    Attachment {
+        file_path: format!("./static/abcdef-{}", name),
+        original_name: Some(name.to_string()),
+        mime_type: Some("image/png".to_string()),
+        size: Some(1234),
+        width: Some(10),
+        height: Some(20),
+        duration: None,
+        thumbnail_path: Some(format!("./static/abcdef-{}.thumb.jpg", name)),
+        sha256: Some("00ff".to_string()),
+        md5: Some("AP8=".to_string()),
+    }
+}
+
+fn ids(posts: &[Post]) -> Vec<i32> {
+    posts.iter().map(|post| post.id).collect()
+}
+
+// A thread with `replies` replies on `board_id`, returning the ids in order
+fn thread(store: &dyn Store, board_id: i32, at: i64, replies: usize) -> Vec<i32> {
+    let id = store.insert_post(board_id, &post(0, "op", at), &Poster::default()).unwrap();
+    let mut ids = vec![id];
+    for n in 0..replies {
+        ids.push(store.insert_post(board_id, &post(id, &format!("reply{}", n), at), &Poster::default()).unwrap());
+    }
+    ids
+}
+
+mod conformance {
+    use super::*;
+
+    pub fn insert_and_fetch(store: &dyn Store) {
+        let mut op = post(0, "first", 100);
+        op.files = vec![attachment("a.png"), attachment("b.png")];
+        op.name = Some("name".to_string());
+        op.tripcode = Some("!trip".to_string());
+        let id = store.insert_post(1, &op, &Poster::default()).unwrap();
+        assert!(id > 0);
+
+        let fetched = store.fetch_post(id).unwrap().unwrap();
+        assert_eq!(fetched.id, id);
+        assert_eq!(fetched.post_id, "pfirst");
+        assert_eq!(fetched.parent_id, 0);
+        assert_eq!(fetched.title, "first");
+        assert_eq!(fetched.message, "message of first");
+        assert_eq!(fetched.name.as_deref(), Some("name"));
+        assert_eq!(fetched.tripcode.as_deref(), Some("!trip"));
+        assert_eq!(fetched.created_at, 100);
+        assert_eq!(fetched.last_reply_at, 100);
+        let names: Vec<_> = fetched.files.iter().map(|file| file.display_name()).collect();
+        assert_eq!(names, ["a.png", "b.png"]);
+        assert_eq!(fetched.files[0].size, Some(1234));
+        assert_eq!(fetched.files[0].height, Some(20));
+        assert_eq!(fetched.files[0].sha256.as_deref(), Some("00ff"));
+        assert_eq!(fetched.files[0].md5.as_deref(), Some("AP8="));
+
+        assert!(store.fetch_post(id + 1000).unwrap().is_none());
+    }
+
+    pub fn ids_increase(store: &dyn Store) {
+        let first = store.insert_post(1, &post(0, "a", 1), &Poster::default()).unwrap();
+        let second = store.insert_post(2, &post(0, "b", 1), &Poster::default()).unwrap();
+        let third = store.insert_post(1, &post(first, "c", 1), &Poster::default()).unwrap();
+        assert!(first < second && second < third);
+    }
+
+    pub fn post_board(store: &dyn Store) {
+        let first = thread(store, 1, 1, 1);
+        let second = thread(store, 2, 1, 1);
+        assert_eq!(store.post_board(first[1]).unwrap(), Some(1));
+        assert_eq!(store.post_board(second[0]).unwrap(), Some(2));
+        assert_eq!(store.post_board(second[1]).unwrap(), Some(2));
+        assert_eq!(store.post_board(second[1] + 1000).unwrap(), None);
+        store.delete_post(first[0]).unwrap();
+        assert_eq!(store.post_board(first[1]).unwrap(), None);
+    }
+
+    pub fn fetch_thread(store: &dyn Store) {
+        let ids = thread(store, 1, 10, 3);
+        thread(store, 1, 10, 2);
+        assert_eq!(super::ids(&store.fetch_thread(1, ids[0]).unwrap()), ids);
+        // Threads are looked up on their own board only
+        assert!(store.fetch_thread(2, ids[0]).unwrap().is_empty());
+        assert!(store.fetch_thread(1, ids[3] + 1000).unwrap().is_empty());
+    }
+
+    pub fn thread_exists(store: &dyn Store) {
+        let ids = thread(store, 1, 10, 1);
+        assert!(store.thread_exists(1, ids[0]).unwrap());
+        assert!(!store.thread_exists(2, ids[0]).unwrap());
+        // A reply doesn't start a thread
+        assert!(!store.thread_exists(1, ids[1]).unwrap());
+        assert!(!store.thread_exists(1, ids[1] + 1000).unwrap());
+    }
+
+    pub fn list_threads_in_bump_order(store: &dyn Store) {
+        let old = thread(store, 1, 100, 0)[0];
+        let new = thread(store, 1, 200, 0)[0];
+        let same_time = thread(store, 1, 200, 0)[0];
+        thread(store, 2, 300, 0);
+        assert_eq!(ids(&store.list_threads(1, 10, 0).unwrap()), [same_time, new, old]);
+
+        store.bump(old, 400).unwrap();
+        assert_eq!(ids(&store.list_threads(1, 10, 0).unwrap()), [old, same_time, new]);
+        assert_eq!(store.fetch_post(old).unwrap().unwrap().last_reply_at, 400);
+    }
+
+    pub fn list_threads_pages(store: &dyn Store) {
+        let threads: Vec<i32> = (0..5).map(|n| thread(store, 1, n, 1)[0]).collect();
+        assert_eq!(ids(&store.list_threads(1, 2, 0).unwrap()), [threads[4], threads[3]]);
+        assert_eq!(ids(&store.list_threads(1, 2, 2).unwrap()), [threads[2], threads[1]]);
+        assert_eq!(ids(&store.list_threads(1, 2, 4).unwrap()), [threads[0]]);
+        assert!(store.list_threads(1, 2, 6).unwrap().is_empty());
+        assert!(store.list_threads(3, 2, 0).unwrap().is_empty());
+    }
+
+    pub fn count_threads(store: &dyn Store) {
+        assert_eq!(store.count_threads(1).unwrap(), 0);
+        thread(store, 1, 1, 2);
+        thread(store, 1, 1, 0);
+        thread(store, 2, 1, 1);
+        assert_eq!(store.count_threads(1).unwrap(), 2);
+        assert_eq!(store.count_threads(2).unwrap(), 1);
+    }
+
+    pub fn board_ids(store: &dyn Store) {
+        assert!(store.board_ids().unwrap().is_empty());
+        let gone = thread(store, 3, 1, 1)[0];
+        thread(store, 2, 1, 0);
+        thread(store, 1, 1, 0);
+        assert_eq!(store.board_ids().unwrap(), [1, 2, 3]);
+        store.delete_post(gone).unwrap();
+        assert_eq!(store.board_ids().unwrap(), [1, 2]);
+    }
+
+    pub fn recent_threads(store: &dyn Store) {
+        let first = thread(store, 1, 100, 1)[0];
+        let second = thread(store, 1, 50, 0)[0];
+        thread(store, 2, 200, 0);
+        // Bumps don't count
+        store.bump(first, 500).unwrap();
+        assert_eq!(ids(&store.recent_threads(1, 10).unwrap()), [second, first]);
+        assert_eq!(ids(&store.recent_threads(1, 1).unwrap()), [second]);
+        assert!(store.recent_threads(3, 10).unwrap().is_empty());
+    }
+
+    pub fn count_replies(store: &dyn Store) {
+        let op = thread(store, 1, 1, 0)[0];
+        assert_eq!(store.count_replies(op).unwrap(), (0, 0));
+        store.insert_post(1, &post(op, "plain", 2), &Poster::default()).unwrap();
+        let mut with_file = post(op, "file", 3);
+        with_file.files = vec![attachment("c.png"), attachment("d.png")];
+        store.insert_post(1, &with_file, &Poster::default()).unwrap();
+        thread(store, 1, 1, 4);
+        assert_eq!(store.count_replies(op).unwrap(), (2, 1));
+    }
+
+    pub fn last_replies(store: &dyn Store) {
+        let thread_ids = thread(store, 1, 1, 4);
+        thread(store, 1, 1, 2);
+        assert_eq!(ids(&store.last_replies(thread_ids[0], 2).unwrap()), thread_ids[3..]);
+        assert_eq!(ids(&store.last_replies(thread_ids[0], 10).unwrap()), thread_ids[1..]);
+        assert!(store.last_replies(thread_ids[4], 10).unwrap().is_empty());
+        assert!(store.last_replies(thread_ids[0] + 1000, 10).unwrap().is_empty());
+    }
+
+    pub fn bump_moves_replies_along(store: &dyn Store) {
+        let ids = thread(store, 1, 10, 2);
+        store.bump(ids[0], 50).unwrap();
+        for post in store.fetch_thread(1, ids[0]).unwrap() {
+            assert_eq!(post.last_reply_at, 50);
+            assert_eq!(post.created_at, 10);
+        }
+    }
+
+    pub fn reply_number(store: &dyn Store) {
+        let ids = thread(store, 1, 1, 3);
+        thread(store, 1, 1, 2);
+        let numbers: Vec<i64> = ids
+            .iter()
+            .map(|&id| store.reply_number(&store.fetch_post(id).unwrap().unwrap()).unwrap())
+            .collect();
+        assert_eq!(numbers, [0, 1, 2, 3]);
+    }
+
+    pub fn posts_after(store: &dyn Store) {
+        let first = thread(store, 1, 1, 2);
+        let second = thread(store, 1, 1, 1);
+        thread(store, 2, 1, 1);
+
+        let board = ids(&store.posts_after(1, None, 0, 100).unwrap());
+        assert_eq!(board, [first.clone(), second.clone()].concat());
+        assert_eq!(ids(&store.posts_after(1, None, first[1], 100).unwrap()), [first[2], second[0], second[1]]);
+        assert_eq!(ids(&store.posts_after(1, None, 0, 2).unwrap()), [first[0], first[1]]);
+        assert_eq!(ids(&store.posts_after(1, Some(first[0]), 0, 100).unwrap()), first);
+        assert_eq!(ids(&store.posts_after(1, Some(first[0]), first[1], 100).unwrap()), [first[2]]);
+        assert!(store.posts_after(1, None, second[1], 100).unwrap().is_empty());
+    }
+
+    pub fn search(store: &dyn Store) {
+        // 2024-01-02 and 2024-01-05, both at 01:00
+        let (earlier, later) = (1_704_157_200, 1_704_416_400);
+        let mut op = post(0, "Cats &amp; dogs", earlier);
+        op.message = "A post about cats".to_string();
+        let op = store.insert_post(1, &op, &Poster::default()).unwrap();
+        let mut reply = post(op, "re", later);
+        reply.message = "dogs only".to_string();
+        reply.files = vec![attachment("c.png")];
+        let reply = store.insert_post(1, &reply, &Poster::default()).unwrap();
+        let mut other = post(0, "cats elsewhere", earlier);
+        other.message = "nothing".to_string();
+        let other = store.insert_post(2, &other, &Poster::default()).unwrap();
+
+        let query = |terms: &[&str]| SearchQuery {
+            terms: terms.iter().map(|term| term.to_string()).collect(),
+            board_id: None,
+            from: None,
+            to: None,
+            has_file: false,
+        };
+        let found = |query: SearchQuery| {
+            let (total, hits) = store.search(&query, 10, 0).unwrap();
+            let mut ids: Vec<i32> = hits.iter().map(|hit| hit.id).collect();
+            ids.sort();
+            assert_eq!(total, ids.len() as i64);
+            ids
+        };
+        assert_eq!(found(query(&["cats"])), [op, other]);
+        assert_eq!(found(query(&["CATS"])), [op, other]);
+        assert_eq!(found(SearchQuery { board_id: Some(1), ..query(&["cats"]) }), [op]);
+        // Every term has to match, in the title or the message
+        assert_eq!(found(query(&["cats", "dogs"])), [op]);
+        assert_eq!(found(query(&["about cats"])), [op]);
+        assert!(found(query(&["cats about"])).is_empty());
+        assert_eq!(found(SearchQuery { has_file: true, ..query(&["dogs"]) }), [reply]);
+        assert_eq!(found(SearchQuery { to: Some("2024-01-02".to_string()), ..query(&["dogs"]) }), [op]);
+        assert_eq!(found(SearchQuery { from: Some("2024-01-03".to_string()), ..query(&["dogs"]) }), [reply]);
+
+        let (total, hits) = store.search(&query(&["dogs"]), 10, 0).unwrap();
+        assert_eq!(total, 2);
+        let hit = hits.iter().find(|hit| hit.id == reply).unwrap();
+        assert_eq!((hit.parent_id, hit.board_id, hit.has_file), (op, 1, true));
+        assert!(hit.message.contains("<mark>dogs</mark>"));
+        assert!(hit.created_at.starts_with("2024-01-05"));
+        let hit = hits.iter().find(|hit| hit.id == op).unwrap();
+        assert!(hit.title.contains("<mark>dogs</mark>"));
+
+        let (total, hits) = store.search(&query(&["cats"]), 1, 1).unwrap();
+        assert_eq!((total, hits.len()), (2, 1));
+    }
+
+    pub fn find_file(store: &dyn Store) {
+        let mut op = post(0, "op", 1);
+        op.files = vec![attachment("a.png"), attachment("b.png")];
+        store.insert_post(1, &op, &Poster::default()).unwrap();
+        let found = store.find_file("abcdef-b.png").unwrap().unwrap();
+        assert_eq!(found.original_name.as_deref(), Some("b.png"));
+        let found = store.find_file("abcdef-a.png.thumb.jpg").unwrap().unwrap();
+        assert_eq!(found.original_name.as_deref(), Some("a.png"));
+        assert!(store.find_file("abcdef-c.png").unwrap().is_none());
+        // Only whole names count
+        assert!(store.find_file("b.png").unwrap().is_none());
+    }
+
+    pub fn poster_id(store: &dyn Store) {
+        let derive = |thread_id: i32| format!("id-{}", thread_id);
+        let poster = Poster { poster_id: Some(&derive), ..Poster::default() };
+        let op = store.insert_post(1, &post(0, "op", 1), &poster).unwrap();
+        let reply = store.insert_post(1, &post(op, "reply", 1), &poster).unwrap();
+        let plain = store.insert_post(1, &post(op, "plain", 1), &Poster::default()).unwrap();
+        // Both take the id of the thread, which for its starter is its own
+        let expected = format!("id-{}", op);
+        assert_eq!(store.fetch_post(op).unwrap().unwrap().poster_id, Some(expected.clone()));
+        assert_eq!(store.fetch_post(reply).unwrap().unwrap().poster_id, Some(expected));
+        assert!(store.fetch_post(plain).unwrap().unwrap().poster_id.is_none());
+    }
+
+    pub fn deletion_password(store: &dyn Store) {
+        let op = thread(store, 1, 1, 0)[0];
+        let poster = Poster { deletion_password: Some("salt$hash".to_string()), ..Poster::default() };
+        let reply = store.insert_post(1, &post(op, "reply", 1), &poster).unwrap();
+        assert_eq!(store.deletion_password(reply).unwrap().as_deref(), Some("salt$hash"));
+        assert!(store.deletion_password(op).unwrap().is_none());
+        assert!(store.deletion_password(reply + 1000).unwrap().is_none());
+    }
+
+    pub fn author(store: &dyn Store) {
+        let poster = Poster { author: Some("author-id".to_string()), ..Poster::default() };
+        let op = store.insert_post(1, &post(0, "op", 1), &poster).unwrap();
+        let reply = store.insert_post(1, &post(op, "reply", 1), &Poster::default()).unwrap();
+        assert_eq!(store.author(op).unwrap().as_deref(), Some("author-id"));
+        assert!(store.author(reply).unwrap().is_none());
+    }
+
+    pub fn edit_keeps_revisions(store: &dyn Store) {
+        let ids = thread(store, 1, 10, 1);
+        assert!(store.revisions(ids[1]).unwrap().is_empty());
+        assert!(store.edit_post(ids[1], "second", "message two", 20).unwrap());
+        assert!(store.edit_post(ids[1], "third", "message three", 30).unwrap());
+
+        let post = store.fetch_post(ids[1]).unwrap().unwrap();
+        assert_eq!((post.title.as_str(), post.message.as_str()), ("third", "message three"));
+        assert_eq!(post.edited_at, Some(30));
+        assert_eq!(post.created_at, 10);
+        assert!(store.fetch_post(ids[0]).unwrap().unwrap().edited_at.is_none());
+
+        let revisions = store.revisions(ids[1]).unwrap();
+        let versions: Vec<_> = revisions
+            .iter()
+            .map(|revision| (revision.title.as_str(), revision.message.as_str(), revision.replaced_at))
+            .collect();
+        assert_eq!(versions, [("reply0", "message of reply0", 20), ("second", "message two", 30)]);
+
+        assert!(!store.edit_post(ids[1] + 1000, "none", "none", 40).unwrap());
+        store.delete_post(ids[1]).unwrap();
+        assert!(store.revisions(ids[1]).unwrap().is_empty());
+    }
+
+    pub fn delete_reply(store: &dyn Store) {
+        let ids = thread(store, 1, 1, 2);
+        let mut with_file = post(ids[0], "file", 2);
+        with_file.files = vec![attachment("a.png")];
+        let reply = store.insert_post(1, &with_file, &Poster::default()).unwrap();
+        assert_eq!(store.count_replies(ids[0]).unwrap(), (3, 1));
+
+        let deleted = store.delete_post(reply).unwrap();
+        assert_eq!(deleted.posts, 1);
+        assert_eq!(deleted.files, ["./static/abcdef-a.png", "./static/abcdef-a.png.thumb.jpg"]);
+        assert!(store.fetch_post(reply).unwrap().is_none());
+        assert!(store.find_file("abcdef-a.png").unwrap().is_none());
+        assert_eq!(store.count_replies(ids[0]).unwrap(), (2, 0));
+        assert_eq!(super::ids(&store.fetch_thread(1, ids[0]).unwrap()), ids);
+        assert_eq!(store.delete_post(reply).unwrap().posts, 0);
+    }
+
+    pub fn delete_thread(store: &dyn Store) {
+        let ids = thread(store, 1, 1, 2);
+        let other = thread(store, 1, 2, 1);
+        assert_eq!(store.delete_post(ids[0]).unwrap().posts, 3);
+        for id in &ids {
+            assert!(store.fetch_post(*id).unwrap().is_none());
+        }
+        assert!(!store.thread_exists(1, ids[0]).unwrap());
+        assert_eq!(store.count_threads(1).unwrap(), 1);
+        assert_eq!(super::ids(&store.list_threads(1, 10, 0).unwrap()), [other[0]]);
+        assert_eq!(super::ids(&store.posts_after(1, None, 0, 100).unwrap()), other);
+    }
+
+    pub fn delete_board(store: &dyn Store) {
+        let kept = thread(store, 2, 1, 1);
+        let gone = thread(store, 1, 1, 2);
+        let mut with_file = post(gone[0], "file", 2);
+        with_file.files = vec![attachment("a.png")];
+        let reply = store.insert_post(1, &with_file, &Poster::default()).unwrap();
+        thread(store, 1, 3, 0);
+
+        let deleted = store.delete_board(1).unwrap();
+        assert_eq!(deleted.posts, 5);
+        assert_eq!(deleted.files, ["./static/abcdef-a.png", "./static/abcdef-a.png.thumb.jpg"]);
+        assert!(store.fetch_post(gone[0]).unwrap().is_none());
+        assert!(store.fetch_post(reply).unwrap().is_none());
+        assert!(store.find_file("abcdef-a.png").unwrap().is_none());
+        assert_eq!(store.count_threads(1).unwrap(), 0);
+        assert!(store.list_threads(1, 10, 0).unwrap().is_empty());
+        assert!(store.posts_after(1, None, 0, 100).unwrap().is_empty());
+        assert_eq!(store.board_ids().unwrap(), [2]);
+        assert_eq!(ids(&store.posts_after(2, None, 0, 100).unwrap()), kept);
+        assert_eq!(store.delete_board(1).unwrap().posts, 0);
+
+        // The board can be posted to again
+        let again = thread(store, 1, 4, 1);
+        assert_eq!(ids(&store.fetch_thread(1, again[0]).unwrap()), again);
+        assert_eq!(store.count_threads(1).unwrap(), 1);
+    }
+
+    pub fn delete_files(store: &dyn Store) {
+        let op = thread(store, 1, 1, 0)[0];
+        let mut with_files = post(op, "files", 2);
+        with_files.files = vec![attachment("a.png"), attachment("b.png")];
+        let reply = store.insert_post(1, &with_files, &Poster::default()).unwrap();
+
+        let deleted = store.delete_files(reply).unwrap();
+        assert_eq!(deleted.posts, 0);
+        assert_eq!(deleted.files.len(), 4);
+        let kept = store.fetch_post(reply).unwrap().unwrap();
+        assert!(kept.files.is_empty());
+        assert_eq!(kept.title, "files");
+        assert!(store.find_file("abcdef-b.png").unwrap().is_none());
+        assert_eq!(store.count_replies(op).unwrap(), (1, 0));
+        assert!(store.delete_files(reply).unwrap().files.is_empty());
+    }
+
+    pub fn move_files(store: &dyn Store) {
+        let mut op = post(0, "op", 1);
+        op.files = vec![attachment("a.png")];
+        let mut elsewhere = attachment("b.png");
+        elsewhere.file_path = "./uploads/abcdef-b.png".to_string();
+        elsewhere.thumbnail_path = None;
+        op.files.push(elsewhere);
+        let id = store.insert_post(1, &op, &Poster::default()).unwrap();
+
+        assert_eq!(store.move_files("./static", "./uploads/").unwrap(), 1);
+        let files = store.fetch_post(id).unwrap().unwrap().files;
+        let paths: Vec<_> = files.iter().map(|file| (file.file_path.as_str(), file.thumbnail_path.as_deref())).collect();
+        assert_eq!(
+            paths,
+            [
+                ("./uploads/abcdef-a.png", Some("./uploads/abcdef-a.png.thumb.jpg")),
+                ("./uploads/abcdef-b.png", None)
+            ]
+        );
+        assert_eq!(store.find_file("abcdef-a.png").unwrap().unwrap().file_path, "./uploads/abcdef-a.png");
+        assert_eq!(store.move_files("./static", "./uploads").unwrap(), 0);
+    }
+}
+
+// A test per conformance check, each with a store from `$store`
+macro_rules! conformance_tests {
+    ($store:expr; $($check:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $check() {
+                let store = $store;
+                crate::conformance::$check(&store);
+            }
+        )*
+    };
+}
+
+macro_rules! backend {
+    ($store:expr) => {
+        conformance_tests!($store;
+            insert_and_fetch,
+            ids_increase,
+            post_board,
+            fetch_thread,
+            thread_exists,
+            list_threads_in_bump_order,
+            list_threads_pages,
+            count_threads,
+            board_ids,
+            recent_threads,
+            count_replies,
+            last_replies,
+            bump_moves_replies_along,
+            reply_number,
+            posts_after,
+            search,
+            find_file,
+            poster_id,
+            deletion_password,
+            author,
+            edit_keeps_revisions,
+            delete_reply,
+            delete_thread,
+            delete_board,
+            delete_files,
+            move_files,
+        );
+    };
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use adelia::db;
+    use adelia::store::SqliteStore;
+    use std::sync::{Arc, Mutex};
+
+    fn store() -> SqliteStore {
+        SqliteStore::new(Arc::new(Mutex::new(db::initialize_db(":memory:").unwrap())))
+    }
+
+    backend!(store());
+}
+
+#[cfg(feature = "sled")]
+mod sled {
+    use adelia::db::Poster;
+    use adelia::store::{SledStore, Store};
+
+    fn store() -> SledStore {
+        SledStore::temporary().unwrap()
+    }
+
+    backend!(store());
+
+    // Databases from before boards had trees of their own keep all posts in
+    // one `posts` tree
+    #[test]
+    fn moves_older_databases_into_boards() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let posts = db.open_tree("posts").unwrap();
+        for (id, board_id, parent_id, at) in [(1, 1, 0, 10), (2, 1, 0, 20), (3, 1, 1, 30), (4, 2, 0, 30), (5, 1, 1, 30)] {
+            let mut post = super::post(parent_id, &id.to_string(), at);
+            post.id = id;
+            let record = serde_json::json!({ "board_id": board_id, "post": post });
+            posts.insert(id.to_be_bytes(), serde_json::to_vec(&record).unwrap()).unwrap();
+        }
+        drop(posts);
+        let store = SledStore::new(db.clone()).unwrap();
+        assert_eq!(store.count_threads(1).unwrap(), 2);
+        assert_eq!(store.count_threads(2).unwrap(), 1);
+        assert_eq!(store.count_replies(1).unwrap(), (2, 0));
+        assert_eq!(super::ids(&store.list_threads(1, 10, 0).unwrap()), [2, 1]);
+        assert_eq!(super::ids(&store.fetch_thread(1, 1).unwrap()), [1, 3, 5]);
+        assert_eq!(super::ids(&store.posts_after(1, None, 2, 10).unwrap()), [3, 5]);
+        assert!(store.fetch_post(4).unwrap().is_some());
+        assert!(!db.tree_names().iter().any(|name| name.as_ref() == b"posts"));
+
+        // Opening it again changes nothing
+        drop(store);
+        let store = SledStore::new(db).unwrap();
+        assert_eq!(store.count_threads(1).unwrap(), 2);
+        assert_eq!(store.count_replies(1).unwrap(), (2, 0));
+        assert_eq!(store.insert_post(1, &super::post(0, "new", 40), &Poster::default()).unwrap(), 6);
+    }
+
+    // A crash after the upgrade wrote the version but before it dropped the
+    // old trees must not make the next open upgrade again from them
+    #[test]
+    fn finishes_an_upgrade_cut_short() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let store = SledStore::new(db.clone()).unwrap();
+        let ids = super::thread(&store, 1, 10, 2);
+        drop(store);
+        let stale = db.open_tree("posts").unwrap();
+        let mut post = super::post(0, "stale", 5);
+        post.id = 100;
+        let record = serde_json::json!({ "board_id": 2, "post": post });
+        stale.insert(100i32.to_be_bytes(), serde_json::to_vec(&record).unwrap()).unwrap();
+        db.open_tree("counters").unwrap().insert(b"x", b"y").unwrap();
+        drop(stale);
+
+        let store = SledStore::new(db.clone()).unwrap();
+        assert_eq!(super::ids(&store.fetch_thread(1, ids[0]).unwrap()), ids);
+        assert_eq!(store.count_replies(ids[0]).unwrap(), (2, 0));
+        assert!(store.fetch_post(100).unwrap().is_none());
+        let names = db.tree_names();
+        assert!(!names.iter().any(|name| name.as_ref() == b"posts" || name.as_ref() == b"counters"));
+    }
+
+    #[test]
+    fn ids_go_on_after_reopening() {
+        let db = ::sled::Config::new().temporary(true).open().unwrap();
+        let first = SledStore::new(db.clone()).unwrap().insert_post(1, &super::post(0, "a", 1), &Poster::default()).unwrap();
+        let second = SledStore::new(db).unwrap().insert_post(1, &super::post(0, "b", 1), &Poster::default()).unwrap();
+        assert_eq!((first, second), (1, 2));
+    }
+}